@@ -12,7 +12,8 @@ fn benchmark_publish(c: &mut Criterion) {
         b.iter(|| {
             let message = Message {
                 id: "1".to_string(),
-                content: "Test message".to_string(),
+                payload: b"Test message".to_vec(),
+                ..Default::default()
             };
             black_box(rapidmq.publish("test_queue", message));
         })
@@ -29,7 +30,8 @@ fn benchmark_consume(c: &mut Criterion) {
     for i in 0..1000 {
         let message = Message {
             id: i.to_string(),
-            content: format!("Test message {}", i),
+            payload: format!("Test message {}", i).into_bytes(),
+            ..Default::default()
         };
         rapidmq.publish("test_queue", message);
     }
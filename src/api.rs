@@ -1,95 +1,1265 @@
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use actix_web::body::{BodySize, MessageBody as ResponseBody};
+use actix::{ActorContext, ActorFutureExt, AsyncContext, SpawnHandle, WrapFuture};
 use actix_web_actors::ws;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use crate::{RapidMQ, Message};
+use crate::{RapidMQ, Delivery, Message, PublishOutcome, CompactionPolicy, RetentionPolicy, StartOffset, Tap};
+use crate::dead_letter::DeadLetterPolicy;
+use crate::dedup::{PRODUCER_ID_HEADER, PRODUCER_SEQ_HEADER};
+use crate::exchange::{Binding, ExchangeKind};
+use crate::priority::{PriorityMode, MAX_PRIORITY};
+use crate::scheduler::now_millis;
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
 use bcrypt::{hash, verify};
 use prometheus::{Encoder, TextEncoder};
 use actix_files::Files;
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
 use crate::proto::RapidMQMessage;
 use prost::Message as ProstMessage;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
 
 #[derive(Deserialize)]
 struct PublishRequest {
     queue_name: String,
-    message: String,
+    #[serde(flatten)]
+    body: MessageBody,
+}
+
+#[derive(Deserialize)]
+struct ExchangePublishRequest {
+    #[serde(default)]
+    routing_key: String,
+    #[serde(flatten)]
+    body: MessageBody,
+}
+
+// Message fields shared by every publish endpoint
+#[derive(Deserialize)]
+struct MessageBody {
+    // Set it to make retries idempotent; the broker generates one otherwise
+    id: Option<String>,
+    // Alternative dedup key for producers that number their messages
+    producer_id: Option<String>,
+    sequence: Option<u64>,
+    // Text body; binary payloads go in `payload_base64` instead
+    message: Option<String>,
+    payload_base64: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    content_type: Option<String>,
+    correlation_id: Option<String>,
+    reply_to: Option<String>,
+    // Producer timestamp in Unix milliseconds; the broker fills it in when absent
+    timestamp: Option<u64>,
+    #[serde(default)]
+    priority: u8,
+    // Unix time in milliseconds to deliver at, or a delay relative to now
+    deliver_at: Option<u64>,
+    delay_secs: Option<u64>,
+    // Overrides the queue's default TTL for this message
+    ttl_secs: Option<u64>,
+    // Compaction key for compacted streams
+    key: Option<String>,
+}
+
+impl MessageBody {
+    fn into_message(self) -> Result<Message, String> {
+        if self.priority > MAX_PRIORITY {
+            return Err(format!("Priority must be between 0 and {}", MAX_PRIORITY));
+        }
+        let payload = match (self.message, self.payload_base64) {
+            (_, Some(encoded)) => BASE64.decode(encoded).map_err(|e| format!("Invalid payload_base64: {}", e))?,
+            (Some(text), None) => text.into_bytes(),
+            (None, None) => return Err("Either message or payload_base64 is required".to_string()),
+        };
+        let mut headers = self.headers;
+        if let (Some(producer_id), Some(sequence)) = (self.producer_id, self.sequence) {
+            headers.insert(PRODUCER_ID_HEADER.to_string(), producer_id);
+            headers.insert(PRODUCER_SEQ_HEADER.to_string(), sequence.to_string());
+        }
+        Ok(Message {
+            id: self.id.unwrap_or_else(|| Uuid::new_v4().to_string()),
+            payload,
+            headers,
+            timestamp: self.timestamp,
+            content_type: self.content_type,
+            correlation_id: self.correlation_id,
+            reply_to: self.reply_to,
+            priority: self.priority,
+            deliver_at: self.deliver_at
+                .or_else(|| self.delay_secs.map(|secs| now_millis() + secs * 1000)),
+            expires_at: self.ttl_secs.map(|secs| now_millis() + secs * 1000),
+            key: self.key,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct BatchPublishResponse {
+    published: usize,
+    // Positions in the request of messages recognised as retries
+    duplicates: Vec<usize>,
+    // Positions of messages that missed the write quorum and should be retried
+    unreplicated: Vec<usize>,
+    // Positions of messages that were not stored because their queue was unavailable
+    unavailable: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+struct ConsumeQuery {
+    // How long to wait for a message when the queue is empty, e.g. `30s`
+    wait: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BatchConsumeQuery {
+    max: Option<usize>,
+    // How long to wait for the first message, e.g. `30s` or `500ms`
+    wait: Option<String>,
+}
+
+const DEFAULT_BATCH_SIZE: usize = 100;
+
+// Longest a consume request may hold the connection open
+const MAX_CONSUME_WAIT: Duration = Duration::from_secs(300);
+
+#[derive(Deserialize)]
+struct EdgeSyncRequest {
+    node_id: String,
+    // (row id, topic, JSON payload, Unix seconds) as stored by the edge node
+    messages: Vec<(i64, String, String, i64)>,
+}
+
+#[derive(Deserialize)]
+struct DedupWindowRequest {
+    window_secs: u64,
+}
+
+#[derive(Serialize)]
+struct TransactionResponse {
+    transaction_id: String,
+}
+
+#[derive(Deserialize)]
+struct SubscriptionRequest {
+    queue_name: String,
+    subscriber_queue: String,
+}
+
+#[derive(Deserialize)]
+struct DeclareExchangeRequest {
+    kind: ExchangeKind,
+}
+
+#[derive(Deserialize)]
+struct TtlRequest {
+    // None clears the queue's default TTL
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct PriorityModeRequest {
+    mode: PriorityMode,
 }
 
 #[derive(Serialize)]
 struct MessageResponse {
     id: String,
-    content: String,
+    payload_base64: String,
+    headers: HashMap<String, String>,
+    priority: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delivery_tag: Option<String>,
+}
+
+impl From<Message> for MessageResponse {
+    fn from(message: Message) -> Self {
+        MessageResponse {
+            id: message.id,
+            payload_base64: BASE64.encode(&message.payload),
+            headers: message.headers,
+            priority: message.priority,
+            timestamp: message.timestamp,
+            content_type: message.content_type,
+            correlation_id: message.correlation_id,
+            reply_to: message.reply_to,
+            key: message.key,
+            delivery_tag: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PartitionsRequest {
+    partitions: u32,
+}
+
+#[derive(Deserialize)]
+struct ReplicationRequest {
+    replication_factor: usize,
+    // Copies that must confirm a write; a majority of the replicas by default
+    write_quorum: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct JoinGroupRequest {
+    queue_name: String,
+    member_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    // `earliest`, `latest`, `timestamp:<millis>` or an offset
+    from: Option<String>,
+    // Without `from`, resume from this consumer's committed offset
+    consumer: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    // Where to start when the request has no `Last-Event-ID`; defaults to `latest`
+    from: Option<String>,
+    // Queues only: `consume` takes messages off the queue instead of watching a copy
+    mode: Option<String>,
+    // Exchanges only: binding key selecting the messages to watch
+    pattern: Option<String>,
+}
+
+// How long an event stream waits for new messages before sending a keep-alive,
+// which is also how a closed connection is noticed
+const EVENTS_KEEPALIVE: Duration = Duration::from_secs(15);
+
+// Response body fed through a channel, for server-sent events
+struct ChannelBody(mpsc::Receiver<web::Bytes>);
+
+impl ResponseBody for ChannelBody {
+    type Error = std::convert::Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<web::Bytes, Self::Error>>> {
+        self.0.poll_recv(cx).map(|chunk| chunk.map(Ok))
+    }
+}
+
+#[derive(Deserialize)]
+struct CompactionRequest {
+    // None turns compaction off
+    compaction: Option<CompactionPolicy>,
+}
+
+#[derive(Deserialize)]
+struct CommitOffsetRequest {
+    offset: u64,
+}
+
+const DEFAULT_STREAM_READ_LIMIT: usize = 100;
+
+#[derive(Serialize)]
+struct StreamRecordResponse {
+    offset: u64,
+    #[serde(flatten)]
+    message: MessageResponse,
+}
+
+#[derive(Serialize)]
+struct SeekResponse {
+    offset: u64,
+    first_offset: u64,
+    next_offset: u64,
+}
+
+#[derive(Deserialize)]
+struct LimitQuery {
+    limit: Option<usize>,
+}
+
+const DEFAULT_DLQ_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct AddNodeRequest {
+    node_id: u64,
+    address: String,
+}
+
+async fn authenticate(req: HttpRequest, credentials: web::Json<Credentials>) -> impl Responder {
+    // In a real-world scenario, you would check against a database
+    let hashed_password = hash(&credentials.password, 4).unwrap();
+    if credentials.username == "admin" && verify(&credentials.password, &hashed_password).unwrap() {
+        // In a real-world scenario, you would generate and store a session token
+        HttpResponse::Ok().body("Authenticated")
+    } else {
+        HttpResponse::Unauthorized().body("Invalid credentials")
+    }
+}
+
+async fn create_queue(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    queue_name: web::Path<String>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    rapidmq.create_queue(&queue_name);
+    HttpResponse::Ok().body(format!("Queue '{}' created", queue_name))
+}
+
+async fn publish_message(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    req_body: web::Json<PublishRequest>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let req_body = req_body.into_inner();
+    let message = match req_body.body.into_message() {
+        Ok(message) => message,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    match rapidmq.publish(&req_body.queue_name, message).await {
+        PublishOutcome::Published => HttpResponse::Ok().body("Message published"),
+        PublishOutcome::Duplicate => HttpResponse::Ok()
+            .insert_header(("X-Duplicate", "true"))
+            .body("Duplicate message ignored"),
+        PublishOutcome::Unreplicated => HttpResponse::ServiceUnavailable()
+            .body("Message not confirmed by enough replicas; retry"),
+        PublishOutcome::Unavailable => HttpResponse::ServiceUnavailable()
+            .body("Queue unavailable; retry"),
+    }
+}
+
+// Accepts a JSON array of messages, or one JSON message per line when sent
+// as `application/x-ndjson`
+async fn publish_batch(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    queue_name: web::Path<String>,
+    body: web::Bytes,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let bodies: Vec<MessageBody> = if is_ndjson(&req, "Content-Type") {
+        let parsed: Result<Vec<MessageBody>, _> = body
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+            .map(serde_json::from_slice)
+            .collect();
+        match parsed {
+            Ok(bodies) => bodies,
+            Err(e) => return HttpResponse::BadRequest().body(format!("Invalid NDJSON: {}", e)),
+        }
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(bodies) => bodies,
+            Err(e) => return HttpResponse::BadRequest().body(format!("Invalid JSON: {}", e)),
+        }
+    };
+    let mut messages = Vec::with_capacity(bodies.len());
+    for (index, body) in bodies.into_iter().enumerate() {
+        match body.into_message() {
+            Ok(message) => messages.push(message),
+            Err(e) => return HttpResponse::BadRequest().body(format!("Message {}: {}", index, e)),
+        }
+    }
+    let outcomes = rapidmq.publish_batch(&queue_name, messages).await;
+    let positions = |wanted: PublishOutcome| -> Vec<usize> {
+        outcomes.iter()
+            .enumerate()
+            .filter(|(_, outcome)| **outcome == wanted)
+            .map(|(index, _)| index)
+            .collect()
+    };
+    let duplicates = positions(PublishOutcome::Duplicate);
+    let unreplicated = positions(PublishOutcome::Unreplicated);
+    let unavailable = positions(PublishOutcome::Unavailable);
+    HttpResponse::Ok().json(BatchPublishResponse {
+        published: outcomes.len() - duplicates.len() - unreplicated.len() - unavailable.len(),
+        duplicates,
+        unreplicated,
+        unavailable,
+    })
+}
+
+async fn edge_sync(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    req_body: web::Json<EdgeSyncRequest>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let req_body = req_body.into_inner();
+    let mut processed = Vec::with_capacity(req_body.messages.len());
+    for (row_id, topic, payload, timestamp) in req_body.messages {
+        // The edge node's row id is its sequence number, so a batch resent after a
+        // lost response is recognised as duplicates
+        let mut headers = HashMap::new();
+        headers.insert(PRODUCER_ID_HEADER.to_string(), req_body.node_id.clone());
+        headers.insert(PRODUCER_SEQ_HEADER.to_string(), row_id.to_string());
+        let message = Message {
+            id: Uuid::new_v4().to_string(),
+            payload: payload.into_bytes(),
+            headers,
+            timestamp: Some(timestamp.max(0) as u64 * 1000),
+            content_type: Some("application/json".to_string()),
+            ..Default::default()
+        };
+        // Duplicates are already stored, so the edge node may mark them sent too.
+        // Anything else stays unprocessed for the edge node to resend.
+        match rapidmq.publish(&topic, message).await {
+            PublishOutcome::Published | PublishOutcome::Duplicate => processed.push(row_id),
+            PublishOutcome::Unreplicated | PublishOutcome::Unavailable => {}
+        }
+    }
+    HttpResponse::Ok().json(processed)
+}
+
+async fn set_dedup_window(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    req_body: web::Json<DedupWindowRequest>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    rapidmq.set_dedup_window(Duration::from_secs(req_body.window_secs));
+    HttpResponse::Ok().body(format!("Dedup window set to {}s", req_body.window_secs))
+}
+
+async fn consume_message(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    queue_name: web::Path<String>,
+    query: web::Query<ConsumeQuery>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let wait = match query.wait.as_deref().map(parse_wait).transpose() {
+        Ok(wait) => wait.unwrap_or(Duration::ZERO),
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    if let Some(delivery) = rapidmq.consume_wait(&queue_name, wait).await {
+        if wants_json(&req) {
+            let mut response = MessageResponse::from(delivery.message);
+            response.delivery_tag = Some(delivery.tag);
+            return HttpResponse::Ok().json(response);
+        }
+        let proto_message: RapidMQMessage = delivery.message.into();
+        let encoded = proto_message.encode_to_vec();
+        HttpResponse::Ok()
+            .insert_header(("X-Delivery-Tag", delivery.tag))
+            .body(encoded)
+    } else {
+        HttpResponse::NotFound().body("No messages in queue")
+    }
+}
+
+async fn consume_batch(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    queue_name: web::Path<String>,
+    query: web::Query<BatchConsumeQuery>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let wait = match query.wait.as_deref().map(parse_wait).transpose() {
+        Ok(wait) => wait.unwrap_or(Duration::ZERO),
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let max = query.max.unwrap_or(DEFAULT_BATCH_SIZE);
+    let responses: Vec<MessageResponse> = rapidmq.consume_batch(&queue_name, max, wait).await
+        .into_iter()
+        .map(|delivery| {
+            let mut response = MessageResponse::from(delivery.message);
+            response.delivery_tag = Some(delivery.tag);
+            response
+        })
+        .collect();
+    if is_ndjson(&req, "Accept") {
+        let mut body = String::new();
+        for response in &responses {
+            body.push_str(&serde_json::to_string(response).unwrap());
+            body.push('\n');
+        }
+        return HttpResponse::Ok().content_type("application/x-ndjson").body(body);
+    }
+    HttpResponse::Ok().json(responses)
+}
+
+async fn ack_message(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let (queue_name, delivery_tag) = path.into_inner();
+    if rapidmq.ack(&queue_name, &delivery_tag).await {
+        HttpResponse::Ok().body("Message acknowledged")
+    } else {
+        HttpResponse::NotFound().body("Unknown or expired delivery tag")
+    }
+}
+
+async fn nack_message(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let (queue_name, delivery_tag) = path.into_inner();
+    if rapidmq.nack(&queue_name, &delivery_tag).await {
+        HttpResponse::Ok().body("Message requeued")
+    } else {
+        HttpResponse::NotFound().body("Unknown or expired delivery tag")
+    }
+}
+
+async fn begin_transaction(req: HttpRequest, rapidmq: web::Data<RapidMQ>) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    HttpResponse::Ok().json(TransactionResponse {
+        transaction_id: rapidmq.begin_transaction(),
+    })
+}
+
+async fn transactional_publish(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    tx_id: web::Path<String>,
+    req_body: web::Json<PublishRequest>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let req_body = req_body.into_inner();
+    let message = match req_body.body.into_message() {
+        Ok(message) => message,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    match rapidmq.transactional_publish(&tx_id, &req_body.queue_name, message) {
+        Ok(()) => HttpResponse::Ok().body("Publish added to transaction"),
+        Err(e) => HttpResponse::NotFound().body(e),
+    }
+}
+
+async fn transactional_ack(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    path: web::Path<(String, String, String)>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let (tx_id, queue_name, delivery_tag) = path.into_inner();
+    match rapidmq.transactional_ack(&tx_id, &queue_name, &delivery_tag) {
+        Ok(()) => HttpResponse::Ok().body("Ack added to transaction"),
+        Err(e) => HttpResponse::NotFound().body(e),
+    }
+}
+
+async fn commit_transaction(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    tx_id: web::Path<String>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    match rapidmq.commit_transaction(&tx_id).await {
+        Ok(()) => HttpResponse::Ok().body("Transaction committed"),
+        Err(e) => HttpResponse::Conflict().body(e),
+    }
+}
+
+async fn abort_transaction(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    tx_id: web::Path<String>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    if rapidmq.abort_transaction(&tx_id).await {
+        HttpResponse::Ok().body("Transaction aborted")
+    } else {
+        HttpResponse::NotFound().body("Transaction not found")
+    }
+}
+
+async fn subscribe(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    req_body: web::Json<SubscriptionRequest>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    match rapidmq.subscribe(&req_body.queue_name, &req_body.subscriber_queue) {
+        Ok(true) => HttpResponse::Ok().body(format!("'{}' subscribed to '{}'", req_body.subscriber_queue, req_body.queue_name)),
+        Ok(false) => HttpResponse::Ok().body("Subscription already exists"),
+        Err(e) => HttpResponse::ServiceUnavailable().body(e),
+    }
+}
+
+async fn unsubscribe(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    req_body: web::Json<SubscriptionRequest>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    match rapidmq.unsubscribe(&req_body.queue_name, &req_body.subscriber_queue) {
+        Ok(true) => HttpResponse::Ok().body(format!("'{}' unsubscribed from '{}'", req_body.subscriber_queue, req_body.queue_name)),
+        Ok(false) => HttpResponse::NotFound().body("Subscription not found"),
+        Err(e) => HttpResponse::ServiceUnavailable().body(e),
+    }
+}
+
+async fn list_subscriptions(req: HttpRequest, rapidmq: web::Data<RapidMQ>) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    HttpResponse::Ok().json(rapidmq.subscriptions())
+}
+
+async fn list_queue_subscribers(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    queue_name: web::Path<String>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    HttpResponse::Ok().json(rapidmq.subscribers_of(&queue_name))
+}
+
+async fn declare_exchange(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    name: web::Path<String>,
+    req_body: web::Json<DeclareExchangeRequest>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    match rapidmq.declare_exchange(&name, req_body.kind) {
+        Ok(()) => HttpResponse::Ok().body(format!("Exchange '{}' declared", name)),
+        Err(e) => HttpResponse::Conflict().body(e),
+    }
+}
+
+async fn delete_exchange(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    name: web::Path<String>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    match rapidmq.delete_exchange(&name) {
+        Ok(true) => HttpResponse::Ok().body(format!("Exchange '{}' deleted", name)),
+        Ok(false) => HttpResponse::NotFound().body("Exchange not found"),
+        Err(e) => HttpResponse::ServiceUnavailable().body(e),
+    }
+}
+
+async fn list_exchanges(req: HttpRequest, rapidmq: web::Data<RapidMQ>) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    HttpResponse::Ok().json(rapidmq.list_exchanges())
+}
+
+async fn bind_queue(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    name: web::Path<String>,
+    binding: web::Json<Binding>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    match rapidmq.bind_queue(&name, binding.into_inner()) {
+        Ok(_) => HttpResponse::Ok().body("Binding added"),
+        Err(e) => HttpResponse::NotFound().body(e),
+    }
+}
+
+async fn unbind_queue(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    name: web::Path<String>,
+    binding: web::Json<Binding>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    match rapidmq.unbind_queue(&name, &binding) {
+        Ok(true) => HttpResponse::Ok().body("Binding removed"),
+        Ok(false) => HttpResponse::NotFound().body("Binding not found"),
+        Err(e) => HttpResponse::NotFound().body(e),
+    }
+}
+
+async fn publish_to_exchange(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    name: web::Path<String>,
+    req_body: web::Json<ExchangePublishRequest>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let req_body = req_body.into_inner();
+    let message = match req_body.body.into_message() {
+        Ok(message) => message,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    match rapidmq.publish_to_exchange(&name, &req_body.routing_key, message).await {
+        Ok(routed) => HttpResponse::Ok().body(format!("Message routed to {} queues", routed)),
+        Err(e) => HttpResponse::NotFound().body(e),
+    }
+}
+
+async fn set_priority_mode(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    queue_name: web::Path<String>,
+    req_body: web::Json<PriorityModeRequest>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    if rapidmq.set_priority_mode(&queue_name, req_body.mode) {
+        HttpResponse::Ok().body(format!("Priority mode set for queue '{}'", queue_name))
+    } else {
+        HttpResponse::NotFound().body("Queue not found on this node")
+    }
+}
+
+async fn set_default_ttl(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    queue_name: web::Path<String>,
+    req_body: web::Json<TtlRequest>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let ttl = req_body.ttl_secs.map(Duration::from_secs);
+    if rapidmq.set_default_ttl(&queue_name, ttl) {
+        HttpResponse::Ok().body(format!("Default TTL set for queue '{}'", queue_name))
+    } else {
+        HttpResponse::NotFound().body("Queue not found on this node")
+    }
 }
 
-#[derive(Deserialize)]
-struct Credentials {
-    username: String,
-    password: String,
+async fn set_dead_letter_policy(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    queue_name: web::Path<String>,
+    policy: web::Json<DeadLetterPolicy>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    if rapidmq.set_dead_letter_policy(&queue_name, policy.into_inner()) {
+        HttpResponse::Ok().body(format!("Dead-letter policy set for queue '{}'", queue_name))
+    } else {
+        HttpResponse::NotFound().body("Queue not found on this node")
+    }
 }
 
-#[derive(Deserialize)]
-struct AddNodeRequest {
-    node_id: u64,
-    address: String,
+async fn list_dead_letters(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    queue_name: web::Path<String>,
+    query: web::Query<LimitQuery>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_DLQ_LIMIT);
+    let messages: Vec<MessageResponse> = rapidmq.peek_dead_letters(&queue_name, limit)
+        .into_iter()
+        .map(MessageResponse::from)
+        .collect();
+    HttpResponse::Ok().json(messages)
 }
 
-async fn authenticate(req: HttpRequest, credentials: web::Json<Credentials>) -> impl Responder {
-    // In a real-world scenario, you would check against a database
-    let hashed_password = hash(&credentials.password, 4).unwrap();
-    if credentials.username == "admin" && verify(&credentials.password, &hashed_password).unwrap() {
-        // In a real-world scenario, you would generate and store a session token
-        HttpResponse::Ok().body("Authenticated")
-    } else {
-        HttpResponse::Unauthorized().body("Invalid credentials")
+async fn redrive_dead_letters(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    queue_name: web::Path<String>,
+    query: web::Query<LimitQuery>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
     }
+    let limit = query.limit.unwrap_or(DEFAULT_DLQ_LIMIT);
+    let redriven = rapidmq.redrive(&queue_name, limit).await;
+    HttpResponse::Ok().body(format!("Re-drove {} messages from '{}'", redriven, queue_name))
 }
 
-async fn create_queue(
+async fn create_partitioned_queue(
     req: HttpRequest,
     rapidmq: web::Data<RapidMQ>,
     queue_name: web::Path<String>,
+    req_body: web::Json<PartitionsRequest>,
 ) -> impl Responder {
     if !is_authenticated(&req) {
         return HttpResponse::Unauthorized().body("Authentication required");
     }
-    rapidmq.create_queue(&queue_name);
-    HttpResponse::Ok().body(format!("Queue '{}' created", queue_name))
+    if req_body.partitions == 0 {
+        return HttpResponse::BadRequest().body("partitions must be at least 1");
+    }
+    rapidmq.create_partitioned_queue(&queue_name, req_body.partitions);
+    HttpResponse::Ok().body(format!("Queue '{}' created with {} partitions", queue_name, req_body.partitions))
 }
 
-async fn publish_message(
+async fn create_replicated_queue(
     req: HttpRequest,
     rapidmq: web::Data<RapidMQ>,
-    req_body: web::Json<PublishRequest>,
+    queue_name: web::Path<String>,
+    req_body: web::Json<ReplicationRequest>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let write_quorum = req_body.write_quorum.unwrap_or(req_body.replication_factor / 2 + 1);
+    match rapidmq.create_replicated_queue(&queue_name, req_body.replication_factor, write_quorum) {
+        Ok(replicas) => HttpResponse::Ok().json(replicas),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+async fn join_group(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    group: web::Path<String>,
+    req_body: web::Json<JoinGroupRequest>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let req_body = req_body.into_inner();
+    match rapidmq.join_group(&group, &req_body.queue_name, req_body.member_id).await {
+        Ok(assignment) => HttpResponse::Ok().json(assignment),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+async fn group_heartbeat(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let (group, member_id) = path.into_inner();
+    match rapidmq.heartbeat(&group, &member_id) {
+        Ok(assignment) => HttpResponse::Ok().json(assignment),
+        Err(e) => HttpResponse::NotFound().body(e),
+    }
+}
+
+async fn leave_group(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let (group, member_id) = path.into_inner();
+    match rapidmq.leave_group(&group, &member_id) {
+        Ok(true) => HttpResponse::Ok().body(format!("Member '{}' left group '{}'", member_id, group)),
+        Ok(false) => HttpResponse::NotFound().body("Member not found"),
+        Err(e) => HttpResponse::ServiceUnavailable().body(e),
+    }
+}
+
+async fn describe_group(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    group: web::Path<String>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    match rapidmq.describe_group(&group) {
+        Some(description) => HttpResponse::Ok().json(description),
+        None => HttpResponse::NotFound().body("Group not found"),
+    }
+}
+
+async fn consume_group(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let (group, member_id) = path.into_inner();
+    match rapidmq.consume_group(&group, &member_id).await {
+        Ok(Some(group_delivery)) => {
+            let delivery = group_delivery.delivery;
+            if wants_json(&req) {
+                let mut response = MessageResponse::from(delivery.message);
+                response.delivery_tag = Some(delivery.tag);
+                return HttpResponse::Ok()
+                    .insert_header(("X-Partition-Queue", group_delivery.queue_name))
+                    .json(response);
+            }
+            let proto_message: RapidMQMessage = delivery.message.into();
+            HttpResponse::Ok()
+                .insert_header(("X-Delivery-Tag", delivery.tag))
+                .insert_header(("X-Partition-Queue", group_delivery.queue_name))
+                .body(proto_message.encode_to_vec())
+        }
+        Ok(None) => HttpResponse::NotFound().body("No messages in assigned partitions"),
+        Err(e) => HttpResponse::NotFound().body(e),
+    }
+}
+
+async fn create_stream(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    stream_name: web::Path<String>,
+    retention: Option<web::Json<RetentionPolicy>>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let retention = retention.map(|r| r.into_inner()).unwrap_or_default();
+    match rapidmq.create_stream(&stream_name, retention) {
+        Ok(_) => HttpResponse::Ok().body(format!("Stream '{}' created", stream_name)),
+        Err(e) => HttpResponse::ServiceUnavailable().body(e),
+    }
+}
+
+async fn set_stream_retention(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    stream_name: web::Path<String>,
+    retention: web::Json<RetentionPolicy>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    if rapidmq.set_stream_retention(&stream_name, retention.into_inner()) {
+        HttpResponse::Ok().body(format!("Retention set for stream '{}'", stream_name))
+    } else {
+        HttpResponse::NotFound().body("Stream not found on this node")
+    }
+}
+
+async fn set_stream_compaction(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    stream_name: web::Path<String>,
+    req_body: web::Json<CompactionRequest>,
 ) -> impl Responder {
     if !is_authenticated(&req) {
         return HttpResponse::Unauthorized().body("Authentication required");
     }
-    let message = Message {
-        id: Uuid::new_v4().to_string(),
-        content: req_body.message.clone(),
+    if rapidmq.set_stream_compaction(&stream_name, req_body.into_inner().compaction) {
+        HttpResponse::Ok().body(format!("Compaction updated for stream '{}'", stream_name))
+    } else {
+        HttpResponse::NotFound().body("Stream not found on this node")
+    }
+}
+
+fn start_offset(rapidmq: &RapidMQ, stream_name: &str, query: &StreamQuery) -> Result<StartOffset, String> {
+    if let Some(from) = query.from.as_deref() {
+        return from.parse();
+    }
+    let committed = query.consumer.as_deref().and_then(|consumer| rapidmq.committed_offset(stream_name, consumer));
+    Ok(committed.map_or(StartOffset::Earliest, StartOffset::Offset))
+}
+
+async fn read_stream(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    stream_name: web::Path<String>,
+    query: web::Query<StreamQuery>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let start = match start_offset(&rapidmq, &stream_name, &query) {
+        Ok(start) => start,
+        Err(e) => return HttpResponse::BadRequest().body(e),
     };
-    rapidmq.publish(&req_body.queue_name, message).await;
-    HttpResponse::Ok().body("Message published")
+    let limit = query.limit.unwrap_or(DEFAULT_STREAM_READ_LIMIT);
+    match rapidmq.read_stream(&stream_name, start, limit) {
+        Some(records) => {
+            let records: Vec<StreamRecordResponse> = records
+                .into_iter()
+                .map(|record| StreamRecordResponse {
+                    offset: record.offset,
+                    message: MessageResponse::from(record.message),
+                })
+                .collect();
+            HttpResponse::Ok().json(records)
+        }
+        None => HttpResponse::NotFound().body("Stream not found on this node"),
+    }
 }
 
-async fn consume_message(
+async fn seek_stream(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    stream_name: web::Path<String>,
+    query: web::Query<StreamQuery>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let start = match start_offset(&rapidmq, &stream_name, &query) {
+        Ok(start) => start,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    match rapidmq.seek_stream(&stream_name, start) {
+        Some((offset, first_offset, next_offset)) => HttpResponse::Ok().json(SeekResponse { offset, first_offset, next_offset }),
+        None => HttpResponse::NotFound().body("Stream not found on this node"),
+    }
+}
+
+async fn commit_offset(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    path: web::Path<(String, String)>,
+    req_body: web::Json<CommitOffsetRequest>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let (stream_name, consumer) = path.into_inner();
+    match rapidmq.commit_offset(&stream_name, &consumer, req_body.offset) {
+        Ok(()) => HttpResponse::Ok().body(format!("Offset {} committed for '{}'", req_body.offset, consumer)),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+async fn fetch_offset(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let (stream_name, consumer) = path.into_inner();
+    match rapidmq.consumer_offset(&stream_name, &consumer) {
+        Some(offset) => HttpResponse::Ok().json(offset),
+        None => HttpResponse::NotFound().body("Stream not found on this node"),
+    }
+}
+
+async fn list_offsets(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    stream_name: web::Path<String>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    match rapidmq.consumer_offsets(&stream_name) {
+        Some(offsets) => HttpResponse::Ok().json(offsets),
+        None => HttpResponse::NotFound().body("Stream not found on this node"),
+    }
+}
+
+// Server-sent events of a stream, one event per record with the offset as
+// its id, so a reconnecting EventSource resumes after the last record it saw
+async fn stream_events(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    stream_name: web::Path<String>,
+    query: web::Query<EventsQuery>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    tail_stream(&req, rapidmq.get_ref().clone(), stream_name.into_inner(), None, &query)
+}
+
+// Server-sent events of a queue. By default a copy of each message is shown
+// through the queue's tap stream and the queue itself is left alone; with
+// `mode=consume` messages are consumed and acked once written to the response.
+async fn queue_events(
     req: HttpRequest,
     rapidmq: web::Data<RapidMQ>,
     queue_name: web::Path<String>,
+    query: web::Query<EventsQuery>,
 ) -> impl Responder {
     if !is_authenticated(&req) {
         return HttpResponse::Unauthorized().body("Authentication required");
     }
-    if let Some(message) = rapidmq.consume(&queue_name).await {
-        let proto_message: RapidMQMessage = message.into();
-        let encoded = proto_message.encode_to_vec();
-        HttpResponse::Ok().body(encoded)
-    } else {
-        HttpResponse::NotFound().body("No messages in queue")
+    let queue_name = queue_name.into_inner();
+    match query.mode.as_deref() {
+        None | Some("tail") => {
+            match rapidmq.tap_queue(&queue_name) {
+                Ok(tap) => tail_stream(&req, rapidmq.get_ref().clone(), tap.stream().to_string(), Some(tap), &query),
+                Err(e) => HttpResponse::NotFound().body(e),
+            }
+        }
+        Some("consume") => {
+            if rapidmq.queue_node(&queue_name).is_none() {
+                return HttpResponse::NotFound().body(format!("Queue '{}' not found", queue_name));
+            }
+            let rapidmq = rapidmq.get_ref().clone();
+            // With room for a single event, a send only goes through once the
+            // response has taken the event before it. The delivery behind that
+            // event is acked then, or nacked if the client goes away first.
+            let (tx, rx) = mpsc::channel(1);
+            actix_web::rt::spawn(async move {
+                let mut unwritten: Option<String> = None;
+                loop {
+                    let started = tokio::time::Instant::now();
+                    let (event, tag) = match rapidmq.consume_wait(&queue_name, EVENTS_KEEPALIVE).await {
+                        Some(delivery) => {
+                            let mut response = MessageResponse::from(delivery.message);
+                            response.delivery_tag = Some(delivery.tag.clone());
+                            let event = format!("event: message\ndata: {}\n\n", serde_json::to_string(&response).unwrap());
+                            (event, Some(delivery.tag))
+                        }
+                        None => {
+                            // An early None means the queue's node is down or the queue
+                            // is gone; hold off instead of retrying straight away
+                            tokio::time::sleep_until(started + EVENTS_KEEPALIVE).await;
+                            (": keep-alive\n\n".to_string(), None)
+                        }
+                    };
+                    if tx.send(event.into()).await.is_err() {
+                        for tag in unwritten.iter().chain(tag.iter()) {
+                            rapidmq.nack(&queue_name, tag).await;
+                        }
+                        return;
+                    }
+                    if let Some(written) = std::mem::replace(&mut unwritten, tag) {
+                        rapidmq.ack(&queue_name, &written).await;
+                    }
+                }
+            });
+            event_stream_response(rx)
+        }
+        Some(other) => HttpResponse::BadRequest().body(format!("Unknown mode '{}'; use tail or consume", other)),
+    }
+}
+
+// Server-sent events of the messages an exchange routes with `pattern`
+async fn exchange_events(
+    req: HttpRequest,
+    rapidmq: web::Data<RapidMQ>,
+    exchange: web::Path<String>,
+    query: web::Query<EventsQuery>,
+) -> impl Responder {
+    if !is_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("Authentication required");
+    }
+    let Some(pattern) = query.pattern.as_deref() else {
+        return HttpResponse::BadRequest().body("pattern is required");
+    };
+    match rapidmq.tap_exchange(&exchange, pattern) {
+        Ok(tap) => tail_stream(&req, rapidmq.get_ref().clone(), tap.stream().to_string(), Some(tap), &query),
+        Err(e) => HttpResponse::NotFound().body(e),
     }
 }
 
+// Streams records as server-sent events, starting after `Last-Event-ID` when
+// the client is reconnecting and at `from` otherwise. A tap feed is held until
+// the client disconnects, so the tap stops filling once nobody is watching.
+fn tail_stream(req: &HttpRequest, rapidmq: RapidMQ, stream_name: String, feed: Option<Tap>, query: &EventsQuery) -> HttpResponse {
+    let last_event_id = req.headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok());
+    let start = match (last_event_id, query.from.as_deref()) {
+        (Some(last), _) => StartOffset::Offset(last + 1),
+        (None, Some(from)) => match from.parse() {
+            Ok(start) => start,
+            Err(e) => return HttpResponse::BadRequest().body(e),
+        },
+        (None, None) => StartOffset::Latest,
+    };
+    let Some((mut next, _, _)) = rapidmq.seek_stream(&stream_name, start) else {
+        return HttpResponse::NotFound().body("Stream not found on this node");
+    };
+
+    let (tx, rx) = mpsc::channel(16);
+    actix_web::rt::spawn(async move {
+        let _feed = feed;
+        loop {
+            let Some(records) = rapidmq.read_stream_wait(&stream_name, StartOffset::Offset(next), DEFAULT_STREAM_READ_LIMIT, EVENTS_KEEPALIVE).await else {
+                // The stream was removed from this node
+                return;
+            };
+            let mut chunk = String::new();
+            for record in records {
+                next = record.offset + 1;
+                let record = StreamRecordResponse {
+                    offset: record.offset,
+                    message: MessageResponse::from(record.message),
+                };
+                chunk.push_str(&format!("id: {}\nevent: message\ndata: {}\n\n", record.offset, serde_json::to_string(&record).unwrap()));
+            }
+            if chunk.is_empty() {
+                chunk.push_str(": keep-alive\n\n");
+            }
+            if tx.send(chunk.into()).await.is_err() {
+                return;
+            }
+        }
+    });
+    event_stream_response(rx)
+}
+
+fn event_stream_response(rx: mpsc::Receiver<web::Bytes>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .body(ChannelBody(rx))
+}
+
 async fn add_node(
     req: HttpRequest,
     rapidmq: web::Data<RapidMQ>,
@@ -116,6 +1286,34 @@ async fn remove_node(
     HttpResponse::Ok().body(format!("Node {} removed", node_id))
 }
 
+// Consumers get the protobuf envelope unless they ask for JSON
+fn wants_json(req: &HttpRequest) -> bool {
+    req.headers()
+        .get("Accept")
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |accept| accept.contains("application/json"))
+}
+
+fn is_ndjson(req: &HttpRequest, header: &str) -> bool {
+    req.headers()
+        .get(header)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.contains("application/x-ndjson"))
+}
+
+// Parses `30s`, `500ms` or a bare number of seconds, capped at MAX_CONSUME_WAIT
+fn parse_wait(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let parsed = if let Some(millis) = value.strip_suffix("ms") {
+        millis.parse().map(Duration::from_millis)
+    } else {
+        value.strip_suffix('s').unwrap_or(value).parse().map(Duration::from_secs)
+    };
+    parsed
+        .map(|wait| wait.min(MAX_CONSUME_WAIT))
+        .map_err(|_| format!("Invalid wait '{}'; use e.g. 30s or 500ms", value))
+}
+
 fn is_authenticated(req: &HttpRequest) -> bool {
     // In a real-world scenario, you would validate the session token
     req.headers().contains_key("Authorization")
@@ -128,8 +1326,15 @@ async fn metrics() -> impl Responder {
     HttpResponse::Ok().body(String::from_utf8(buffer).unwrap())
 }
 
-async fn ws_index(r: HttpRequest, stream: web::Payload) -> Result<HttpResponse, actix_web::Error> {
-    ws::start(MyWebSocket::new(), &r, stream)
+async fn ws_index(
+    req: HttpRequest,
+    stream: web::Payload,
+    rapidmq: web::Data<RapidMQ>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if !is_authenticated(&req) {
+        return Ok(HttpResponse::Unauthorized().body("Authentication required"));
+    }
+    ws::start(WebSocketSession::new(rapidmq.get_ref().clone()), &req, stream)
 }
 
 async fn ai_insights(rapidmq: web::Data<RapidMQ>) -> impl Responder {
@@ -148,8 +1353,52 @@ pub async fn start_api(rapidmq: RapidMQ) -> std::io::Result<()> {
             .service(Files::new("/dashboard", "static").index_file("dashboard.html"))
             .route("/authenticate", web::post().to(authenticate))
             .route("/queue/{name}", web::post().to(create_queue))
+            .route("/queue/{name}/priority_mode", web::put().to(set_priority_mode))
+            .route("/queue/{name}/ttl", web::put().to(set_default_ttl))
+            .route("/queue/{name}/dead_letter_policy", web::put().to(set_dead_letter_policy))
+            .route("/queue/{name}/partitions", web::post().to(create_partitioned_queue))
+            .route("/queue/{name}/replicated", web::post().to(create_replicated_queue))
+            .route("/dlq/{queue_name}", web::get().to(list_dead_letters))
+            .route("/dlq/{queue_name}/redrive", web::post().to(redrive_dead_letters))
             .route("/publish", web::post().to(publish_message))
+            .route("/publish_batch/{queue_name}", web::post().to(publish_batch))
+            .route("/edge_sync", web::post().to(edge_sync))
+            .route("/transactions", web::post().to(begin_transaction))
+            .route("/transactions/{tx_id}/publish", web::post().to(transactional_publish))
+            .route("/transactions/{tx_id}/ack/{queue_name}/{delivery_tag}", web::post().to(transactional_ack))
+            .route("/transactions/{tx_id}/commit", web::post().to(commit_transaction))
+            .route("/transactions/{tx_id}/abort", web::post().to(abort_transaction))
+            .route("/dedup/window", web::put().to(set_dedup_window))
+            .route("/subscriptions", web::get().to(list_subscriptions))
+            .route("/subscriptions", web::post().to(subscribe))
+            .route("/subscriptions", web::delete().to(unsubscribe))
+            .route("/subscriptions/{queue_name}", web::get().to(list_queue_subscribers))
+            .route("/exchanges", web::get().to(list_exchanges))
+            .route("/exchange/{name}", web::post().to(declare_exchange))
+            .route("/exchange/{name}", web::delete().to(delete_exchange))
+            .route("/exchange/{name}/bindings", web::post().to(bind_queue))
+            .route("/exchange/{name}/bindings", web::delete().to(unbind_queue))
+            .route("/exchange/{name}/publish", web::post().to(publish_to_exchange))
             .route("/consume/{queue_name}", web::get().to(consume_message))
+            .route("/consume_batch/{queue_name}", web::get().to(consume_batch))
+            .route("/stream/{name}", web::post().to(create_stream))
+            .route("/stream/{name}", web::get().to(read_stream))
+            .route("/stream/{name}/retention", web::put().to(set_stream_retention))
+            .route("/stream/{name}/compaction", web::put().to(set_stream_compaction))
+            .route("/stream/{name}/seek", web::get().to(seek_stream))
+            .route("/stream/{name}/offsets", web::get().to(list_offsets))
+            .route("/stream/{name}/offsets/{consumer}", web::get().to(fetch_offset))
+            .route("/stream/{name}/offsets/{consumer}", web::post().to(commit_offset))
+            .route("/events/stream/{name}", web::get().to(stream_events))
+            .route("/events/queue/{name}", web::get().to(queue_events))
+            .route("/events/exchange/{name}", web::get().to(exchange_events))
+            .route("/groups/{group}", web::get().to(describe_group))
+            .route("/groups/{group}/join", web::post().to(join_group))
+            .route("/groups/{group}/heartbeat/{member_id}", web::post().to(group_heartbeat))
+            .route("/groups/{group}/members/{member_id}", web::delete().to(leave_group))
+            .route("/groups/{group}/consume/{member_id}", web::get().to(consume_group))
+            .route("/ack/{queue_name}/{delivery_tag}", web::post().to(ack_message))
+            .route("/nack/{queue_name}/{delivery_tag}", web::post().to(nack_message))
             .route("/metrics", web::get().to(metrics))
             .route("/node", web::post().to(add_node))
             .route("/node/{node_id}", web::delete().to(remove_node))
@@ -161,30 +1410,237 @@ pub async fn start_api(rapidmq: RapidMQ) -> std::io::Result<()> {
     .await
 }
 
-struct MyWebSocket;
+// Longest one push consume waits on an empty queue before starting over
+const PUSH_WAIT: Duration = Duration::from_secs(30);
 
-impl MyWebSocket {
-    fn new() -> Self {
-        Self
+// How long a push subscription waits before trying again when its queue's
+// node could not be reached
+const PUSH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Frames sent by WebSocket clients as JSON text, e.g.
+// `{"type": "subscribe", "queue": "orders", "credit": 10}`
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientFrame {
+    // Starts pushing messages from a queue. Subscribing again adds the credit.
+    Subscribe { queue: String, #[serde(default)] credit: u32 },
+    // Allows `credit` more messages to be pushed from the queue
+    Credit { queue: String, credit: u32 },
+    Unsubscribe { queue: String },
+    Ack { queue: String, delivery_tag: String },
+    Nack { queue: String, delivery_tag: String },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerFrame {
+    Subscribed { queue: String, credit: u32 },
+    Unsubscribed { queue: String },
+    Message {
+        queue: String,
+        delivery_count: u32,
+        #[serde(flatten)]
+        message: MessageResponse,
+    },
+    Acked { queue: String, delivery_tag: String, ok: bool },
+    Nacked { queue: String, delivery_tag: String, ok: bool },
+    Error { error: String },
+}
+
+// Push state of one queue on a WebSocket connection. Every pushed message
+// uses up one credit; nothing is pushed while credit is zero.
+struct PushSubscription {
+    credit: u32,
+    // The consume currently waiting on the queue, or the pending retry, if any
+    pump: Option<SpawnHandle>,
+    // Pushed but not yet acked or nacked
+    unacked: HashSet<String>,
+}
+
+// A WebSocket connection pushing messages from the queues it subscribed to.
+// Unacked messages are nacked when the connection closes so they are
+// redelivered without waiting for the visibility timeout.
+struct WebSocketSession {
+    rapidmq: RapidMQ,
+    subscriptions: HashMap<String, PushSubscription>,
+}
+
+impl WebSocketSession {
+    fn new(rapidmq: RapidMQ) -> Self {
+        WebSocketSession {
+            rapidmq,
+            subscriptions: HashMap::new(),
+        }
+    }
+
+    fn send(ctx: &mut ws::WebsocketContext<Self>, frame: &ServerFrame) {
+        ctx.text(serde_json::to_string(frame).unwrap());
+    }
+
+    fn handle_frame(&mut self, frame: ClientFrame, ctx: &mut ws::WebsocketContext<Self>) {
+        match frame {
+            ClientFrame::Subscribe { queue, credit } => {
+                if self.rapidmq.queue_node(&queue).is_none() {
+                    let error = format!("Queue '{}' not found", queue);
+                    return WebSocketSession::send(ctx, &ServerFrame::Error { error });
+                }
+                let subscription = self.subscriptions.entry(queue.clone()).or_insert(PushSubscription {
+                    credit: 0,
+                    pump: None,
+                    unacked: HashSet::new(),
+                });
+                subscription.credit = subscription.credit.saturating_add(credit);
+                let credit = subscription.credit;
+                WebSocketSession::send(ctx, &ServerFrame::Subscribed { queue: queue.clone(), credit });
+                self.pump(&queue, ctx);
+            }
+            ClientFrame::Credit { queue, credit } => {
+                match self.subscriptions.get_mut(&queue) {
+                    Some(subscription) => subscription.credit = subscription.credit.saturating_add(credit),
+                    None => {
+                        let error = format!("Not subscribed to queue '{}'", queue);
+                        return WebSocketSession::send(ctx, &ServerFrame::Error { error });
+                    }
+                }
+                self.pump(&queue, ctx);
+            }
+            ClientFrame::Unsubscribe { queue } => {
+                if let Some(subscription) = self.subscriptions.remove(&queue) {
+                    if let Some(pump) = subscription.pump {
+                        ctx.cancel_future(pump);
+                    }
+                    self.release(&queue, subscription.unacked);
+                }
+                WebSocketSession::send(ctx, &ServerFrame::Unsubscribed { queue });
+            }
+            ClientFrame::Ack { queue, delivery_tag } => {
+                self.forget(&queue, &delivery_tag);
+                let rapidmq = self.rapidmq.clone();
+                let acked = async move {
+                    let ok = rapidmq.ack(&queue, &delivery_tag).await;
+                    ServerFrame::Acked { queue, delivery_tag, ok }
+                };
+                ctx.spawn(acked.into_actor(self).map(|frame, _, ctx| WebSocketSession::send(ctx, &frame)));
+            }
+            ClientFrame::Nack { queue, delivery_tag } => {
+                self.forget(&queue, &delivery_tag);
+                let rapidmq = self.rapidmq.clone();
+                let nacked = async move {
+                    let ok = rapidmq.nack(&queue, &delivery_tag).await;
+                    ServerFrame::Nacked { queue, delivery_tag, ok }
+                };
+                ctx.spawn(nacked.into_actor(self).map(|frame, _, ctx| WebSocketSession::send(ctx, &frame)));
+            }
+        }
+    }
+
+    // Waits for the next message of `queue` unless a wait is already running or
+    // the client has no credit left. Parks on the queue's wakeup signal, so an
+    // idle subscription does not poll.
+    fn pump(&mut self, queue: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let Some(subscription) = self.subscriptions.get_mut(queue) else {
+            return;
+        };
+        if subscription.pump.is_some() || subscription.credit == 0 {
+            return;
+        }
+        let rapidmq = self.rapidmq.clone();
+        let queue = queue.to_string();
+        let consume = async move {
+            let started = tokio::time::Instant::now();
+            let delivery = rapidmq.consume_wait(&queue, PUSH_WAIT).await;
+            (queue, delivery, started.elapsed() < PUSH_WAIT)
+        };
+        let handle = ctx.spawn(consume.into_actor(self).map(|(queue, delivery, early), session, ctx| {
+            session.push(queue, delivery, early, ctx);
+        }));
+        // The subscription is still there: the future cannot have run yet
+        if let Some(subscription) = self.subscriptions.get_mut(queue.as_str()) {
+            subscription.pump = Some(handle);
+        }
+    }
+
+    // `early` is set when the consume returned before its wait was up
+    fn push(&mut self, queue: String, delivery: Option<Delivery>, early: bool, ctx: &mut ws::WebsocketContext<Self>) {
+        // Unsubscribing cancels the pump, so the subscription is still there
+        let Some(subscription) = self.subscriptions.get_mut(&queue) else {
+            return;
+        };
+        subscription.pump = None;
+        if delivery.is_none() && early {
+            // The queue's node is down or the queue is gone; try again later
+            // instead of spinning, and keep the slot taken until then
+            let retry = ctx.run_later(PUSH_RETRY_DELAY, move |session, ctx| {
+                if let Some(subscription) = session.subscriptions.get_mut(&queue) {
+                    subscription.pump = None;
+                }
+                session.pump(&queue, ctx);
+            });
+            subscription.pump = Some(retry);
+            return;
+        }
+        if let Some(delivery) = delivery {
+            subscription.credit = subscription.credit.saturating_sub(1);
+            subscription.unacked.insert(delivery.tag.clone());
+            let mut message = MessageResponse::from(delivery.message);
+            message.delivery_tag = Some(delivery.tag);
+            WebSocketSession::send(ctx, &ServerFrame::Message {
+                queue: queue.clone(),
+                delivery_count: delivery.delivery_count,
+                message,
+            });
+        }
+        self.pump(&queue, ctx);
+    }
+
+    fn forget(&mut self, queue: &str, delivery_tag: &str) {
+        if let Some(subscription) = self.subscriptions.get_mut(queue) {
+            subscription.unacked.remove(delivery_tag);
+        }
+    }
+
+    // Hands unacked messages back to their queue for redelivery
+    fn release(&self, queue: &str, unacked: HashSet<String>) {
+        if unacked.is_empty() {
+            return;
+        }
+        let rapidmq = self.rapidmq.clone();
+        let queue = queue.to_string();
+        actix_web::rt::spawn(async move {
+            for delivery_tag in unacked {
+                rapidmq.nack(&queue, &delivery_tag).await;
+            }
+        });
     }
 }
 
-impl actix::Actor for MyWebSocket {
+impl actix::Actor for WebSocketSession {
     type Context = ws::WebsocketContext<Self>;
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        ctx.text("WebSocket connection established");
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        for (queue, subscription) in std::mem::take(&mut self.subscriptions) {
+            self.release(&queue, subscription.unacked);
+        }
     }
 }
 
-impl actix::StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWebSocket {
+impl actix::StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         match msg {
-            Ok(ws::Message::Text(text)) => ctx.text(text),
+            Ok(ws::Message::Text(text)) => match serde_json::from_str::<ClientFrame>(&text) {
+                Ok(frame) => self.handle_frame(frame, ctx),
+                Err(e) => WebSocketSession::send(ctx, &ServerFrame::Error { error: format!("Invalid frame: {}", e) }),
+            },
             Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
             Ok(ws::Message::Pong(_)) => (),
-            Ok(ws::Message::Binary(_)) => (),
-            Ok(ws::Message::Close(reason)) => ctx.close(reason),
+            Ok(ws::Message::Binary(_)) => {
+                WebSocketSession::send(ctx, &ServerFrame::Error { error: "Frames must be JSON text".to_string() });
+            }
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(_) => ctx.stop(),
             _ => (),
         }
     }
@@ -14,7 +14,8 @@ fn benchmark_publish(c: &mut Criterion) {
         b.iter(|| {
             let message = Message {
                 id: "1".to_string(),
-                content: "Test message".to_string(),
+                payload: b"Test message".to_vec(),
+                ..Default::default()
             };
             let proto_message: RapidMQMessage = message.into();
             let encoded = proto_message.encode_to_vec();
@@ -33,7 +34,8 @@ fn benchmark_consume(c: &mut Criterion) {
     for i in 0..1000 {
         let message = Message {
             id: i.to_string(),
-            content: format!("Test message {}", i),
+            payload: format!("Test message {}", i).into_bytes(),
+            ..Default::default()
         };
         rapidmq.publish("test_queue", message);
     }
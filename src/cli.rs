@@ -27,6 +27,40 @@ pub enum Commands {
         /// The name of the queue
         queue_name: String,
     },
+    /// Declare an exchange (direct, fanout, topic or headers)
+    DeclareExchange {
+        /// The name of the exchange
+        name: String,
+        /// The exchange kind
+        kind: String,
+    },
+    /// Delete an exchange and its bindings
+    DeleteExchange {
+        /// The name of the exchange
+        name: String,
+    },
+    /// Bind a queue to an exchange
+    BindQueue {
+        /// The name of the exchange
+        exchange: String,
+        /// The name of the queue
+        queue_name: String,
+        /// The binding key; topic exchanges accept `*` and `#` wildcards
+        #[clap(default_value = "")]
+        routing_key: String,
+    },
+    /// Remove a queue binding from an exchange
+    UnbindQueue {
+        /// The name of the exchange
+        exchange: String,
+        /// The name of the queue
+        queue_name: String,
+        /// The binding key
+        #[clap(default_value = "")]
+        routing_key: String,
+    },
+    /// List exchanges and their bindings
+    ListExchanges,
     /// Add a new node to the cluster
     AddNode {
         /// The ID of the node
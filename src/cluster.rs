@@ -1,14 +1,26 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
-use raft::{Config, Node, NodeId, RaftState};
+use std::time::{Duration, Instant};
+use raft::{Config, NodeId, RawNode, StateRole, Storage, INVALID_ID};
+use rocksdb::DB;
 use serde::{Serialize, Deserialize};
 use tokio::sync::mpsc;
-use tonic::{transport::{Server, Channel}, Request, Response, Status};
+use tonic::{transport::{Server, Channel}, Request, Response, Status, Streaming};
+use tonic::codegen::tokio_stream::wrappers::ReceiverStream;
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
 use raft::prelude::*;
 use tonic::transport::ClientTlsConfig;
 use crate::ai_module::AIModule;
+use crate::consumer_group::{GroupState, DEFAULT_SESSION_TIMEOUT};
+use crate::exchange::{Binding, Exchange, ExchangeKind, ExchangeRegistry};
 use crate::quantum_module::QuantumModule;
+use crate::proto::RapidMQMessage;
+use crate::raft_storage::RaftStorage;
+use crate::raft_transport::{RaftReceiver, RaftTransport};
+use crate::replication::{self, ReplicaOp, ReplicaSet};
+use crate::scheduler::now_millis;
+use crate::transaction::TxOp;
+use prost::Message as ProstMessage;
 
 pub mod rapidmq {
     tonic::include_proto!("rapidmq");
@@ -16,94 +28,511 @@ pub mod rapidmq {
 
 use rapidmq::{
     rapid_mq_server::{RapidMq, RapidMqServer},
-    PublishRequest, PublishResponse, ConsumeRequest, ConsumeResponse, StateUpdateRequest, StateUpdateResponse,
+    PublishRequest, PublishResponse, ConsumeRequest, ConsumeResponse, RaftMessageRequest, RaftMessageResponse,
+    AckRequest, AckResponse, RejectRequest,
+    JoinGroupRequest, GroupMemberRequest, GroupAssignment, GroupConsumeResponse,
+    ReadStreamRequest, ReadStreamResponse, StreamRecord, SeekStreamRequest, SeekStreamResponse,
+    CommitOffsetRequest, FetchOffsetRequest, OffsetResponse,
+    TxOperation, PrepareTransactionRequest, PrepareTransactionResponse, TransactionRequest,
+    ReplicaOperation, ReplicateRequest, replica_operation::Kind,
+    PublishBatchRequest, PublishBatchResponse, ConsumeBatchRequest, ConsumeBatchResponse,
+    SubscribeRequest, ConsumeStart, ConsumeCommand, consume_command::Command,
 };
 
-#[derive(Clone, Serialize, Deserialize)]
+// Longest a streaming consumer waits on an empty queue before checking
+// whether its client is still there
+const STREAM_CONSUME_WAIT: Duration = Duration::from_secs(30);
+
+// Responses buffered per streaming consumer
+const STREAM_BUFFER: usize = 16;
+
+// Records returned by ReadStream when the request sets no limit
+const DEFAULT_READ_RECORDS: usize = 100;
+
+// Raft timing is counted in ticks: followers start an election after
+// ELECTION_TICK ticks without hearing from a leader
+const RAFT_TICK_INTERVAL: Duration = Duration::from_millis(100);
+const ELECTION_TICK: usize = 10;
+const HEARTBEAT_TICK: usize = 3;
+
+// Applied entries kept in the raft log before they are folded into a snapshot
+const RAFT_LOG_COMPACT_THRESHOLD: u64 = 1000;
+
+// A peer the raft leader has not heard from for this long is considered down,
+// and the replicated queues it leads move to another replica
+const NODE_FAILURE_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Address a node is assumed to listen on until it is added with another one
+fn default_address(node_id: NodeId) -> String {
+    format!("127.0.0.1:{}", 50000 + node_id)
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct ClusterState {
     pub nodes: HashMap<NodeId, String>,
     pub queue_assignments: HashMap<String, NodeId>,
     pub node_loads: HashMap<NodeId, usize>,
+    // Source queue -> queues that receive a copy of every message published to it
+    #[serde(default)]
+    pub subscriptions: HashMap<String, Vec<String>>,
+    // Partitioned queue -> number of partitions
+    #[serde(default)]
+    pub partitions: HashMap<String, u32>,
+    // Stream -> consumer name -> next offset the consumer will read
+    #[serde(default)]
+    pub committed_offsets: HashMap<String, HashMap<String, u64>>,
+    // Replicated queue -> the nodes holding a copy, leader first
+    #[serde(default)]
+    pub replica_sets: HashMap<String, ReplicaSet>,
+    // Consumer group -> its members and their partitions
+    #[serde(default)]
+    pub consumer_groups: HashMap<String, GroupState>,
+    #[serde(default)]
+    pub exchanges: ExchangeRegistry,
+}
+
+impl ClusterState {
+    // State of a new cluster, before any command has been applied
+    fn bootstrap(voters: &[NodeId]) -> Self {
+        ClusterState {
+            nodes: voters.iter().map(|&id| (id, default_address(id))).collect(),
+            node_loads: voters.iter().map(|&id| (id, 0)).collect(),
+            ..Default::default()
+        }
+    }
+
+    // Every node applies the same commands in the same order, so this must only
+    // depend on the current state and the command
+    pub fn apply(&mut self, command: &ClusterCommand) {
+        match command {
+            ClusterCommand::AddNode { node_id, address } => {
+                self.nodes.insert(*node_id, address.clone());
+                self.node_loads.entry(*node_id).or_insert(0);
+            }
+            ClusterCommand::RemoveNode { node_id } => {
+                self.nodes.remove(node_id);
+                self.node_loads.remove(node_id);
+            }
+            ClusterCommand::CreateQueue { queue_name, node_id }
+            | ClusterCommand::CreateStream { stream_name: queue_name, node_id, .. }
+            | ClusterCommand::AssignQueue { queue_name, node_id } => {
+                if let Some(previous) = self.queue_assignments.insert(queue_name.clone(), *node_id) {
+                    if let Some(load) = self.node_loads.get_mut(&previous) {
+                        *load = load.saturating_sub(1);
+                    }
+                }
+                *self.node_loads.entry(*node_id).or_default() += 1;
+            }
+            ClusterCommand::Subscribe { queue_name, subscriber_queue } => {
+                let subscribers = self.subscriptions.entry(queue_name.clone()).or_default();
+                if !subscribers.contains(subscriber_queue) {
+                    subscribers.push(subscriber_queue.clone());
+                }
+            }
+            ClusterCommand::Unsubscribe { queue_name, subscriber_queue } => {
+                if let Some(subscribers) = self.subscriptions.get_mut(queue_name) {
+                    subscribers.retain(|s| s != subscriber_queue);
+                    if subscribers.is_empty() {
+                        self.subscriptions.remove(queue_name);
+                    }
+                }
+            }
+            ClusterCommand::SetPartitions { queue_name, partitions } => {
+                self.partitions.insert(queue_name.clone(), *partitions);
+            }
+            ClusterCommand::CommitOffset { stream_name, consumer, offset } => {
+                self.committed_offsets.entry(stream_name.clone()).or_default().insert(consumer.clone(), *offset);
+            }
+            ClusterCommand::CreateReplicatedQueue { queue_name, replica_set } => {
+                if self.queue_assignments.contains_key(queue_name) {
+                    return;
+                }
+                self.queue_assignments.insert(queue_name.clone(), replica_set.leader());
+                for replica in &replica_set.replicas {
+                    *self.node_loads.entry(*replica).or_default() += 1;
+                }
+                self.replica_sets.insert(queue_name.clone(), replica_set.clone());
+            }
+            ClusterCommand::SetInSync { queue_name, node_id, in_sync } => {
+                if let Some(replica_set) = self.replica_sets.get_mut(queue_name) {
+                    replica_set.set_in_sync(*node_id, *in_sync);
+                }
+            }
+            ClusterCommand::PromoteReplica { queue_name, node_id } => {
+                if let Some(replica_set) = self.replica_sets.get_mut(queue_name) {
+                    // Repeated proposals for a failover that already happened are no-ops
+                    if replica_set.leader() != *node_id && replica_set.is_in_sync(*node_id) {
+                        replica_set.promote(*node_id);
+                        self.queue_assignments.insert(queue_name.clone(), *node_id);
+                    }
+                }
+            }
+            ClusterCommand::JoinGroup { group, queue_name, partitions, member_id, at } => {
+                let state = self.consumer_groups.entry(group.clone())
+                    .or_insert_with(|| GroupState::new(queue_name, *partitions));
+                // A group is tied to the queue it was first joined with
+                if state.queue_name == *queue_name {
+                    state.join(member_id, *at);
+                }
+            }
+            ClusterCommand::GroupHeartbeat { group, member_id, at } => {
+                if let Some(state) = self.consumer_groups.get_mut(group) {
+                    state.heartbeat(member_id, *at);
+                }
+            }
+            ClusterCommand::LeaveGroup { group, member_ids } => {
+                if let Some(state) = self.consumer_groups.get_mut(group) {
+                    state.leave(member_ids);
+                    if state.members.is_empty() {
+                        self.consumer_groups.remove(group);
+                    }
+                }
+            }
+            // The proposer checks these against its own copy; one that went stale
+            // before it applied, like a bind to a deleted exchange, is dropped
+            ClusterCommand::DeclareExchange { name, kind } => {
+                let _ = self.exchanges.declare(name, *kind);
+            }
+            ClusterCommand::DeleteExchange { name } => {
+                self.exchanges.delete(name);
+            }
+            ClusterCommand::BindQueue { exchange, binding } => {
+                let _ = self.exchanges.bind(exchange, binding.clone());
+            }
+            ClusterCommand::UnbindQueue { exchange, binding } => {
+                let _ = self.exchanges.unbind(exchange, binding);
+            }
+        }
+    }
+}
+
+// A change to ClusterState, proposed to the raft group as JSON. Decisions that
+// are not deterministic, like where the AI places a queue, are made by the
+// proposer and recorded in the command.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClusterCommand {
+    AddNode { node_id: NodeId, address: String },
+    RemoveNode { node_id: NodeId },
+    // Places a new queue; the owning node opens it when the command applies
+    CreateQueue { queue_name: String, node_id: NodeId },
+    // Places a new stream; the owning node opens it with `retention`
+    CreateStream { stream_name: String, node_id: NodeId, retention: crate::RetentionPolicy },
+    AssignQueue { queue_name: String, node_id: NodeId },
+    Subscribe { queue_name: String, subscriber_queue: String },
+    Unsubscribe { queue_name: String, subscriber_queue: String },
+    SetPartitions { queue_name: String, partitions: u32 },
+    CommitOffset { stream_name: String, consumer: String, offset: u64 },
+    // Places a new queue on several nodes; each of them opens its copy when the
+    // command applies. Ignored if the queue already exists.
+    CreateReplicatedQueue { queue_name: String, replica_set: ReplicaSet },
+    // Reported by a queue leader when a follower misses or catches up on changes
+    SetInSync { queue_name: String, node_id: NodeId, in_sync: bool },
+    // Hands a replicated queue to an in-sync follower after its leader failed
+    PromoteReplica { queue_name: String, node_id: NodeId },
+    // `at` is the proposer's Unix time in milliseconds, taken as the member's
+    // latest heartbeat
+    JoinGroup { group: String, queue_name: String, partitions: u32, member_id: String, at: u64 },
+    GroupHeartbeat { group: String, member_id: String, at: u64 },
+    // Drops members that left or stopped heartbeating; an empty group is removed
+    LeaveGroup { group: String, member_ids: Vec<String> },
+    DeclareExchange { name: String, kind: ExchangeKind },
+    DeleteExchange { name: String },
+    BindQueue { exchange: String, binding: Binding },
+    UnbindQueue { exchange: String, binding: Binding },
+}
+
+// Called on every node after a command is applied to its ClusterState
+pub type ApplyHook = Box<dyn Fn(&ClusterCommand) + Send + Sync>;
+
+// When this node last heard from each peer. Only meaningful on the raft leader,
+// which hears from every peer; the entries start over with each term.
+#[derive(Default)]
+struct Liveness {
+    term: u64,
+    last_heard: HashMap<NodeId, Instant>,
+}
+
+// What is stored next to the raft log after each round of applied entries
+#[derive(Serialize, Deserialize)]
+struct AppliedState {
+    index: u64,
+    state: ClusterState,
 }
 
 pub struct ClusterManager {
-    node: Arc<Mutex<Node<ClusterState>>>,
+    node_id: NodeId,
+    raw_node: Mutex<RawNode<RaftStorage>>,
     state: Arc<Mutex<ClusterState>>,
+    on_apply: ApplyHook,
+    // Raft messages waiting to be sent to peers; the receiver is taken by the driver
+    outbox: mpsc::UnboundedSender<raft::prelude::Message>,
+    outgoing: Mutex<Option<mpsc::UnboundedReceiver<raft::prelude::Message>>>,
+    // Where this node reaches its peers. Kept outside the replicated state, since
+    // peers have to talk before the cluster can agree on anything.
+    peer_addresses: Mutex<HashMap<NodeId, String>>,
+    liveness: Mutex<Liveness>,
     rpc_clients: Arc<Mutex<HashMap<NodeId, rapidmq::rapid_mq_client::RapidMqClient<tonic::transport::Channel>>>>,
     ai_module: AIModule,
     quantum_module: QuantumModule,
 }
 
 impl ClusterManager {
-    pub fn new(node_id: NodeId, peers: Vec<NodeId>) -> Self {
-        let config = Config::new(node_id);
-        let state = ClusterState {
-            nodes: peers.into_iter().map(|id| (id, format!("127.0.0.1:{}", 50000 + id.0))).collect(),
-            queue_assignments: HashMap::new(),
-            node_loads: HashMap::new(),
+    // Opens the raft log in `db`. A node with an empty log starts a new cluster
+    // whose voters are itself and `peers`; a node that is its only voter elects
+    // itself right away, so it can commit without a running driver.
+    pub fn new(node_id: NodeId, peers: Vec<NodeId>, db: Arc<DB>, on_apply: ApplyHook) -> Self {
+        let mut voters: Vec<NodeId> = peers;
+        voters.push(node_id);
+        voters.sort();
+        voters.dedup();
+
+        let mut storage = RaftStorage::open(db);
+        let saved = storage.applied_state().and_then(|data| serde_json::from_slice::<AppliedState>(&data).ok());
+        let (applied, state) = match saved {
+            Some(saved) => (saved.index, saved.state),
+            None => {
+                if storage.is_empty() {
+                    storage.bootstrap(voters.clone());
+                }
+                (0, ClusterState::bootstrap(&voters))
+            }
         };
-        let node = Node::new(config, state.clone());
+        let sole_voter = storage.conf_state().voters == vec![node_id];
+
+        let config = Config {
+            id: node_id,
+            election_tick: ELECTION_TICK,
+            heartbeat_tick: HEARTBEAT_TICK,
+            applied,
+            pre_vote: true,
+            ..Default::default()
+        };
+        let raw_node = RawNode::new(&config, storage, &raft::default_logger()).expect("Failed to start raft node");
+        let (outbox, outgoing) = mpsc::unbounded_channel();
 
         let ai_module = AIModule::new().expect("Failed to initialize AI module");
         let quantum_module = QuantumModule::new();
 
-        ClusterManager {
-            node: Arc::new(Mutex::new(node)),
+        let manager = ClusterManager {
+            node_id,
+            raw_node: Mutex::new(raw_node),
             state: Arc::new(Mutex::new(state)),
+            on_apply,
+            outbox,
+            outgoing: Mutex::new(Some(outgoing)),
+            peer_addresses: Mutex::new(HashMap::new()),
+            liveness: Mutex::new(Liveness::default()),
             rpc_clients: Arc::new(Mutex::new(HashMap::new())),
             ai_module,
             quantum_module,
+        };
+        {
+            let mut raw_node = manager.raw_node.lock().unwrap();
+            if sole_voter {
+                raw_node.campaign().expect("Failed to start election");
+            }
+            // Applies entries committed before a restart
+            manager.handle_ready(&mut raw_node);
         }
+        manager
     }
 
-    pub fn add_node(&self, node_id: NodeId, address: String) {
-        let mut state = self.state.lock().unwrap();
-        state.nodes.insert(node_id, address);
-        state.node_loads.insert(node_id, 0);
-        self.rebalance_queues();
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
     }
 
-    pub fn remove_node(&self, node_id: NodeId) {
-        let mut state = self.state.lock().unwrap();
-        state.nodes.remove(&node_id);
-        state.node_loads.remove(&node_id);
-        self.rebalance_queues();
+    // The current raft leader, if this node knows one
+    pub fn leader(&self) -> Option<NodeId> {
+        let leader = self.raw_node.lock().unwrap().raft.leader_id;
+        (leader != INVALID_ID).then_some(leader)
     }
 
-    pub async fn sync_state(&self) {
-        let state = self.state.lock().unwrap().clone();
-        for (node_id, _) in state.nodes.iter() {
-            if *node_id != self.node.lock().unwrap().id() {
-                if let Err(e) = self.send_state_update(*node_id, state.clone()).await {
-                    eprintln!("Failed to sync state with node {}: {}", node_id, e);
+    // Proposes a change to the cluster state. Followers forward it to the leader.
+    // It is applied once a quorum has stored it, which on a single-node cluster
+    // is before this returns.
+    pub fn propose(&self, command: ClusterCommand) -> Result<(), String> {
+        let data = serde_json::to_vec(&command).unwrap();
+        let mut raw_node = self.raw_node.lock().unwrap();
+        raw_node.propose(Vec::new(), data).map_err(|e| e.to_string())?;
+        self.handle_ready(&mut raw_node);
+        Ok(())
+    }
+
+    // Proposes a membership change. `command` rides in the entry's context and
+    // is applied together with the change.
+    fn propose_conf_change(&self, change_type: ConfChangeType, node_id: NodeId, command: &ClusterCommand) -> Result<(), String> {
+        let mut change = ConfChange::default();
+        change.set_change_type(change_type);
+        change.node_id = node_id;
+        let context = serde_json::to_vec(command).unwrap();
+        let mut raw_node = self.raw_node.lock().unwrap();
+        raw_node.propose_conf_change(context, change).map_err(|e| e.to_string())?;
+        self.handle_ready(&mut raw_node);
+        Ok(())
+    }
+
+    // Proposes `command`, logging a rejected proposal, e.g. while no leader is known
+    fn submit(&self, command: ClusterCommand) -> bool {
+        match self.propose(command.clone()) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Failed to propose {:?}: {}", command, e);
+                false
+            }
+        }
+    }
+
+    // Feeds a raft message from a peer into this node
+    pub fn step(&self, message: raft::prelude::Message) -> Result<(), String> {
+        self.liveness.lock().unwrap().last_heard.insert(message.from, Instant::now());
+        let mut raw_node = self.raw_node.lock().unwrap();
+        raw_node.step(message).map_err(|e| e.to_string())?;
+        self.handle_ready(&mut raw_node);
+        Ok(())
+    }
+
+    fn tick(&self) {
+        let mut raw_node = self.raw_node.lock().unwrap();
+        raw_node.tick();
+        if raw_node.raft.state == StateRole::Leader {
+            self.perform_leader_duties(&mut raw_node);
+        }
+        self.handle_ready(&mut raw_node);
+    }
+
+    // Persists, sends and applies whatever the raft node has ready. Called with
+    // the node locked after anything that may have changed it.
+    fn handle_ready(&self, raw_node: &mut RawNode<RaftStorage>) {
+        if !raw_node.has_ready() {
+            return;
+        }
+        let mut ready = raw_node.ready();
+        self.send(ready.take_messages());
+
+        let mut applied = false;
+        if *ready.snapshot() != Snapshot::default() {
+            self.restore_snapshot(raw_node, ready.snapshot().clone());
+            applied = true;
+        }
+        applied |= self.apply_entries(raw_node, ready.take_committed_entries());
+        raw_node.mut_store().append(ready.entries());
+        if let Some(hard_state) = ready.hs() {
+            raw_node.mut_store().set_hard_state(hard_state.clone());
+        }
+        self.send(ready.take_persisted_messages());
+
+        let mut light_ready = raw_node.advance(ready);
+        if let Some(commit) = light_ready.commit_index() {
+            raw_node.mut_store().set_commit(commit);
+        }
+        self.send(light_ready.take_messages());
+        applied |= self.apply_entries(raw_node, light_ready.take_committed_entries());
+        raw_node.advance_apply();
+
+        if applied {
+            self.save_applied(raw_node);
+        }
+    }
+
+    fn send(&self, messages: Vec<raft::prelude::Message>) {
+        for message in messages {
+            // Only fails once the manager is gone
+            let _ = self.outbox.send(message);
+        }
+    }
+
+    // Applies committed entries to the cluster state. Returns whether there were any.
+    fn apply_entries(&self, raw_node: &mut RawNode<RaftStorage>, entries: Vec<Entry>) -> bool {
+        let applied = !entries.is_empty();
+        for entry in entries {
+            let command = match entry.get_entry_type() {
+                EntryType::EntryNormal => &entry.data[..],
+                EntryType::EntryConfChange => {
+                    let change = ConfChange::decode(&entry.data[..]).unwrap();
+                    match raw_node.apply_conf_change(&change) {
+                        Ok(conf_state) => raw_node.mut_store().set_conf_state(conf_state),
+                        Err(e) => eprintln!("Failed to apply membership change at index {}: {}", entry.index, e),
+                    }
+                    &entry.context[..]
+                }
+                EntryType::EntryConfChangeV2 => {
+                    let change = ConfChangeV2::decode(&entry.data[..]).unwrap();
+                    match raw_node.apply_conf_change(&change) {
+                        Ok(conf_state) => raw_node.mut_store().set_conf_state(conf_state),
+                        Err(e) => eprintln!("Failed to apply membership change at index {}: {}", entry.index, e),
+                    }
+                    &entry.context[..]
                 }
+            };
+            // Empty entries are appended by new leaders and learner promotions
+            if command.is_empty() {
+                continue;
+            }
+            match serde_json::from_slice::<ClusterCommand>(command) {
+                Ok(command) => {
+                    self.state.lock().unwrap().apply(&command);
+                    (self.on_apply)(&command);
+                }
+                Err(e) => eprintln!("Skipping unreadable cluster command at index {}: {}", entry.index, e),
             }
         }
+        applied
     }
 
-    async fn send_state_update(&self, node_id: NodeId, state: ClusterState) -> Result<(), Box<dyn std::error::Error>> {
-        let mut clients = self.rpc_clients.lock().unwrap();
-        let client = clients.entry(node_id).or_insert_with(|| {
-            let addr = self.state.lock().unwrap().nodes.get(&node_id).unwrap().clone();
-            let channel = Channel::from_shared(addr)
-                .unwrap()
-                .tls_config(tonic::transport::ClientTlsConfig::new())
-                .unwrap()
-                .connect_lazy();
-            rapidmq::rapid_mq_client::RapidMqClient::new(channel)
-        });
+    fn restore_snapshot(&self, raw_node: &mut RawNode<RaftStorage>, snapshot: Snapshot) {
+        match serde_json::from_slice::<ClusterState>(&snapshot.data) {
+            Ok(state) => *self.state.lock().unwrap() = state,
+            Err(e) => eprintln!("Ignoring unreadable cluster state snapshot: {}", e),
+        }
+        raw_node.mut_store().apply_snapshot(snapshot);
+    }
 
-        let request = tonic::Request::new(StateUpdateRequest {
-            state: serde_json::to_string(&state).unwrap(),
-        });
+    // Stores the applied state and compacts the log once it has grown long enough
+    fn save_applied(&self, raw_node: &mut RawNode<RaftStorage>) {
+        let index = raw_node.raft.raft_log.applied;
+        let state = self.state.lock().unwrap().clone();
+        let store = raw_node.mut_store();
+        let first_index = store.first_index().unwrap();
+        if index >= first_index + RAFT_LOG_COMPACT_THRESHOLD {
+            if let Err(e) = store.compact(index, serde_json::to_vec(&state).unwrap()) {
+                eprintln!("Failed to compact raft log at index {}: {}", index, e);
+            }
+        }
+        store.set_applied_state(serde_json::to_vec(&AppliedState { index, state }).unwrap());
+    }
 
-        client.update_state(request).await?;
-        Ok(())
+    // Adds a node as a learner; the leader promotes it to voter once it has
+    // caught up. Adding a known member only updates its address.
+    pub fn add_node(&self, node_id: NodeId, address: String) {
+        self.peer_addresses.lock().unwrap().insert(node_id, address.clone());
+        self.rpc_clients.lock().unwrap().remove(&node_id);
+        let command = ClusterCommand::AddNode { node_id, address };
+        if self.is_member(node_id) {
+            self.submit(command);
+        } else if let Err(e) = self.propose_conf_change(ConfChangeType::AddLearnerNode, node_id, &command) {
+            eprintln!("Failed to add node {}: {}", node_id, e);
+        }
+    }
+
+    pub fn remove_node(&self, node_id: NodeId) {
+        let command = ClusterCommand::RemoveNode { node_id };
+        if !self.is_member(node_id) {
+            self.submit(command);
+        } else if let Err(e) = self.propose_conf_change(ConfChangeType::RemoveNode, node_id, &command) {
+            eprintln!("Failed to remove node {}: {}", node_id, e);
+        }
+    }
+
+    fn is_member(&self, node_id: NodeId) -> bool {
+        let raw_node = self.raw_node.lock().unwrap();
+        let conf_state = raw_node.store().conf_state();
+        conf_state.voters.contains(&node_id) || conf_state.learners.contains(&node_id)
     }
 
     pub async fn assign_queue(&self, queue_name: &str) -> NodeId {
-        let mut state = self.state.lock().await;
         let priority = self.ai_module.predict_message_priority(queue_name).await.unwrap_or(0.5);
+        let state = self.get_state();
         let nodes: Vec<NodeId> = state.nodes.keys().cloned().collect();
         let node_loads: Vec<f32> = nodes.iter().map(|&id| *state.node_loads.get(&id).unwrap_or(&0) as f32).collect();
         
@@ -119,13 +548,12 @@ impl ClusterManager {
         let index = (priority * (combined_order.len() as f32)) as usize;
         let node_id = combined_order[index.min(combined_order.len() - 1)];
         
-        state.queue_assignments.insert(queue_name.to_string(), node_id);
-        *state.node_loads.entry(node_id).or_default() += 1;
+        self.submit(ClusterCommand::AssignQueue { queue_name: queue_name.to_string(), node_id });
         node_id
     }
 
     pub async fn rebalance_queues(&self) {
-        let mut state = self.state.lock().await;
+        let state = self.get_state();
         let node_loads: Vec<f32> = state.node_loads.values().map(|&load| load as f32).collect();
         
         if let Ok(optimized_order) = self.ai_module.optimize_cluster_load(&node_loads).await {
@@ -137,17 +565,260 @@ impl ClusterManager {
                 .collect();
             
             // Implement queue reassignment based on the combined optimization
-            for (queue, current_node) in state.queue_assignments.iter_mut() {
+            for (queue, current_node) in state.queue_assignments.iter() {
                 let new_node = combined_order[0];
                 if *current_node != new_node {
-                    *current_node = new_node;
-                    *state.node_loads.get_mut(&new_node).unwrap() += 1;
-                    *state.node_loads.get_mut(current_node).unwrap() -= 1;
+                    self.submit(ClusterCommand::AssignQueue { queue_name: queue.clone(), node_id: new_node });
                 }
             }
         }
     }
 
+    // Places a new queue on the least loaded node. The chosen node opens it
+    // when it applies the placement. A queue that already exists keeps its node.
+    pub fn place_queue(&self, queue_name: &str) -> Result<NodeId, String> {
+        let state = self.get_state();
+        if let Some(&node_id) = state.queue_assignments.get(queue_name) {
+            return Ok(node_id);
+        }
+        let mut nodes: Vec<NodeId> = state.nodes.keys().cloned().collect();
+        nodes.sort();
+        let node_id = replication::place_replicas(&state.node_loads, &nodes, 1)?[0];
+        self.propose(ClusterCommand::CreateQueue { queue_name: queue_name.to_string(), node_id })?;
+        Ok(node_id)
+    }
+
+    // Same as place_queue, for a stream that is opened with `retention`
+    pub fn place_stream(&self, stream_name: &str, retention: crate::RetentionPolicy) -> Result<NodeId, String> {
+        let state = self.get_state();
+        if let Some(&node_id) = state.queue_assignments.get(stream_name) {
+            return Ok(node_id);
+        }
+        let mut nodes: Vec<NodeId> = state.nodes.keys().cloned().collect();
+        nodes.sort();
+        let node_id = replication::place_replicas(&state.node_loads, &nodes, 1)?[0];
+        self.propose(ClusterCommand::CreateStream { stream_name: stream_name.to_string(), node_id, retention })?;
+        Ok(node_id)
+    }
+
+    // Places a new queue on a node, bypassing the placement heuristics
+    pub fn create_queue(&self, queue_name: &str, node_id: NodeId) {
+        self.submit(ClusterCommand::CreateQueue { queue_name: queue_name.to_string(), node_id });
+    }
+
+    // Pins `queue_name` to a node, bypassing the placement heuristics
+    pub fn assign_queue_to(&self, queue_name: &str, node_id: NodeId) {
+        self.submit(ClusterCommand::AssignQueue { queue_name: queue_name.to_string(), node_id });
+    }
+
+    // Assigns `queue_name` to whichever node holds `with_queue`
+    pub fn colocate_queue(&self, queue_name: &str, with_queue: &str) -> Option<NodeId> {
+        let node_id = self.get_queue_node(with_queue)?;
+        if self.get_queue_node(queue_name) != Some(node_id) {
+            self.assign_queue_to(queue_name, node_id);
+        }
+        Some(node_id)
+    }
+
+    // Subscriptions are part of the replicated ClusterState, so every node fans
+    // out the same way
+    pub fn subscribe(&self, queue_name: &str, subscriber_queue: &str) -> Result<bool, String> {
+        if self.subscribers_of(queue_name).iter().any(|s| s == subscriber_queue) {
+            return Ok(false);
+        }
+        self.propose(ClusterCommand::Subscribe {
+            queue_name: queue_name.to_string(),
+            subscriber_queue: subscriber_queue.to_string(),
+        })?;
+        Ok(true)
+    }
+
+    pub fn unsubscribe(&self, queue_name: &str, subscriber_queue: &str) -> Result<bool, String> {
+        if !self.subscribers_of(queue_name).iter().any(|s| s == subscriber_queue) {
+            return Ok(false);
+        }
+        self.propose(ClusterCommand::Unsubscribe {
+            queue_name: queue_name.to_string(),
+            subscriber_queue: subscriber_queue.to_string(),
+        })?;
+        Ok(true)
+    }
+
+    pub fn subscribers_of(&self, queue_name: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.subscriptions.get(queue_name).cloned().unwrap_or_default()
+    }
+
+    // Proposes subscriptions persisted by this node that the cluster does not know
+    pub fn restore_subscriptions(&self, subscriptions: HashMap<String, Vec<String>>) -> Result<(), String> {
+        for (queue_name, subscribers) in subscriptions {
+            for subscriber in subscribers {
+                self.subscribe(&queue_name, &subscriber)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn commit_offset(&self, stream_name: &str, consumer: &str, offset: u64) -> Result<(), String> {
+        self.propose(ClusterCommand::CommitOffset {
+            stream_name: stream_name.to_string(),
+            consumer: consumer.to_string(),
+            offset,
+        })
+    }
+
+    pub fn committed_offset(&self, stream_name: &str, consumer: &str) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        state.committed_offsets.get(stream_name).and_then(|consumers| consumers.get(consumer)).cloned()
+    }
+
+    pub fn committed_offsets_of(&self, stream_name: &str) -> HashMap<String, u64> {
+        let state = self.state.lock().unwrap();
+        state.committed_offsets.get(stream_name).cloned().unwrap_or_default()
+    }
+
+    // Proposes offsets persisted by this node that the cluster does not know;
+    // offsets already known to the cluster win
+    pub fn restore_offsets(&self, offsets: HashMap<String, HashMap<String, u64>>) -> Result<(), String> {
+        for (stream_name, consumers) in offsets {
+            let known = self.committed_offsets_of(&stream_name);
+            for (consumer, offset) in consumers {
+                if !known.contains_key(&consumer) {
+                    self.commit_offset(&stream_name, &consumer, offset)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn join_group(&self, group: &str, queue_name: &str, partitions: u32, member_id: &str) -> Result<(), String> {
+        self.propose(ClusterCommand::JoinGroup {
+            group: group.to_string(),
+            queue_name: queue_name.to_string(),
+            partitions,
+            member_id: member_id.to_string(),
+            at: now_millis(),
+        })
+    }
+
+    pub fn group_heartbeat(&self, group: &str, member_id: &str) -> Result<(), String> {
+        self.propose(ClusterCommand::GroupHeartbeat {
+            group: group.to_string(),
+            member_id: member_id.to_string(),
+            at: now_millis(),
+        })
+    }
+
+    pub fn leave_group(&self, group: &str, member_ids: Vec<String>) -> Result<(), String> {
+        self.propose(ClusterCommand::LeaveGroup { group: group.to_string(), member_ids })
+    }
+
+    pub fn consumer_group(&self, group: &str) -> Option<GroupState> {
+        let state = self.state.lock().unwrap();
+        state.consumer_groups.get(group).cloned()
+    }
+
+    // Exchanges are part of the replicated ClusterState, so an exchange declared
+    // through any node routes on every node
+    pub fn declare_exchange(&self, name: &str, kind: ExchangeKind) -> Result<(), String> {
+        match self.exchange(name) {
+            Some(existing) if existing.kind != kind => {
+                Err(format!("Exchange '{}' already exists as {:?}", name, existing.kind))
+            }
+            Some(_) => Ok(()),
+            None => self.propose(ClusterCommand::DeclareExchange { name: name.to_string(), kind }),
+        }
+    }
+
+    pub fn delete_exchange(&self, name: &str) -> Result<bool, String> {
+        if self.exchange(name).is_none() {
+            return Ok(false);
+        }
+        self.propose(ClusterCommand::DeleteExchange { name: name.to_string() })?;
+        Ok(true)
+    }
+
+    // Returns whether the binding is new
+    pub fn bind_queue(&self, exchange: &str, binding: Binding) -> Result<bool, String> {
+        let existing = self.exchange(exchange)
+            .ok_or_else(|| format!("Exchange '{}' not found", exchange))?;
+        if existing.bindings.contains(&binding) {
+            return Ok(false);
+        }
+        self.propose(ClusterCommand::BindQueue { exchange: exchange.to_string(), binding })?;
+        Ok(true)
+    }
+
+    pub fn unbind_queue(&self, exchange: &str, binding: &Binding) -> Result<bool, String> {
+        let existing = self.exchange(exchange)
+            .ok_or_else(|| format!("Exchange '{}' not found", exchange))?;
+        if !existing.bindings.contains(binding) {
+            return Ok(false);
+        }
+        self.propose(ClusterCommand::UnbindQueue { exchange: exchange.to_string(), binding: binding.clone() })?;
+        Ok(true)
+    }
+
+    // Proposes exchanges persisted by this node that the cluster does not know
+    pub fn restore_exchanges(&self, exchanges: Vec<Exchange>) -> Result<(), String> {
+        for exchange in exchanges {
+            self.declare_exchange(&exchange.name, exchange.kind)?;
+            for binding in exchange.bindings {
+                self.bind_queue(&exchange.name, binding)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn exchange(&self, name: &str) -> Option<Exchange> {
+        let state = self.state.lock().unwrap();
+        state.exchanges.get(name).cloned()
+    }
+
+    pub fn list_exchanges(&self) -> Vec<Exchange> {
+        let state = self.state.lock().unwrap();
+        state.exchanges.list()
+    }
+
+    // Queues a message published to `exchange` goes to; None if it does not exist
+    pub fn route(&self, exchange: &str, routing_key: &str, headers: &HashMap<String, String>) -> Option<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        state.exchanges.route(exchange, routing_key, headers)
+    }
+
+    pub fn set_partitions(&self, queue_name: &str, partitions: u32) {
+        self.submit(ClusterCommand::SetPartitions { queue_name: queue_name.to_string(), partitions });
+    }
+
+    pub fn get_partitions(&self, queue_name: &str) -> Option<u32> {
+        let state = self.state.lock().unwrap();
+        state.partitions.get(queue_name).cloned()
+    }
+
+    // Places a replicated queue on the `replication_factor` least loaded nodes,
+    // the first of which leads it. Returns the chosen nodes, leader first.
+    pub fn create_replicated_queue(&self, queue_name: &str, replication_factor: usize, write_quorum: usize) -> Result<Vec<NodeId>, String> {
+        let state = self.get_state();
+        if state.queue_assignments.contains_key(queue_name) {
+            return Err(format!("queue '{}' already exists", queue_name));
+        }
+        let mut nodes: Vec<NodeId> = state.nodes.keys().cloned().collect();
+        nodes.sort();
+        let replicas = replication::place_replicas(&state.node_loads, &nodes, replication_factor)?;
+        let replica_set = ReplicaSet::new(replicas.clone(), write_quorum)?;
+        self.propose(ClusterCommand::CreateReplicatedQueue { queue_name: queue_name.to_string(), replica_set })?;
+        Ok(replicas)
+    }
+
+    pub fn replica_set(&self, queue_name: &str) -> Option<ReplicaSet> {
+        let state = self.state.lock().unwrap();
+        state.replica_sets.get(queue_name).cloned()
+    }
+
+    pub fn set_in_sync(&self, queue_name: &str, node_id: NodeId, in_sync: bool) {
+        self.submit(ClusterCommand::SetInSync { queue_name: queue_name.to_string(), node_id, in_sync });
+    }
+
     pub fn get_queue_node(&self, queue_name: &str) -> Option<NodeId> {
         let state = self.state.lock().unwrap();
         state.queue_assignments.get(queue_name).cloned()
@@ -157,10 +828,20 @@ impl ClusterManager {
         self.state.lock().unwrap().clone()
     }
 
-    pub async fn run(&self) {
-        let node = self.node.clone();
-        let state = self.state.clone();
-        
+    // Serves the gRPC API for `broker` on `addr` without TLS and without the
+    // background tasks of `run`, apart from the raft driver
+    pub async fn serve(&self, broker: crate::RapidMQ, addr: std::net::SocketAddr) -> Result<(), tonic::transport::Error> {
+        let manager = broker.cluster_manager.clone();
+        ClusterManager::drive(manager.clone(), Arc::new(GrpcTransport { manager }));
+        let rapid_mq = RapidMqService { broker };
+        Server::builder()
+            .add_service(RapidMqServer::new(rapid_mq))
+            .serve(addr)
+            .await
+    }
+
+    // Serves gRPC for `broker`, the RapidMQ instance this manager belongs to
+    pub async fn run(&self, broker: crate::RapidMQ) {
         // Set up TLS
         let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
         builder.set_private_key_file("key.pem", SslFiletype::PEM).unwrap();
@@ -168,8 +849,10 @@ impl ClusterManager {
         let acceptor = builder.build();
         
         // Start the RPC server
-        let addr = format!("127.0.0.1:{}", 50000 + self.node.lock().unwrap().id().0).parse().unwrap();
-        let rapid_mq = RapidMqService { state: state.clone() };
+        let addr = default_address(self.node_id).parse().unwrap();
+        let manager = broker.cluster_manager.clone();
+        ClusterManager::drive(manager.clone(), Arc::new(GrpcTransport { manager }));
+        let rapid_mq = RapidMqService { broker };
         
         tokio::spawn(async move {
             Server::builder()
@@ -180,21 +863,6 @@ impl ClusterManager {
                 .unwrap();
         });
 
-        // Run the Raft node
-        tokio::spawn(async move {
-            loop {
-                let mut node = node.lock().unwrap();
-                node.tick();
-                if let Some(leader) = node.leader() {
-                    if leader == node.id() {
-                        // This node is the leader, perform leader duties
-                        self.perform_leader_duties().await;
-                    }
-                }
-                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-            }
-        });
-
         // Periodically update AI model
         tokio::spawn(async move {
             loop {
@@ -211,24 +879,130 @@ impl ClusterManager {
         });
     }
 
-    async fn perform_leader_duties(&self) {
-        // Synchronize state across all nodes
-        self.sync_state().await;
-        // Rebalance queues if necessary
-        self.rebalance_queues().await;
+    // Ticks the raft node and sends its messages to peers over `transport`. Only
+    // the first call starts anything, so `run` and `serve` can both call it.
+    pub fn drive(manager: Arc<ClusterManager>, transport: Arc<dyn RaftTransport>) {
+        let Some(mut outgoing) = manager.outgoing.lock().unwrap().take() else {
+            return;
+        };
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RAFT_TICK_INTERVAL);
+            loop {
+                interval.tick().await;
+                manager.tick();
+            }
+        });
+        tokio::spawn(async move {
+            while let Some(message) = outgoing.recv().await {
+                transport.send(message);
+            }
+        });
     }
 
-    pub async fn publish_remote(&self, node_id: NodeId, queue_name: &str, message: crate::Message) -> Result<(), Box<dyn std::error::Error>> {
+    // Fails over queues led by nodes that went quiet, and promotes a learner
+    // that has caught up with the log to voter. Only one membership change can
+    // be in flight at a time.
+    fn perform_leader_duties(&self, raw_node: &mut RawNode<RaftStorage>) {
+        self.fail_over_queues(raw_node);
+        self.expire_group_members(raw_node);
+        if raw_node.raft.has_pending_conf() {
+            return;
+        }
+        let committed = raw_node.raft.raft_log.committed;
+        let caught_up = raw_node.store().conf_state().learners.iter()
+            .find(|&&id| raw_node.raft.prs().get(id).map_or(false, |progress| progress.matched >= committed))
+            .cloned();
+        if let Some(learner) = caught_up {
+            let mut change = ConfChange::default();
+            change.set_change_type(ConfChangeType::AddNode);
+            change.node_id = learner;
+            if let Err(e) = raw_node.propose_conf_change(Vec::new(), change) {
+                eprintln!("Failed to promote node {}: {}", learner, e);
+            }
+        }
+    }
+
+    // Proposes a new leader for every replicated queue whose leader this node has
+    // not heard from in NODE_FAILURE_TIMEOUT: its first in-sync follower that
+    // is still up. Queues without such a follower stay where they are.
+    fn fail_over_queues(&self, raw_node: &mut RawNode<RaftStorage>) {
+        let now = Instant::now();
+        let down: HashSet<NodeId> = {
+            let mut liveness = self.liveness.lock().unwrap();
+            if liveness.term != raw_node.raft.term {
+                // Followers only hear from the leader, so what this node heard
+                // before it was elected says nothing about the other peers
+                liveness.term = raw_node.raft.term;
+                liveness.last_heard.clear();
+            }
+            raw_node.raft.prs().iter()
+                .map(|(&id, _)| id)
+                .filter(|&id| id != self.node_id)
+                .filter(|&id| now.duration_since(*liveness.last_heard.entry(id).or_insert(now)) >= NODE_FAILURE_TIMEOUT)
+                .collect()
+        };
+        if down.is_empty() {
+            return;
+        }
+
+        let promotions: Vec<ClusterCommand> = {
+            let state = self.state.lock().unwrap();
+            state.replica_sets.iter()
+                .filter(|(_, replica_set)| down.contains(&replica_set.leader()))
+                .filter_map(|(queue_name, replica_set)| {
+                    let successor = replica_set.followers().iter()
+                        .find(|&&id| replica_set.is_in_sync(id) && !down.contains(&id))?;
+                    Some(ClusterCommand::PromoteReplica { queue_name: queue_name.clone(), node_id: *successor })
+                })
+                .collect()
+        };
+        // Proposed again on every tick until applied; applying it twice is harmless
+        for command in promotions {
+            if let Err(e) = raw_node.propose(Vec::new(), serde_json::to_vec(&command).unwrap()) {
+                eprintln!("Failed to propose {:?}: {}", command, e);
+            }
+        }
+    }
+
+    // Proposes dropping consumer group members that have not sent a heartbeat
+    // through any node in DEFAULT_SESSION_TIMEOUT
+    fn expire_group_members(&self, raw_node: &mut RawNode<RaftStorage>) {
+        let now = now_millis();
+        let leaves: Vec<ClusterCommand> = {
+            let state = self.state.lock().unwrap();
+            state.consumer_groups.iter()
+                .map(|(group, group_state)| (group, group_state.expired(now, DEFAULT_SESSION_TIMEOUT)))
+                .filter(|(_, member_ids)| !member_ids.is_empty())
+                .map(|(group, member_ids)| ClusterCommand::LeaveGroup { group: group.clone(), member_ids })
+                .collect()
+        };
+        // Proposed again on every tick until applied; applying it twice is harmless
+        for command in leaves {
+            if let Err(e) = raw_node.propose(Vec::new(), serde_json::to_vec(&command).unwrap()) {
+                eprintln!("Failed to propose {:?}: {}", command, e);
+            }
+        }
+    }
+
+    // Nodes registered with an `https://` address are reached over TLS, others
+    // (e.g. loopback nodes in tests) over plain HTTP/2
+    fn client_for(&self, node_id: NodeId) -> rapidmq::rapid_mq_client::RapidMqClient<Channel> {
         let mut clients = self.rpc_clients.lock().unwrap();
-        let client = clients.entry(node_id).or_insert_with(|| {
-            let addr = self.state.lock().unwrap().nodes.get(&node_id).unwrap().clone();
-            let channel = Channel::from_shared(addr)
-                .unwrap()
-                .tls_config(tonic::transport::ClientTlsConfig::new())
-                .unwrap()
-                .connect_lazy();
+        clients.entry(node_id).or_insert_with(|| {
+            let addr = self.peer_addresses.lock().unwrap().get(&node_id).cloned()
+                .or_else(|| self.state.lock().unwrap().nodes.get(&node_id).cloned())
+                .unwrap_or_else(|| default_address(node_id));
+            let mut endpoint = Channel::from_shared(addr).unwrap();
+            if endpoint.uri().scheme_str() == Some("https") {
+                endpoint = endpoint.tls_config(tonic::transport::ClientTlsConfig::new()).unwrap();
+            }
+            let channel = endpoint.connect_lazy();
             rapidmq::rapid_mq_client::RapidMqClient::new(channel)
-        });
+        }).clone()
+    }
+
+    pub async fn publish_remote(&self, node_id: NodeId, queue_name: &str, message: crate::Message) -> Result<crate::PublishOutcome, Box<dyn std::error::Error>> {
+        let mut client = self.client_for(node_id);
 
         let proto_message: RapidMQMessage = message.into();
         let encoded = proto_message.encode_to_vec();
@@ -236,34 +1010,212 @@ impl ClusterManager {
         let request = tonic::Request::new(PublishRequest {
             queue_name: queue_name.to_string(),
             message: encoded,
+            ..Default::default()
         });
 
-        client.publish_message(request).await?;
-        Ok(())
+        let response = client.publish_message(request).await?.into_inner();
+        if response.duplicate {
+            Ok(crate::PublishOutcome::Duplicate)
+        } else if !response.success {
+            Ok(crate::PublishOutcome::Unreplicated)
+        } else {
+            Ok(crate::PublishOutcome::Published)
+        }
     }
 
-    pub async fn consume_remote(&self, node_id: NodeId, queue_name: &str) -> Result<Option<crate::Message>, Box<dyn std::error::Error>> {
-        let mut clients = self.rpc_clients.lock().unwrap();
-        let client = clients.entry(node_id).or_insert_with(|| {
-            let addr = self.state.lock().unwrap().nodes.get(&node_id).unwrap().clone();
-            rapidmq::rapid_mq_client::RapidMqClient::connect(addr)
-        });
+    pub async fn consume_remote(&self, node_id: NodeId, queue_name: &str, wait: std::time::Duration) -> Result<Option<crate::Delivery>, Box<dyn std::error::Error>> {
+        let mut client = self.client_for(node_id);
 
         let request = tonic::Request::new(ConsumeRequest {
             queue_name: queue_name.to_string(),
+            wait_ms: wait.as_millis() as u64,
         });
 
         let response = client.consume_message(request).await?;
-        let message = response.into_inner();
+        ClusterManager::delivery_from(response.into_inner())
+    }
+
+    pub async fn publish_batch_remote(&self, node_id: NodeId, queue_name: &str, messages: Vec<crate::Message>) -> Result<Vec<crate::PublishOutcome>, Box<dyn std::error::Error>> {
+        let mut client = self.client_for(node_id);
+
+        let request = tonic::Request::new(PublishBatchRequest {
+            queue_name: queue_name.to_string(),
+            messages: messages.into_iter().map(|m| RapidMQMessage::from(m).encode_to_vec()).collect(),
+        });
+
+        let response = client.publish_batch(request).await?.into_inner();
+        // Nodes without replication leave `unreplicated` empty
+        let unreplicated = response.unreplicated.into_iter().chain(std::iter::repeat(false));
+        Ok(response.duplicate.into_iter().zip(unreplicated)
+            .map(|(duplicate, unreplicated)| match (duplicate, unreplicated) {
+                (true, _) => crate::PublishOutcome::Duplicate,
+                (false, true) => crate::PublishOutcome::Unreplicated,
+                (false, false) => crate::PublishOutcome::Published,
+            })
+            .collect())
+    }
+
+    pub async fn consume_batch_remote(&self, node_id: NodeId, queue_name: &str, max: usize, wait: std::time::Duration) -> Result<Vec<crate::Delivery>, Box<dyn std::error::Error>> {
+        let mut client = self.client_for(node_id);
+
+        let request = tonic::Request::new(ConsumeBatchRequest {
+            queue_name: queue_name.to_string(),
+            max_messages: max as u32,
+            wait_ms: wait.as_millis() as u64,
+        });
+
+        let response = client.consume_batch(request).await?;
+        let mut deliveries = Vec::new();
+        for item in response.into_inner().deliveries {
+            if let Some(delivery) = ClusterManager::delivery_from(item)? {
+                deliveries.push(delivery);
+            }
+        }
+        Ok(deliveries)
+    }
+
+    // Opens a Subscribe stream on the node that owns the queue
+    pub async fn subscribe_remote(&self, node_id: NodeId, queue_name: &str, auto_ack: bool) -> Result<Streaming<ConsumeResponse>, Box<dyn std::error::Error>> {
+        let mut client = self.client_for(node_id);
+
+        let request = tonic::Request::new(SubscribeRequest {
+            queue_name: queue_name.to_string(),
+            auto_ack,
+        });
+
+        Ok(client.subscribe(request).await?.into_inner())
+    }
+
+    // Opens a Consume stream on the node that owns the queue. `commands` must
+    // start with a `start` command; credit, acks and nacks follow on it.
+    pub async fn consume_stream_remote(&self, node_id: NodeId, commands: mpsc::Receiver<ConsumeCommand>) -> Result<Streaming<ConsumeResponse>, Box<dyn std::error::Error>> {
+        let mut client = self.client_for(node_id);
+        Ok(client.consume(ReceiverStream::new(commands)).await?.into_inner())
+    }
+
+    fn delivery_from(response: ConsumeResponse) -> Result<Option<crate::Delivery>, Box<dyn std::error::Error>> {
+        if response.message.is_empty() {
+            return Ok(None);
+        }
+        let proto_message = RapidMQMessage::decode(&response.message[..])?;
+        Ok(Some(crate::Delivery {
+            tag: response.delivery_tag,
+            message: proto_message.into(),
+            delivery_count: response.delivery_count,
+        }))
+    }
+
+    pub async fn ack_remote(&self, node_id: NodeId, queue_name: &str, delivery_tag: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut client = self.client_for(node_id);
+
+        let request = tonic::Request::new(AckRequest {
+            queue_name: queue_name.to_string(),
+            delivery_tag: delivery_tag.to_string(),
+        });
+
+        let response = client.ack_message(request).await?;
+        Ok(response.into_inner().success)
+    }
+
+    // Ships changes of a replicated queue to one of its followers. With `reset`
+    // the follower replaces its copy with `ops` instead of applying them on top.
+    // Returns whether the follower applied them.
+    pub async fn replicate_remote(&self, node_id: NodeId, queue_name: &str, ops: Vec<ReplicaOp>, reset: bool) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut client = self.client_for(node_id);
+
+        let operations = ops.into_iter().map(|op| match op {
+            ReplicaOp::Ready { seq, encoded } => ReplicaOperation { kind: Kind::Ready as i32, seq, message: encoded, ..Default::default() },
+            ReplicaOp::Scheduled { due, seq, encoded } => ReplicaOperation { kind: Kind::Scheduled as i32, seq, due, message: encoded, ..Default::default() },
+            ReplicaOp::Promote { due, id, seq } => ReplicaOperation { kind: Kind::Promote as i32, seq, due, timer_id: id, ..Default::default() },
+            ReplicaOp::Remove { seq } => ReplicaOperation { kind: Kind::Remove as i32, seq, ..Default::default() },
+        }).collect();
+        let request = tonic::Request::new(ReplicateRequest {
+            queue_name: queue_name.to_string(),
+            operations,
+            reset,
+            leader: self.node_id,
+        });
+
+        let response = client.replicate(request).await?;
+        Ok(response.into_inner().success)
+    }
+
+    // First phase of a cross-node transaction; a no vote comes back as an error
+    pub async fn prepare_remote(&self, node_id: NodeId, transaction_id: &str, ops: Vec<TxOp>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut client = self.client_for(node_id);
 
-        if message.message.is_empty() {
-            Ok(None)
+        let operations = ops.into_iter().map(|op| match op {
+            TxOp::Publish { queue_name, message } => TxOperation {
+                queue_name,
+                message: RapidMQMessage::from(message).encode_to_vec(),
+                delivery_tag: String::new(),
+            },
+            TxOp::Ack { queue_name, delivery_tag } => TxOperation {
+                queue_name,
+                message: Vec::new(),
+                delivery_tag,
+            },
+        }).collect();
+        let request = tonic::Request::new(PrepareTransactionRequest {
+            transaction_id: transaction_id.to_string(),
+            operations,
+        });
+
+        let response = client.prepare_transaction(request).await?.into_inner();
+        if response.prepared {
+            Ok(())
         } else {
-            let proto_message = RapidMQMessage::decode(&message.message[..])?;
-            Ok(Some(proto_message.into()))
+            Err(response.reason.into())
         }
     }
 
+    pub async fn commit_prepared_remote(&self, node_id: NodeId, transaction_id: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut client = self.client_for(node_id);
+
+        let request = tonic::Request::new(TransactionRequest {
+            transaction_id: transaction_id.to_string(),
+        });
+
+        let response = client.commit_transaction(request).await?;
+        Ok(response.into_inner().success)
+    }
+
+    pub async fn abort_prepared_remote(&self, node_id: NodeId, transaction_id: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut client = self.client_for(node_id);
+
+        let request = tonic::Request::new(TransactionRequest {
+            transaction_id: transaction_id.to_string(),
+        });
+
+        let response = client.abort_transaction(request).await?;
+        Ok(response.into_inner().success)
+    }
+
+    pub async fn nack_remote(&self, node_id: NodeId, queue_name: &str, delivery_tag: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut client = self.client_for(node_id);
+
+        let request = tonic::Request::new(AckRequest {
+            queue_name: queue_name.to_string(),
+            delivery_tag: delivery_tag.to_string(),
+        });
+
+        let response = client.nack_message(request).await?;
+        Ok(response.into_inner().success)
+    }
+
+    pub async fn reject_remote(&self, node_id: NodeId, queue_name: &str, delivery_tag: &str, reason: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut client = self.client_for(node_id);
+
+        let request = tonic::Request::new(RejectRequest {
+            queue_name: queue_name.to_string(),
+            delivery_tag: delivery_tag.to_string(),
+            reason: reason.to_string(),
+        });
+
+        let response = client.reject_message(request).await?;
+        Ok(response.into_inner().success)
+    }
+
     fn collect_performance_data(&self) -> Vec<f32> {
         // Implement logic to collect relevant performance metrics
         // This is a placeholder implementation
@@ -310,8 +1262,104 @@ impl ClusterManager {
     }
 }
 
+impl RaftReceiver for ClusterManager {
+    fn receive(&self, message: raft::prelude::Message) {
+        if let Err(e) = self.step(message) {
+            eprintln!("Dropping raft message: {}", e);
+        }
+    }
+}
+
+// Sends raft messages to peers with the RaftMessage RPC
+pub struct GrpcTransport {
+    manager: Arc<ClusterManager>,
+}
+
+impl RaftTransport for GrpcTransport {
+    fn send(&self, message: raft::prelude::Message) {
+        let mut client = self.manager.client_for(message.to);
+        tokio::spawn(async move {
+            let request = Request::new(RaftMessageRequest { message: message.encode_to_vec() });
+            // Unreachable peers are expected while nodes come and go
+            let _ = client.raft_message(request).await;
+        });
+    }
+}
+
 pub struct RapidMqService {
-    state: Arc<Mutex<ClusterState>>,
+    broker: crate::RapidMQ,
+}
+
+impl RapidMqService {
+    fn response_for(delivery: crate::Delivery) -> ConsumeResponse {
+        ConsumeResponse {
+            message_id: delivery.message.id.clone(),
+            content: String::new(),
+            message: RapidMQMessage::from(delivery.message).encode_to_vec(),
+            delivery_tag: delivery.tag,
+            delivery_count: delivery.delivery_count,
+        }
+    }
+
+    fn decode(encoded: &[u8]) -> Result<crate::Message, Status> {
+        RapidMQMessage::decode(encoded)
+            .map(crate::Message::from)
+            .map_err(|e| Status::invalid_argument(format!("undecodable message: {}", e)))
+    }
+
+    fn assignment_response(assignment: crate::consumer_group::Assignment) -> GroupAssignment {
+        GroupAssignment {
+            member_id: assignment.member_id,
+            generation: assignment.generation,
+            partitions: assignment.partitions,
+        }
+    }
+
+    // An empty `from` reads from the start, as documented in the proto
+    fn start_offset(from: &str) -> Result<crate::StartOffset, Status> {
+        if from.is_empty() {
+            return Ok(crate::StartOffset::Earliest);
+        }
+        from.parse().map_err(Status::invalid_argument)
+    }
+
+    fn offset_response(&self, stream_name: &str, consumer: &str) -> Result<Response<OffsetResponse>, Status> {
+        let offset = self.broker.consumer_offset(stream_name, consumer)
+            .ok_or_else(|| Status::not_found(format!("stream '{}' is not served by this node", stream_name)))?;
+        Ok(Response::new(OffsetResponse {
+            committed: offset.offset.is_some(),
+            offset: offset.offset.unwrap_or(0),
+            next_offset: offset.next_offset,
+            lag: offset.lag,
+        }))
+    }
+
+    // The node serving `queue_name` when it is not this one
+    fn remote_owner(&self, queue_name: &str) -> Result<Option<NodeId>, Status> {
+        let cluster_manager = &self.broker.cluster_manager;
+        match cluster_manager.get_queue_node(queue_name) {
+            Some(node_id) if node_id == cluster_manager.node_id() => Ok(None),
+            Some(node_id) => Ok(Some(node_id)),
+            None => Err(Status::not_found(format!("queue '{}' does not exist", queue_name))),
+        }
+    }
+
+    // Copies responses from another node's stream to our client
+    fn relay(mut upstream: Streaming<ConsumeResponse>, tx: mpsc::Sender<Result<ConsumeResponse, Status>>) {
+        tokio::spawn(async move {
+            loop {
+                let item = match upstream.message().await {
+                    Ok(Some(response)) => Ok(response),
+                    Ok(None) => return,
+                    Err(status) => Err(status),
+                };
+                let failed = item.is_err();
+                if tx.send(item).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+    }
 }
 
 #[tonic::async_trait]
@@ -321,9 +1369,25 @@ impl RapidMq for RapidMqService {
         request: Request<PublishRequest>,
     ) -> Result<Response<PublishResponse>, Status> {
         let req = request.into_inner();
-        // Implement the logic to publish the message locally
-        // This will depend on how you've implemented your local queue management
-        Ok(Response::new(PublishResponse { success: true }))
+        self.remote_owner(&req.queue_name)?;
+        let message = if req.message.is_empty() {
+            // Senders predating the envelope only fill in the id and text content
+            crate::Message {
+                id: req.message_id,
+                payload: req.content.into_bytes(),
+                ..Default::default()
+            }
+        } else {
+            RapidMqService::decode(&req.message)?
+        };
+        let outcome = self.broker.deliver(&req.queue_name, message).await;
+        if outcome == crate::PublishOutcome::Unavailable {
+            return Err(Status::unavailable(format!("Queue '{}' is unavailable", req.queue_name)));
+        }
+        Ok(Response::new(PublishResponse {
+            success: outcome != crate::PublishOutcome::Unreplicated,
+            duplicate: outcome == crate::PublishOutcome::Duplicate,
+        }))
     }
 
     async fn consume_message(
@@ -331,21 +1395,360 @@ impl RapidMq for RapidMqService {
         request: Request<ConsumeRequest>,
     ) -> Result<Response<ConsumeResponse>, Status> {
         let req = request.into_inner();
-        // Implement the logic to consume a message locally
-        // This will depend on how you've implemented your local queue management
-        Ok(Response::new(ConsumeResponse {
-            message_id: "".to_string(),
-            content: "".to_string(),
+        self.remote_owner(&req.queue_name)?;
+        let delivery = self.broker.consume_wait(&req.queue_name, Duration::from_millis(req.wait_ms)).await;
+        Ok(Response::new(delivery.map_or_else(ConsumeResponse::default, RapidMqService::response_for)))
+    }
+
+    async fn ack_message(
+        &self,
+        request: Request<AckRequest>,
+    ) -> Result<Response<AckResponse>, Status> {
+        let req = request.into_inner();
+        self.remote_owner(&req.queue_name)?;
+        let success = self.broker.ack(&req.queue_name, &req.delivery_tag).await;
+        Ok(Response::new(AckResponse { success }))
+    }
+
+    async fn nack_message(
+        &self,
+        request: Request<AckRequest>,
+    ) -> Result<Response<AckResponse>, Status> {
+        let req = request.into_inner();
+        self.remote_owner(&req.queue_name)?;
+        let success = self.broker.nack(&req.queue_name, &req.delivery_tag).await;
+        Ok(Response::new(AckResponse { success }))
+    }
+
+    async fn reject_message(
+        &self,
+        request: Request<RejectRequest>,
+    ) -> Result<Response<AckResponse>, Status> {
+        let req = request.into_inner();
+        self.remote_owner(&req.queue_name)?;
+        let success = self.broker.reject(&req.queue_name, &req.delivery_tag, &req.reason).await;
+        Ok(Response::new(AckResponse { success }))
+    }
+
+    async fn join_group(
+        &self,
+        request: Request<JoinGroupRequest>,
+    ) -> Result<Response<GroupAssignment>, Status> {
+        let req = request.into_inner();
+        let member_id = Some(req.member_id).filter(|id| !id.is_empty());
+        self.broker.join_group(&req.group, &req.queue_name, member_id).await
+            .map(|assignment| Response::new(RapidMqService::assignment_response(assignment)))
+            .map_err(Status::failed_precondition)
+    }
+
+    async fn group_heartbeat(
+        &self,
+        request: Request<GroupMemberRequest>,
+    ) -> Result<Response<GroupAssignment>, Status> {
+        let req = request.into_inner();
+        self.broker.heartbeat(&req.group, &req.member_id)
+            .map(|assignment| Response::new(RapidMqService::assignment_response(assignment)))
+            .map_err(Status::failed_precondition)
+    }
+
+    async fn leave_group(
+        &self,
+        request: Request<GroupMemberRequest>,
+    ) -> Result<Response<AckResponse>, Status> {
+        let req = request.into_inner();
+        let success = self.broker.leave_group(&req.group, &req.member_id)
+            .map_err(Status::unavailable)?;
+        Ok(Response::new(AckResponse { success }))
+    }
+
+    async fn consume_group(
+        &self,
+        request: Request<GroupMemberRequest>,
+    ) -> Result<Response<GroupConsumeResponse>, Status> {
+        let req = request.into_inner();
+        let delivery = self.broker.consume_group(&req.group, &req.member_id).await
+            .map_err(Status::failed_precondition)?;
+        Ok(Response::new(match delivery {
+            Some(group_delivery) => GroupConsumeResponse {
+                partition: group_delivery.partition,
+                queue_name: group_delivery.queue_name,
+                message: RapidMQMessage::from(group_delivery.delivery.message).encode_to_vec(),
+                delivery_tag: group_delivery.delivery.tag,
+            },
+            None => GroupConsumeResponse::default(),
+        }))
+    }
+
+    async fn read_stream(
+        &self,
+        request: Request<ReadStreamRequest>,
+    ) -> Result<Response<ReadStreamResponse>, Status> {
+        let req = request.into_inner();
+        let start = RapidMqService::start_offset(&req.from)?;
+        let max = if req.max_records == 0 { DEFAULT_READ_RECORDS } else { req.max_records as usize };
+        let records = self.broker.read_stream(&req.stream_name, start, max)
+            .ok_or_else(|| Status::not_found(format!("stream '{}' is not served by this node", req.stream_name)))?;
+        Ok(Response::new(ReadStreamResponse {
+            records: records.into_iter()
+                .map(|record| StreamRecord {
+                    offset: record.offset,
+                    message: RapidMQMessage::from(record.message).encode_to_vec(),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn seek_stream(
+        &self,
+        request: Request<SeekStreamRequest>,
+    ) -> Result<Response<SeekStreamResponse>, Status> {
+        let req = request.into_inner();
+        let start = RapidMqService::start_offset(&req.from)?;
+        let (offset, first_offset, next_offset) = self.broker.seek_stream(&req.stream_name, start)
+            .ok_or_else(|| Status::not_found(format!("stream '{}' is not served by this node", req.stream_name)))?;
+        Ok(Response::new(SeekStreamResponse { offset, first_offset, next_offset }))
+    }
+
+    async fn commit_offset(
+        &self,
+        request: Request<CommitOffsetRequest>,
+    ) -> Result<Response<OffsetResponse>, Status> {
+        let req = request.into_inner();
+        self.broker.commit_offset(&req.stream_name, &req.consumer, req.offset)
+            .map_err(Status::failed_precondition)?;
+        self.offset_response(&req.stream_name, &req.consumer)
+    }
+
+    async fn fetch_offset(
+        &self,
+        request: Request<FetchOffsetRequest>,
+    ) -> Result<Response<OffsetResponse>, Status> {
+        let req = request.into_inner();
+        self.offset_response(&req.stream_name, &req.consumer)
+    }
+
+    async fn prepare_transaction(
+        &self,
+        request: Request<PrepareTransactionRequest>,
+    ) -> Result<Response<PrepareTransactionResponse>, Status> {
+        let req = request.into_inner();
+        let mut ops = Vec::with_capacity(req.operations.len());
+        for operation in req.operations {
+            ops.push(if operation.message.is_empty() {
+                TxOp::Ack { queue_name: operation.queue_name, delivery_tag: operation.delivery_tag }
+            } else {
+                TxOp::Publish { message: RapidMqService::decode(&operation.message)?, queue_name: operation.queue_name }
+            });
+        }
+        Ok(Response::new(match self.broker.prepare_transaction(&req.transaction_id, ops) {
+            Ok(()) => PrepareTransactionResponse { prepared: true, reason: String::new() },
+            Err(reason) => PrepareTransactionResponse { prepared: false, reason },
         }))
     }
 
-    async fn update_state(
+    async fn commit_transaction(
+        &self,
+        request: Request<TransactionRequest>,
+    ) -> Result<Response<AckResponse>, Status> {
+        let req = request.into_inner();
+        let success = self.broker.commit_prepared(&req.transaction_id).await.map_err(Status::unavailable)?;
+        Ok(Response::new(AckResponse { success }))
+    }
+
+    async fn abort_transaction(
+        &self,
+        request: Request<TransactionRequest>,
+    ) -> Result<Response<AckResponse>, Status> {
+        let req = request.into_inner();
+        let success = self.broker.abort_prepared(&req.transaction_id);
+        Ok(Response::new(AckResponse { success }))
+    }
+
+    async fn publish_batch(
+        &self,
+        request: Request<PublishBatchRequest>,
+    ) -> Result<Response<PublishBatchResponse>, Status> {
+        let req = request.into_inner();
+        self.remote_owner(&req.queue_name)?;
+        let messages = req.messages.iter()
+            .map(|encoded| RapidMqService::decode(encoded))
+            .collect::<Result<Vec<_>, Status>>()?;
+        let outcomes = self.broker.deliver_batch(&req.queue_name, messages).await;
+        if outcomes.contains(&crate::PublishOutcome::Unavailable) {
+            return Err(Status::unavailable(format!("Queue '{}' is unavailable", req.queue_name)));
+        }
+        Ok(Response::new(PublishBatchResponse {
+            duplicate: outcomes.iter().map(|&outcome| outcome == crate::PublishOutcome::Duplicate).collect(),
+            unreplicated: outcomes.iter().map(|&outcome| outcome == crate::PublishOutcome::Unreplicated).collect(),
+        }))
+    }
+
+    async fn consume_batch(
+        &self,
+        request: Request<ConsumeBatchRequest>,
+    ) -> Result<Response<ConsumeBatchResponse>, Status> {
+        let req = request.into_inner();
+        self.remote_owner(&req.queue_name)?;
+        let max = req.max_messages.max(1) as usize;
+        let deliveries = self.broker.consume_batch(&req.queue_name, max, Duration::from_millis(req.wait_ms)).await;
+        Ok(Response::new(ConsumeBatchResponse {
+            deliveries: deliveries.into_iter().map(RapidMqService::response_for).collect(),
+        }))
+    }
+
+    type SubscribeStream = ReceiverStream<Result<ConsumeResponse, Status>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let req = request.into_inner();
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        if let Some(node_id) = self.remote_owner(&req.queue_name)? {
+            // Forward the subscription to the node that owns the queue
+            let upstream = self.broker.cluster_manager.subscribe_remote(node_id, &req.queue_name, req.auto_ack).await
+                .map_err(|e| Status::unavailable(e.to_string()))?;
+            RapidMqService::relay(upstream, tx);
+            return Ok(Response::new(ReceiverStream::new(rx)));
+        }
+
+        let broker = self.broker.clone();
+        tokio::spawn(async move {
+            loop {
+                let started = Instant::now();
+                let delivery = tokio::select! {
+                    _ = tx.closed() => return,
+                    delivery = broker.consume_wait(&req.queue_name, STREAM_CONSUME_WAIT) => delivery,
+                };
+                let Some(delivery) = delivery else {
+                    // Giving up before the wait was over means the queue is gone
+                    // or its node cannot be reached; end the stream rather than spin
+                    if started.elapsed() < STREAM_CONSUME_WAIT {
+                        let status = Status::unavailable(format!("queue '{}' is unavailable", req.queue_name));
+                        let _ = tx.send(Err(status)).await;
+                        return;
+                    }
+                    continue;
+                };
+                let tag = delivery.tag.clone();
+                if tx.send(Ok(RapidMqService::response_for(delivery))).await.is_err() {
+                    broker.nack(&req.queue_name, &tag).await;
+                    return;
+                }
+                if req.auto_ack {
+                    broker.ack(&req.queue_name, &tag).await;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type ConsumeStream = ReceiverStream<Result<ConsumeResponse, Status>>;
+
+    async fn consume(
+        &self,
+        request: Request<Streaming<ConsumeCommand>>,
+    ) -> Result<Response<Self::ConsumeStream>, Status> {
+        let mut commands = request.into_inner();
+        let Some(Command::Start(ConsumeStart { queue_name, credit })) = commands.message().await?.and_then(|c| c.command) else {
+            return Err(Status::invalid_argument("the first command must be start"));
+        };
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        if let Some(node_id) = self.remote_owner(&queue_name)? {
+            // Forward the stream to the node that owns the queue, passing our
+            // client's commands along
+            let (command_tx, command_rx) = mpsc::channel(STREAM_BUFFER);
+            let start = ConsumeCommand { command: Some(Command::Start(ConsumeStart { queue_name, credit })) };
+            command_tx.send(start).await.map_err(|_| Status::internal("command stream closed"))?;
+            let upstream = self.broker.cluster_manager.consume_stream_remote(node_id, command_rx).await
+                .map_err(|e| Status::unavailable(e.to_string()))?;
+            tokio::spawn(async move {
+                while let Ok(Some(command)) = commands.message().await {
+                    if command_tx.send(command).await.is_err() {
+                        return;
+                    }
+                }
+            });
+            RapidMqService::relay(upstream, tx);
+            return Ok(Response::new(ReceiverStream::new(rx)));
+        }
+
+        let broker = self.broker.clone();
+        tokio::spawn(async move {
+            let mut credit = credit;
+            // Pushed but not yet acked or nacked; handed back when the stream ends
+            let mut unacked = HashSet::new();
+            loop {
+                let started = Instant::now();
+                tokio::select! {
+                    command = commands.message() => match command {
+                        Ok(Some(ConsumeCommand { command: Some(command) })) => match command {
+                            Command::Credit(more) => credit = credit.saturating_add(more),
+                            Command::Ack(tag) => {
+                                unacked.remove(&tag);
+                                broker.ack(&queue_name, &tag).await;
+                            }
+                            Command::Nack(tag) => {
+                                unacked.remove(&tag);
+                                broker.nack(&queue_name, &tag).await;
+                            }
+                            Command::Start(_) => {
+                                let _ = tx.send(Err(Status::invalid_argument("stream already started"))).await;
+                                break;
+                            }
+                        },
+                        Ok(Some(_)) => {}
+                        Ok(None) | Err(_) => break,
+                    },
+                    delivery = broker.consume_wait(&queue_name, STREAM_CONSUME_WAIT), if credit > 0 => match delivery {
+                        Some(delivery) => {
+                            credit -= 1;
+                            unacked.insert(delivery.tag.clone());
+                            if tx.send(Ok(RapidMqService::response_for(delivery))).await.is_err() {
+                                break;
+                            }
+                        }
+                        // Same as subscribe: an early None means the queue is gone or
+                        // its node cannot be reached
+                        None if started.elapsed() < STREAM_CONSUME_WAIT => {
+                            let status = Status::unavailable(format!("queue '{}' is unavailable", queue_name));
+                            let _ = tx.send(Err(status)).await;
+                            break;
+                        }
+                        None => {}
+                    }
+                }
+            }
+            for tag in unacked {
+                broker.nack(&queue_name, &tag).await;
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn raft_message(
+        &self,
+        request: Request<RaftMessageRequest>,
+    ) -> Result<Response<RaftMessageResponse>, Status> {
+        let req = request.into_inner();
+        let message = raft::prelude::Message::decode(&req.message[..])
+            .map_err(|e| Status::invalid_argument(format!("undecodable raft message: {}", e)))?;
+        self.broker.cluster_manager.step(message).map_err(Status::failed_precondition)?;
+        Ok(Response::new(RaftMessageResponse {}))
+    }
+
+    async fn replicate(
         &self,
-        request: Request<StateUpdateRequest>,
-    ) -> Result<Response<StateUpdateResponse>, Status> {
+        request: Request<ReplicateRequest>,
+    ) -> Result<Response<AckResponse>, Status> {
         let req = request.into_inner();
-        let new_state: ClusterState = serde_json::from_str(&req.state).map_err(|e| Status::internal(e.to_string()))?;
-        *self.state.lock().unwrap() = new_state;
-        Ok(Response::new(StateUpdateResponse { success: true }))
+        let ops = req.operations.into_iter().map(|operation| match operation.kind() {
+            Kind::Ready => ReplicaOp::Ready { seq: operation.seq, encoded: operation.message },
+            Kind::Scheduled => ReplicaOp::Scheduled { due: operation.due, seq: operation.seq, encoded: operation.message },
+            Kind::Promote => ReplicaOp::Promote { due: operation.due, id: operation.timer_id, seq: operation.seq },
+            Kind::Remove => ReplicaOp::Remove { seq: operation.seq },
+        }).collect();
+        let success = self.broker.apply_replica_ops(&req.queue_name, req.leader, ops, req.reset);
+        Ok(Response::new(AckResponse { success }))
     }
 }
\ No newline at end of file
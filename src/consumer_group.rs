@@ -0,0 +1,206 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use crate::Delivery;
+
+// Members that miss heartbeats for this long are dropped and their partitions reassigned
+pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Header whose value picks the partition of a message; the message id is used otherwise
+pub const PARTITION_KEY_HEADER: &str = "x-partition-key";
+
+// Partitions are ordinary queues named `<queue>~<index>`
+pub fn partition_queue_name(queue_name: &str, partition: u32) -> String {
+    format!("{}~{}", queue_name, partition)
+}
+
+// Stable FNV-1a hash so a key maps to the same partition on every node and release
+pub fn partition_for(key: &str, partitions: u32) -> u32 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    (hash % partitions.max(1) as u64) as u32
+}
+
+// What a member currently owns; `generation` changes on every rebalance
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Assignment {
+    pub member_id: String,
+    pub generation: u64,
+    pub partitions: Vec<u32>,
+}
+
+// A message consumed on behalf of a group member. Ack it against `queue_name`,
+// the partition queue it came from.
+#[derive(Clone, Debug)]
+pub struct GroupDelivery {
+    pub partition: u32,
+    pub queue_name: String,
+    pub delivery: Delivery,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GroupDescription {
+    pub name: String,
+    pub queue_name: String,
+    pub generation: u64,
+    pub assignments: Vec<Assignment>,
+}
+
+// Members and partition assignment of one group, kept in ClusterState so every
+// node hands out the same assignment
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupState {
+    pub queue_name: String,
+    pub partitions: u32,
+    pub generation: u64,
+    // Member id -> assigned partitions
+    pub members: BTreeMap<String, Vec<u32>>,
+    // Member id -> Unix time in milliseconds of the last heartbeat a node
+    // recorded for it
+    #[serde(default)]
+    pub heartbeats: BTreeMap<String, u64>,
+}
+
+impl GroupState {
+    pub fn new(queue_name: &str, partitions: u32) -> Self {
+        GroupState {
+            queue_name: queue_name.to_string(),
+            partitions,
+            ..Default::default()
+        }
+    }
+
+    // Adds a member and rebalances. Joining again only counts as a heartbeat.
+    pub fn join(&mut self, member_id: &str, at: u64) {
+        self.heartbeats.insert(member_id.to_string(), at);
+        if !self.members.contains_key(member_id) {
+            self.members.insert(member_id.to_string(), Vec::new());
+            self.rebalance();
+        }
+    }
+
+    pub fn heartbeat(&mut self, member_id: &str, at: u64) {
+        if let Some(last) = self.heartbeats.get_mut(member_id) {
+            *last = (*last).max(at);
+        }
+    }
+
+    // Drops members and rebalances if any of them was part of the group
+    pub fn leave(&mut self, member_ids: &[String]) -> bool {
+        let before = self.members.len();
+        self.members.retain(|id, _| !member_ids.contains(id));
+        self.heartbeats.retain(|id, _| !member_ids.contains(id));
+        if self.members.len() == before {
+            return false;
+        }
+        self.rebalance();
+        true
+    }
+
+    // Members whose last heartbeat is older than `session_timeout` at `now`
+    pub fn expired(&self, now: u64, session_timeout: Duration) -> Vec<String> {
+        self.members.keys()
+            .filter(|id| self.heartbeats.get(*id).map_or(true, |&at| now >= at + session_timeout.as_millis() as u64))
+            .cloned()
+            .collect()
+    }
+
+    // Spreads partitions round-robin over members sorted by id, so every
+    // member of a generation computes the same assignment
+    fn rebalance(&mut self) {
+        self.generation += 1;
+        let count = self.members.len().max(1);
+        let partitions = self.partitions;
+        for (index, assigned) in self.members.values_mut().enumerate() {
+            *assigned = (0..partitions).filter(|p| *p as usize % count == index).collect();
+        }
+    }
+
+    pub fn assignment(&self, member_id: &str) -> Option<Assignment> {
+        self.members.get(member_id).map(|partitions| Assignment {
+            member_id: member_id.to_string(),
+            generation: self.generation,
+            partitions: partitions.clone(),
+        })
+    }
+
+    pub fn describe(&self, name: &str) -> GroupDescription {
+        GroupDescription {
+            name: name.to_string(),
+            queue_name: self.queue_name.clone(),
+            generation: self.generation,
+            assignments: self.members.keys().filter_map(|id| self.assignment(id)).collect(),
+        }
+    }
+}
+
+struct Session {
+    // When this node last passed a heartbeat of the member on to the cluster
+    reported: Instant,
+    // Index into the member's partitions of the next partition to consume from
+    cursor: usize,
+}
+
+// Members served by this node. Heartbeats reach the cluster state at most a
+// few times per session timeout, so a busy consumer does not flood the log.
+pub struct ConsumerGroupManager {
+    sessions: HashMap<(String, String), Session>,
+    session_timeout: Duration,
+}
+
+impl ConsumerGroupManager {
+    pub fn new(session_timeout: Duration) -> Self {
+        ConsumerGroupManager {
+            sessions: HashMap::new(),
+            session_timeout,
+        }
+    }
+
+    // Records a heartbeat. Returns whether it is time to pass it on to the cluster.
+    pub fn heartbeat(&mut self, group: &str, member_id: &str) -> bool {
+        let now = Instant::now();
+        let key = (group.to_string(), member_id.to_string());
+        match self.sessions.get_mut(&key) {
+            Some(session) if now.duration_since(session.reported) < self.session_timeout / 3 => false,
+            Some(session) => {
+                session.reported = now;
+                true
+            }
+            None => {
+                self.sessions.insert(key, Session { reported: now, cursor: 0 });
+                true
+            }
+        }
+    }
+
+    pub fn forget(&mut self, group: &str, member_id: &str) {
+        self.sessions.remove(&(group.to_string(), member_id.to_string()));
+    }
+
+    // Partition queues to try, in order, for the member's next consume. The
+    // starting partition rotates so one busy partition cannot hog a member.
+    pub fn next_partitions(&mut self, group: &str, queue_name: &str, assignment: &Assignment) -> Vec<(u32, String)> {
+        let count = assignment.partitions.len();
+        let Some(session) = self.sessions.get_mut(&(group.to_string(), assignment.member_id.clone())) else {
+            return Vec::new();
+        };
+        if count == 0 {
+            return Vec::new();
+        }
+        let start = session.cursor % count;
+        session.cursor = (start + 1) % count;
+        (0..count)
+            .map(|i| assignment.partitions[(start + i) % count])
+            .map(|p| (p, partition_queue_name(queue_name, p)))
+            .collect()
+    }
+}
+
+impl Default for ConsumerGroupManager {
+    fn default() -> Self {
+        Self::new(DEFAULT_SESSION_TIMEOUT)
+    }
+}
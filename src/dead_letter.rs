@@ -0,0 +1,72 @@
+use serde::{Serialize, Deserialize};
+use crate::Message;
+
+// Headers recorded on every dead-lettered message
+pub const ORIGINAL_QUEUE_HEADER: &str = "x-original-queue";
+pub const DELIVERY_COUNT_HEADER: &str = "x-delivery-count";
+pub const DEFAULT_REASON_HEADER: &str = "x-death-reason";
+
+fn default_reason_header() -> String {
+    DEFAULT_REASON_HEADER.to_string()
+}
+
+// Per-queue policy deciding when a message is moved to a dead-letter queue
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeadLetterPolicy {
+    // Deliveries allowed before the message is dead-lettered instead of redelivered
+    pub max_deliveries: u32,
+    pub dead_letter_queue: String,
+    #[serde(default = "default_reason_header")]
+    pub reason_header: String,
+    // Park expired messages here as well instead of discarding them
+    #[serde(default)]
+    pub dead_letter_expired: bool,
+}
+
+impl DeadLetterPolicy {
+    pub fn new(max_deliveries: u32, dead_letter_queue: &str) -> Self {
+        DeadLetterPolicy {
+            max_deliveries,
+            dead_letter_queue: dead_letter_queue.to_string(),
+            reason_header: default_reason_header(),
+            dead_letter_expired: false,
+        }
+    }
+}
+
+// A message leaving its queue for the dead-letter queue.
+// `seq` is its key in the source queue, deleted once the DLQ copy is stored.
+pub struct DeadLetter {
+    pub seq: u64,
+    pub dead_letter_queue: String,
+    pub message: Message,
+}
+
+// Stamps the failure reason and origin onto a message before it is parked
+pub fn mark(mut message: Message, policy: &DeadLetterPolicy, source_queue: &str, reason: &str, delivery_count: u32) -> Message {
+    // A parked message waits for inspection; it must not expire or be delayed again
+    message.expires_at = None;
+    message.deliver_at = None;
+    message.headers.insert(policy.reason_header.clone(), reason.to_string());
+    message.headers.insert(ORIGINAL_QUEUE_HEADER.to_string(), source_queue.to_string());
+    message.headers.insert(DELIVERY_COUNT_HEADER.to_string(), delivery_count.to_string());
+    message
+}
+
+// Builds a placeholder for a record that could not be decoded, keeping its raw bytes as the payload
+pub fn undecodable(seq: u64, encoded: &[u8]) -> Message {
+    Message {
+        id: format!("undecodable-{}", seq),
+        payload: encoded.to_vec(),
+        content_type: Some("application/octet-stream".to_string()),
+        ..Default::default()
+    }
+}
+
+// Strips the dead-letter headers so a re-driven message looks like a fresh publish.
+// Returns the queue the message originally came from.
+pub fn unmark(message: &mut Message, reason_header: &str) -> Option<String> {
+    message.headers.remove(reason_header);
+    message.headers.remove(DELIVERY_COUNT_HEADER);
+    message.headers.remove(ORIGINAL_QUEUE_HEADER)
+}
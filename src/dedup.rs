@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use rocksdb::{DB, WriteBatch};
+use crate::Message;
+
+// Producers that number their messages set both headers; the pair replaces the
+// message id as the deduplication key
+pub const PRODUCER_ID_HEADER: &str = "x-producer-id";
+pub const PRODUCER_SEQ_HEADER: &str = "x-producer-seq";
+
+// How long a published message id is remembered
+pub const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+const DEDUP_PREFIX: &str = "__dedup:";
+
+// Identifies a message within one queue for deduplication. Messages with
+// neither an id nor a producer sequence are never deduplicated.
+pub fn dedup_key(queue_name: &str, message: &Message) -> Option<String> {
+    match (message.headers.get(PRODUCER_ID_HEADER), message.headers.get(PRODUCER_SEQ_HEADER)) {
+        (Some(producer), Some(seq)) => Some(format!("{}/producer/{}/{}", queue_name, producer, seq)),
+        _ if message.id.is_empty() => None,
+        _ => Some(format!("{}/id/{}", queue_name, message.id)),
+    }
+}
+
+// Keys of recently published messages with the Unix time in milliseconds they
+// were first seen, persisted under `__dedup:<key>` so a restart keeps rejecting
+// retries that are still inside the window
+pub struct DedupIndex {
+    seen: HashMap<String, u64>,
+    window: Duration,
+    db: Arc<DB>,
+}
+
+impl DedupIndex {
+    pub fn load(db: Arc<DB>) -> Self {
+        let mut seen = HashMap::new();
+        let iter = db.iterator(rocksdb::IteratorMode::From(DEDUP_PREFIX.as_bytes(), rocksdb::Direction::Forward));
+        for item in iter {
+            let (key, value) = item.unwrap();
+            let Some(dedup_key) = key.strip_prefix(DEDUP_PREFIX.as_bytes()) else {
+                break;
+            };
+            if let Ok(seen_at) = serde_json::from_slice::<u64>(&value) {
+                seen.insert(String::from_utf8_lossy(dedup_key).into_owned(), seen_at);
+            }
+        }
+        DedupIndex {
+            seen,
+            window: DEFAULT_DEDUP_WINDOW,
+            db,
+        }
+    }
+
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    // True if the key was already published inside the window
+    pub fn is_duplicate(&self, key: &str, now: u64) -> bool {
+        self.seen.get(key).map_or(false, |&seen_at| now < seen_at + self.window.as_millis() as u64)
+    }
+
+    pub fn record(&mut self, key: &str, now: u64) {
+        let full_key = format!("{}{}", DEDUP_PREFIX, key);
+        self.db.put(full_key.as_bytes(), serde_json::to_vec(&now).unwrap()).unwrap();
+        self.seen.insert(key.to_string(), now);
+    }
+
+    pub fn forget(&mut self, key: &str) {
+        if self.seen.remove(key).is_some() {
+            self.db.delete(format!("{}{}", DEDUP_PREFIX, key).as_bytes()).unwrap();
+        }
+    }
+
+    // Forgets keys that left the window. Returns how many were removed.
+    pub fn sweep(&mut self, now: u64) -> usize {
+        let window = self.window.as_millis() as u64;
+        let expired: Vec<String> = self.seen.iter()
+            .filter(|(_, &seen_at)| now >= seen_at + window)
+            .map(|(key, _)| key.clone())
+            .collect();
+        if expired.is_empty() {
+            return 0;
+        }
+        let mut batch = WriteBatch::default();
+        for key in &expired {
+            self.seen.remove(key);
+            batch.delete(format!("{}{}", DEDUP_PREFIX, key).as_bytes());
+        }
+        self.db.write(batch).unwrap();
+        expired.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use rocksdb::DB;
+use serde::{Serialize, Deserialize};
+
+// Where nodes used to keep their own copy of each exchange
+const EXCHANGE_PREFIX: &str = "__exchange:";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExchangeKind {
+    // Routing key must equal the binding key
+    Direct,
+    // Every bound queue gets every message
+    Fanout,
+    // Dot-separated binding patterns where `*` matches one word and `#` zero or more
+    Topic,
+    // Message headers are matched against the binding's headers
+    Headers,
+}
+
+impl FromStr for ExchangeKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(ExchangeKind::Direct),
+            "fanout" => Ok(ExchangeKind::Fanout),
+            "topic" => Ok(ExchangeKind::Topic),
+            "headers" => Ok(ExchangeKind::Headers),
+            other => Err(format!("Unknown exchange kind '{}'", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Binding {
+    pub queue: String,
+    #[serde(default)]
+    pub routing_key: String,
+    // Only used by headers exchanges
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    // Headers exchanges: require every binding header (true) or any one of them (false)
+    #[serde(default = "default_match_all")]
+    pub match_all: bool,
+}
+
+fn default_match_all() -> bool {
+    true
+}
+
+impl Binding {
+    pub fn new(queue: &str, routing_key: &str) -> Self {
+        Binding {
+            queue: queue.to_string(),
+            routing_key: routing_key.to_string(),
+            headers: HashMap::new(),
+            match_all: true,
+        }
+    }
+
+    fn matches(&self, kind: ExchangeKind, routing_key: &str, headers: &HashMap<String, String>) -> bool {
+        match kind {
+            ExchangeKind::Direct => self.routing_key == routing_key,
+            ExchangeKind::Fanout => true,
+            ExchangeKind::Topic => topic_matches(&self.routing_key, routing_key),
+            ExchangeKind::Headers => {
+                let mut checks = self.headers.iter().map(|(k, v)| headers.get(k) == Some(v));
+                if self.match_all {
+                    checks.all(|hit| hit)
+                } else {
+                    checks.any(|hit| hit)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Exchange {
+    pub name: String,
+    pub kind: ExchangeKind,
+    pub bindings: Vec<Binding>,
+}
+
+impl Exchange {
+    // Names of the queues a message is delivered to, each listed once
+    pub fn route(&self, routing_key: &str, headers: &HashMap<String, String>) -> Vec<String> {
+        let mut queues: Vec<String> = Vec::new();
+        for binding in &self.bindings {
+            if binding.matches(self.kind, routing_key, headers) && !queues.contains(&binding.queue) {
+                queues.push(binding.queue.clone());
+            }
+        }
+        queues
+    }
+}
+
+// Matches an AMQP-style topic pattern against a dot-separated routing key
+pub fn topic_matches(pattern: &str, routing_key: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    let key: Vec<&str> = if routing_key.is_empty() { Vec::new() } else { routing_key.split('.').collect() };
+    match_words(&pattern, &key)
+}
+
+fn match_words(pattern: &[&str], key: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((&"#", rest)) => (0..=key.len()).any(|skip| match_words(rest, &key[skip..])),
+        Some((&word, rest)) => match key.split_first() {
+            Some((&first, key_rest)) => (word == "*" || word == first) && match_words(rest, key_rest),
+            None => false,
+        },
+    }
+}
+
+// Exchanges and their bindings. Part of the replicated ClusterState, so every
+// node routes the same way; changes arrive as cluster commands.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ExchangeRegistry {
+    exchanges: HashMap<String, Exchange>,
+}
+
+impl ExchangeRegistry {
+    // Exchanges persisted by nodes that predate the cluster state, to be handed
+    // over once
+    pub fn load_legacy(db: &DB) -> Vec<Exchange> {
+        let mut exchanges = Vec::new();
+        let iter = db.iterator(rocksdb::IteratorMode::From(EXCHANGE_PREFIX.as_bytes(), rocksdb::Direction::Forward));
+        for item in iter {
+            let (key, value) = item.unwrap();
+            if !key.starts_with(EXCHANGE_PREFIX.as_bytes()) {
+                break;
+            }
+            match serde_json::from_slice::<Exchange>(&value) {
+                Ok(exchange) => exchanges.push(exchange),
+                Err(e) => eprintln!("Skipping unreadable exchange record: {}", e),
+            }
+        }
+        exchanges
+    }
+
+    pub fn legacy_key(name: &str) -> String {
+        format!("{}{}", EXCHANGE_PREFIX, name)
+    }
+
+    // Declaring an existing exchange is a no-op unless the kind differs
+    pub fn declare(&mut self, name: &str, kind: ExchangeKind) -> Result<(), String> {
+        if let Some(existing) = self.exchanges.get(name) {
+            if existing.kind != kind {
+                return Err(format!("Exchange '{}' already exists as {:?}", name, existing.kind));
+            }
+            return Ok(());
+        }
+        let exchange = Exchange {
+            name: name.to_string(),
+            kind,
+            bindings: Vec::new(),
+        };
+        self.exchanges.insert(name.to_string(), exchange);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, name: &str) -> bool {
+        self.exchanges.remove(name).is_some()
+    }
+
+    // Adds a binding; binding the same queue and key twice is a no-op.
+    // Returns whether the binding is new.
+    pub fn bind(&mut self, exchange: &str, binding: Binding) -> Result<bool, String> {
+        let entry = self.exchanges.get_mut(exchange)
+            .ok_or_else(|| format!("Exchange '{}' not found", exchange))?;
+        if entry.bindings.contains(&binding) {
+            return Ok(false);
+        }
+        entry.bindings.push(binding);
+        Ok(true)
+    }
+
+    pub fn unbind(&mut self, exchange: &str, binding: &Binding) -> Result<bool, String> {
+        let entry = self.exchanges.get_mut(exchange)
+            .ok_or_else(|| format!("Exchange '{}' not found", exchange))?;
+        let before = entry.bindings.len();
+        entry.bindings.retain(|b| b != binding);
+        Ok(entry.bindings.len() != before)
+    }
+
+    pub fn route(&self, exchange: &str, routing_key: &str, headers: &HashMap<String, String>) -> Option<Vec<String>> {
+        self.exchanges.get(exchange).map(|e| e.route(routing_key, headers))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Exchange> {
+        self.exchanges.get(name)
+    }
+
+    pub fn list(&self) -> Vec<Exchange> {
+        let mut exchanges: Vec<Exchange> = self.exchanges.values().cloned().collect();
+        exchanges.sort_by(|a, b| a.name.cmp(&b.name));
+        exchanges
+    }
+}
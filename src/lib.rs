@@ -1,7 +1,9 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 use raft::prelude::*;
-use rocksdb::{DB, Options};
+use rocksdb::{DB, Options, WriteBatch};
 use serde::{Serialize, Deserialize};
 use raft::NodeId;
 use prost::Message as ProstMessage;
@@ -15,35 +17,183 @@ pub mod proto {
 }
 
 use proto::RapidMQMessage;
+use dead_letter::{DeadLetter, DeadLetterPolicy};
+use priority::{PriorityMode, ReadyQueue, StoredMessage};
+use scheduler::TimerIndex;
+use exchange::{Binding, Exchange, ExchangeKind, ExchangeRegistry};
+use consumer_group::{Assignment, ConsumerGroupManager, GroupDelivery, GroupDescription};
+use dedup::DedupIndex;
+use transaction::{TransactionManager, TxOp};
+use replication::{ReplicaOp, ReplicaSet, REPLICATION_TIMEOUT};
 
 // Message struct to represent individual messages
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Message {
     pub id: String,
-    pub content: String,
+    pub payload: Vec<u8>,
+    pub headers: HashMap<String, String>,
+    // 0 (lowest) to priority::MAX_PRIORITY; only honoured by queues with a priority mode
+    pub priority: u8,
+    // Unix time in milliseconds before which the message is not delivered
+    pub deliver_at: Option<u64>,
+    // Unix time in milliseconds after which the message is discarded
+    pub expires_at: Option<u64>,
+    // Unix time in milliseconds when the producer created the message
+    pub timestamp: Option<u64>,
+    pub content_type: Option<String>,
+    pub correlation_id: Option<String>,
+    pub reply_to: Option<String>,
+    // Compacted streams keep only the newest message per key; an empty payload deletes the key
+    pub key: Option<String>,
 }
 
 impl From<Message> for RapidMQMessage {
     fn from(msg: Message) -> Self {
         RapidMQMessage {
             id: msg.id,
-            content: msg.content,
+            content: String::new(),
+            headers: msg.headers,
+            priority: msg.priority as u32,
+            deliver_at: msg.deliver_at.unwrap_or(0),
+            expires_at: msg.expires_at.unwrap_or(0),
+            payload: msg.payload,
+            timestamp: msg.timestamp.unwrap_or(0),
+            content_type: msg.content_type.unwrap_or_default(),
+            correlation_id: msg.correlation_id.unwrap_or_default(),
+            reply_to: msg.reply_to.unwrap_or_default(),
+            key: msg.key.unwrap_or_default(),
         }
     }
 }
 
 impl From<RapidMQMessage> for Message {
     fn from(msg: RapidMQMessage) -> Self {
+        // Records written before `payload` existed only carry the text `content`
+        let payload = if msg.payload.is_empty() {
+            msg.content.into_bytes()
+        } else {
+            msg.payload
+        };
         Message {
             id: msg.id,
-            content: msg.content,
+            payload,
+            headers: msg.headers,
+            priority: msg.priority.min(priority::MAX_PRIORITY as u32) as u8,
+            deliver_at: Some(msg.deliver_at).filter(|&at| at > 0),
+            expires_at: Some(msg.expires_at).filter(|&at| at > 0),
+            timestamp: Some(msg.timestamp).filter(|&at| at > 0),
+            content_type: Some(msg.content_type).filter(|s| !s.is_empty()),
+            correlation_id: Some(msg.correlation_id).filter(|s| !s.is_empty()),
+            reply_to: Some(msg.reply_to).filter(|s| !s.is_empty()),
+            key: Some(msg.key).filter(|s| !s.is_empty()),
         }
     }
 }
 
+// How long a consumed message stays hidden before it is redelivered
+pub const DEFAULT_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Nodes predating the cluster log kept subscriptions as JSON lists under
+// `__subscription:<queue>`
+const SUBSCRIPTION_PREFIX: &str = "__subscription:";
+
+// Nodes predating the cluster log kept committed stream offsets as JSON maps
+// under `__offsets:<stream>`
+const OFFSETS_PREFIX: &str = "__offsets:";
+
+// Taps are streams named `<queue>@tap` or `<exchange>@tap/<pattern>` that
+// keep an hour of copies for live viewers
+const TAP_SUFFIX: &str = "@tap";
+const TAP_RETENTION_SECS: u64 = 60 * 60;
+
+// How often the background task removes expired messages
+pub const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+// How often compacted streams are rewritten to drop superseded records
+pub const COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
+
+// How long a request for a replicated queue keeps retrying after the node
+// serving it stops answering, long enough for a replica to take over
+pub const FAILOVER_WAIT: Duration = Duration::from_secs(10);
+const FAILOVER_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+// How long joining a consumer group waits for the cluster to apply the join
+const GROUP_JOIN_WAIT: Duration = Duration::from_secs(5);
+const GROUP_JOIN_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// A message handed out to a consumer, to be acked or nacked by its tag
+#[derive(Clone, Debug)]
+pub struct Delivery {
+    pub tag: String,
+    pub message: Message,
+    // How many times this message has been handed out, including this delivery
+    pub delivery_count: u32,
+}
+
+// Whether a publish was stored or recognised as a retry of an earlier one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PublishOutcome {
+    Published,
+    // Same message id, or producer id and sequence, seen inside the dedup window
+    Duplicate,
+    // Stored by the queue leader, but fewer replicas than the write quorum
+    // confirmed it in time. It is not remembered for deduplication, so a retry
+    // stores it again.
+    Unreplicated,
+    // The node serving the queue could not be reached, or does not have the
+    // queue open. Nothing was stored.
+    Unavailable,
+}
+
+// A queue change written to a RocksDB batch but not yet visible in memory
+pub enum StagedWrite {
+    Ready(StoredMessage),
+    Scheduled { due: u64, seq: u64, encoded: Vec<u8> },
+    Ack { tag: String, seq: u64 },
+}
+
+// Queue settings are persisted as JSON under `__queue_config:<name>`
+const QUEUE_CONFIG_PREFIX: &str = "__queue_config:";
+
+// The settings a queue keeps across restarts
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct QueueConfig {
+    #[serde(default)]
+    priority_mode: PriorityMode,
+    #[serde(default)]
+    dead_letter_policy: Option<DeadLetterPolicy>,
+    #[serde(default)]
+    default_ttl: Option<Duration>,
+}
+
+// A delivered message that is hidden until acked or its deadline passes
+struct InFlight {
+    message: StoredMessage,
+    deadline: Instant,
+}
+
 // Queue struct to manage message queues
+//
+// Every message is stored under `name:<seq>` where `seq` is a zero-padded,
+// monotonically increasing sequence number, so the RocksDB key order is the
+// queue order. Keys are only deleted on ack, which means a crash between
+// delivery and ack redelivers the message after restart. Messages with a
+// future `deliver_at` wait in a separate timer index until they are due.
 pub struct Queue {
-    messages: VecDeque<Vec<u8>>,
+    messages: ReadyQueue,
+    timers: TimerIndex,
+    priority_mode: PriorityMode,
+    in_flight: HashMap<String, InFlight>,
+    visibility_timeout: Duration,
+    default_ttl: Option<Duration>,
+    delivery_counts: HashMap<u64, u32>,
+    dead_letter_policy: Option<DeadLetterPolicy>,
+    dead_letters: Vec<DeadLetter>,
+    next_seq: u64,
+    // Woken whenever a message becomes visible, for consumers waiting on an empty queue
+    ready: Arc<Notify>,
+    // Changes not yet shipped to followers, recorded only while this node leads a replicated queue
+    journal: Option<Vec<ReplicaOp>>,
     db: Arc<DB>,
     name: String,
 }
@@ -51,245 +201,3331 @@ pub struct Queue {
 impl Queue {
     pub fn new(name: &str, db: Arc<DB>) -> Self {
         let messages = Queue::load_messages(name, &db);
+        let timers = TimerIndex::load(name, &db);
+        let next_seq = messages.last_seq().max(timers.last_id()).map_or(0, |seq| seq + 1);
+        let config = db.get(Queue::config_key(name).as_bytes()).unwrap()
+            .and_then(|value| serde_json::from_slice::<QueueConfig>(&value).ok())
+            .unwrap_or_default();
         Queue {
             messages,
+            timers,
+            priority_mode: config.priority_mode,
+            in_flight: HashMap::new(),
+            visibility_timeout: DEFAULT_VISIBILITY_TIMEOUT,
+            default_ttl: config.default_ttl,
+            delivery_counts: HashMap::new(),
+            dead_letter_policy: config.dead_letter_policy,
+            dead_letters: Vec::new(),
+            next_seq,
+            ready: Arc::new(Notify::new()),
+            journal: None,
             db,
             name: name.to_string(),
         }
     }
 
-    pub fn enqueue(&mut self, message: Message) {
-        let proto_message: RapidMQMessage = message.into();
-        let encoded = proto_message.encode_to_vec();
-        self.messages.push_back(encoded);
-        self.persist_message(&encoded);
+    pub fn set_visibility_timeout(&mut self, timeout: Duration) {
+        self.visibility_timeout = timeout;
     }
 
-    pub fn dequeue(&mut self) -> Option<Message> {
-        self.messages.pop_front().and_then(|encoded| {
-            RapidMQMessage::decode(&encoded[..])
-                .ok()
-                .map(|proto_message| proto_message.into())
-        })
+    // TTL applied to messages published without their own expiry
+    pub fn set_default_ttl(&mut self, ttl: Option<Duration>) {
+        self.default_ttl = ttl;
+        self.persist_config();
     }
 
-    fn persist_message(&self, encoded: &[u8]) {
-        let key = format!("{}:{}", self.name, uuid::Uuid::new_v4());
-        self.db.put(key.as_bytes(), encoded).unwrap();
+    pub fn set_priority_mode(&mut self, mode: PriorityMode) {
+        self.priority_mode = mode;
+        self.persist_config();
     }
 
-    fn load_messages(name: &str, db: &DB) -> VecDeque<Vec<u8>> {
-        let mut messages = VecDeque::new();
-        let prefix = format!("{}:", name);
-        let iter = db.iterator(rocksdb::IteratorMode::From(prefix.as_bytes(), rocksdb::Direction::Forward));
-        for (_, value) in iter {
-            messages.push_back(value.to_vec());
-        }
-        messages
+    pub fn priority_mode(&self) -> PriorityMode {
+        self.priority_mode
     }
-}
 
-// RapidMQ struct to manage the overall messaging system
-#[derive(Clone)]
-pub struct RapidMQ {
-    queues: Arc<Mutex<HashMap<String, Queue>>>,
-    subscribers: Arc<Mutex<HashMap<String, Vec<String>>>>,
-    db: Arc<DB>,
-    cluster_manager: Arc<ClusterManager>,
-}
+    pub fn set_dead_letter_policy(&mut self, policy: Option<DeadLetterPolicy>) {
+        self.dead_letter_policy = policy;
+        self.persist_config();
+    }
 
-impl RapidMQ {
-    pub fn new(node_id: NodeId, peers: Vec<NodeId>) -> Self {
-        let mut opts = Options::default();
-        opts.create_if_missing(true);
-        let db = Arc::new(DB::open(&opts, format!("rapidmq_storage_{}", node_id)).unwrap());
+    pub fn dead_letter_policy(&self) -> Option<&DeadLetterPolicy> {
+        self.dead_letter_policy.as_ref()
+    }
 
-        metrics::register_metrics();
+    // Starts or stops recording changes for `take_journal`, for a node that
+    // leads a replicated queue
+    pub fn set_replicated(&mut self, replicated: bool) {
+        self.journal = if replicated { Some(self.journal.take().unwrap_or_default()) } else { None };
+    }
+
+    pub fn is_replicated(&self) -> bool {
+        self.journal.is_some()
+    }
 
-        let cluster_manager = Arc::new(ClusterManager::new(node_id, peers));
+    // Drains the changes recorded since the last call, oldest first
+    pub fn take_journal(&mut self) -> Vec<ReplicaOp> {
+        self.journal.as_mut().map(std::mem::take).unwrap_or_default()
+    }
 
-        RapidMQ {
-            queues: Arc::new(Mutex::new(HashMap::new())),
-            subscribers: Arc::new(Mutex::new(HashMap::new())),
-            db,
-            cluster_manager,
+    // Every persisted message as changes that rebuild this queue on an empty
+    // replica. In-flight messages count as visible, since an unacked delivery is
+    // redelivered by whichever replica serves the queue next.
+    pub fn replica_snapshot(&self) -> Vec<ReplicaOp> {
+        let mut visible: Vec<&StoredMessage> = self.messages.iter(PriorityMode::Fifo)
+            .chain(self.in_flight.values().map(|entry| &entry.message))
+            .collect();
+        visible.sort_by_key(|stored| stored.seq);
+        let ready = visible.into_iter().map(|stored| ReplicaOp::Ready { seq: stored.seq, encoded: stored.encoded.clone() });
+        let scheduled = self.timers.iter().map(|(due, seq, encoded)| ReplicaOp::Scheduled { due, seq, encoded: encoded.clone() });
+        ready.chain(scheduled).collect()
+    }
+
+    // Applies a change shipped by the queue's leader to this replica
+    pub fn apply_replica(&mut self, op: ReplicaOp) {
+        match op {
+            ReplicaOp::Ready { seq, encoded } => {
+                self.db.put(Queue::message_key(&self.name, seq).as_bytes(), &encoded).unwrap();
+                self.messages.insert(Queue::stored(seq, encoded));
+                self.next_seq = self.next_seq.max(seq + 1);
+            }
+            ReplicaOp::Scheduled { due, seq, encoded } => {
+                self.timers.schedule(&self.db, due, seq, encoded);
+                self.next_seq = self.next_seq.max(seq + 1);
+            }
+            ReplicaOp::Promote { due, id, seq } => {
+                let Some(encoded) = self.timers.remove(due, id) else {
+                    eprintln!("Replica of queue '{}' has no timer {}:{} to promote", self.name, due, id);
+                    return;
+                };
+                let mut batch = WriteBatch::default();
+                batch.delete(self.timers.key(due, id).as_bytes());
+                batch.put(Queue::message_key(&self.name, seq).as_bytes(), &encoded);
+                self.db.write(batch).unwrap();
+                self.messages.insert(Queue::stored(seq, encoded));
+                self.next_seq = self.next_seq.max(seq + 1);
+            }
+            ReplicaOp::Remove { seq } => {
+                self.db.delete(Queue::message_key(&self.name, seq).as_bytes()).unwrap();
+                self.messages.remove_where(|m| m.seq == seq);
+                self.in_flight.retain(|_, entry| entry.message.seq != seq);
+                self.delivery_counts.remove(&seq);
+            }
         }
     }
 
-    pub fn create_queue(&self, queue_name: &str) {
-        let node_id = self.cluster_manager.assign_queue(queue_name);
-        if node_id == self.cluster_manager.node.lock().id() {
-            let mut queues = self.queues.lock().unwrap();
-            queues.entry(queue_name.to_string()).or_insert_with(|| Queue::new(queue_name, self.db.clone()));
+    // Replaces the contents of this replica with a snapshot from the leader
+    pub fn reset_replica(&mut self, ops: Vec<ReplicaOp>) {
+        let mut batch = WriteBatch::default();
+        for stored in self.messages.remove_where(|_| true) {
+            batch.delete(Queue::message_key(&self.name, stored.seq).as_bytes());
+        }
+        for (_, entry) in self.in_flight.drain() {
+            batch.delete(Queue::message_key(&self.name, entry.message.seq).as_bytes());
+        }
+        let timers: Vec<(u64, u64)> = self.timers.iter().map(|(due, id, _)| (due, id)).collect();
+        for (due, id) in timers {
+            batch.delete(self.timers.key(due, id).as_bytes());
+            self.timers.remove(due, id);
+        }
+        self.db.write(batch).unwrap();
+        self.delivery_counts.clear();
+        self.dead_letters.clear();
+        for op in ops {
+            self.apply_replica(op);
         }
-        metrics::QUEUE_COUNT.inc();
     }
 
-    pub async fn publish(&self, queue_name: &str, message: Message) {
-        if let Some(node_id) = self.cluster_manager.get_queue_node(queue_name) {
-            if node_id == self.cluster_manager.node.lock().id() {
-                let mut queues = self.queues.lock().unwrap();
-                if let Some(queue) = queues.get_mut(queue_name) {
-                    queue.enqueue(message.clone());
+    pub fn enqueue(&mut self, message: Message) {
+        let mut batch = WriteBatch::default();
+        let staged = self.stage_enqueue(&mut batch, message);
+        self.db.write(batch).unwrap();
+        self.apply(staged);
+    }
+
+    // Stores several messages with a single RocksDB write
+    pub fn enqueue_all(&mut self, messages: Vec<Message>) {
+        let mut batch = WriteBatch::default();
+        let staged: Vec<StagedWrite> = messages.into_iter()
+            .map(|message| self.stage_enqueue(&mut batch, message))
+            .collect();
+        self.db.write(batch).unwrap();
+        for write in staged {
+            self.apply(write);
+        }
+    }
+
+    // Adds a message's record to `batch` without touching the in-memory queue.
+    // Once the batch is written, pass the result to `apply`; if it is dropped
+    // instead, the queue is unchanged apart from a skipped sequence number.
+    pub fn stage_enqueue(&mut self, batch: &mut WriteBatch, mut message: Message) -> StagedWrite {
+        if message.expires_at.is_none() {
+            message.expires_at = self.default_ttl.map(|ttl| scheduler::now_millis() + ttl.as_millis() as u64);
+        }
+        let priority = message.priority.min(priority::MAX_PRIORITY);
+        let deliver_at = message.deliver_at;
+        let expires_at = message.expires_at;
+        let proto_message: RapidMQMessage = message.into();
+        let encoded = proto_message.encode_to_vec();
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        match deliver_at {
+            Some(due) if due > scheduler::now_millis() => {
+                batch.put(self.timers.key(due, seq).as_bytes(), &encoded);
+                StagedWrite::Scheduled { due, seq, encoded }
+            }
+            _ => {
+                batch.put(Queue::message_key(&self.name, seq).as_bytes(), &encoded);
+                StagedWrite::Ready(StoredMessage { seq, priority, expires_at, encoded })
+            }
+        }
+    }
+
+    // Adds the deletion of an in-flight message to `batch`. None if the tag is
+    // unknown or its visibility timeout already ran out.
+    pub fn stage_ack(&self, batch: &mut WriteBatch, tag: &str) -> Option<StagedWrite> {
+        let seq = self.in_flight.get(tag)?.message.seq;
+        batch.delete(Queue::message_key(&self.name, seq).as_bytes());
+        Some(StagedWrite::Ack { tag: tag.to_string(), seq })
+    }
+
+    // Brings the in-memory queue in line with a staged write that was committed
+    pub fn apply(&mut self, staged: StagedWrite) {
+        match staged {
+            StagedWrite::Ready(stored) => {
+                self.record(ReplicaOp::Ready { seq: stored.seq, encoded: stored.encoded.clone() });
+                self.messages.push_back(stored);
+                self.ready.notify_waiters();
+            }
+            StagedWrite::Scheduled { due, seq, encoded } => {
+                self.record(ReplicaOp::Scheduled { due, seq, encoded: encoded.clone() });
+                self.timers.insert(due, seq, encoded);
+            }
+            StagedWrite::Ack { tag, seq } => {
+                self.record(ReplicaOp::Remove { seq });
+                // The message may have been made visible again in the meantime
+                if self.in_flight.remove(&tag).is_none() {
+                    self.messages.remove_where(|m| m.seq == seq);
                 }
+                self.delivery_counts.remove(&seq);
+            }
+        }
+    }
 
-                let subscribers = self.subscribers.lock().unwrap();
-                if let Some(subs) = subscribers.get(queue_name) {
-                    for subscriber in subs {
-                        if let Some(sub_queue) = queues.get_mut(subscriber) {
-                            sub_queue.enqueue(message.clone());
-                        }
-                    }
+    // Hands out the next visible message. Messages that cannot be decoded or have
+    // used up their deliveries are set aside for the dead-letter queue instead;
+    // collect them with `take_dead_letters`.
+    pub fn dequeue(&mut self) -> Option<Delivery> {
+        self.promote_due_timers();
+        self.requeue_expired();
+        while let Some(stored) = self.messages.pop(self.priority_mode) {
+            let seq = stored.seq;
+            if stored.is_expired(scheduler::now_millis()) {
+                self.expire(stored);
+                continue;
+            }
+            let message: Message = match RapidMQMessage::decode(&stored.encoded[..]) {
+                Ok(proto_message) => proto_message.into(),
+                Err(e) => {
+                    let reason = format!("decode error: {}", e);
+                    self.dead_letter(seq, dead_letter::undecodable(seq, &stored.encoded), &reason);
+                    continue;
                 }
-            } else {
-                // Forward the message to the appropriate node
-                if let Err(e) = self.cluster_manager.publish_remote(node_id, queue_name, message).await {
-                    eprintln!("Failed to publish message to remote node: {}", e);
+            };
+
+            let delivered = self.delivery_counts.get(&seq).copied().unwrap_or(0);
+            if let Some(policy) = &self.dead_letter_policy {
+                if delivered >= policy.max_deliveries {
+                    self.dead_letter(seq, message, "max deliveries exceeded");
+                    continue;
                 }
             }
+
+            let delivery_count = delivered + 1;
+            self.delivery_counts.insert(seq, delivery_count);
+            let tag = uuid::Uuid::new_v4().to_string();
+            self.in_flight.insert(tag.clone(), InFlight {
+                message: stored,
+                deadline: Instant::now() + self.visibility_timeout,
+            });
+            return Some(Delivery { tag, message, delivery_count });
         }
-        metrics::MESSAGES_PUBLISHED.inc();
-        metrics::TOTAL_MESSAGES.inc();
+        None
     }
 
-    pub async fn consume(&self, queue_name: &str) -> Option<Message> {
-        if let Some(node_id) = self.cluster_manager.get_queue_node(queue_name) {
-            if node_id == self.cluster_manager.node.lock().id() {
-                let mut queues = self.queues.lock().unwrap();
-                let message = queues.get_mut(queue_name).and_then(|queue| queue.dequeue());
-                if message.is_some() {
-                    metrics::MESSAGES_CONSUMED.inc();
-                    metrics::TOTAL_MESSAGES.dec();
-                }
-                message
-            } else {
-                // Forward the consume request to the appropriate node
-                match self.cluster_manager.consume_remote(node_id, queue_name).await {
-                    Ok(message) => {
-                        if message.is_some() {
-                            metrics::MESSAGES_CONSUMED.inc();
-                            metrics::TOTAL_MESSAGES.dec();
-                        }
-                        message
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to consume message from remote node: {}", e);
-                        None
-                    }
+    // Removes an in-flight message for good; returns false for unknown or expired tags
+    pub fn ack(&mut self, tag: &str) -> bool {
+        match self.in_flight.remove(tag) {
+            Some(entry) => {
+                self.delivery_counts.remove(&entry.message.seq);
+                self.delete_message(entry.message.seq);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Sends an in-flight message straight to the dead-letter queue, e.g. after a processing failure
+    pub fn reject(&mut self, tag: &str, reason: &str) -> bool {
+        match self.in_flight.remove(tag) {
+            Some(entry) => {
+                let stored = entry.message;
+                match RapidMQMessage::decode(&stored.encoded[..]) {
+                    Ok(proto_message) => self.dead_letter(stored.seq, proto_message.into(), reason),
+                    Err(_) => self.dead_letter(stored.seq, dead_letter::undecodable(stored.seq, &stored.encoded), reason),
                 }
+                true
             }
-        } else {
-            None
+            None => false,
         }
     }
 
-    pub fn subscribe(&self, queue_name: &str, subscriber_queue: &str) {
-        let mut subscribers = self.subscribers.lock().unwrap();
-        subscribers
-            .entry(queue_name.to_string())
-            .or_insert_with(Vec::new)
-            .push(subscriber_queue.to_string());
+    // Drains messages waiting to be moved to a dead-letter queue. Each one must be
+    // stored in its target queue and then released with `remove_dead_lettered`.
+    pub fn take_dead_letters(&mut self) -> Vec<DeadLetter> {
+        std::mem::take(&mut self.dead_letters)
     }
 
-    pub async fn run(&self) {
-        self.cluster_manager.run().await;
+    pub fn remove_dead_lettered(&mut self, seq: u64) {
+        self.delete_message(seq);
     }
 
-    pub fn add_node(&self, node_id: NodeId, address: String) {
-        self.cluster_manager.add_node(node_id, address);
+    // Returns up to `limit` visible messages without delivering them
+    pub fn peek(&self, limit: usize) -> Vec<Message> {
+        self.messages.iter(self.priority_mode)
+            .take(limit)
+            .filter_map(|stored| RapidMQMessage::decode(&stored.encoded[..]).ok())
+            .map(|proto_message| proto_message.into())
+            .collect()
     }
 
-    pub fn remove_node(&self, node_id: NodeId) {
-        self.cluster_manager.remove_node(node_id);
+    // Drops expired messages from the visible queue without waiting for a consumer.
+    // Returns how many were removed.
+    pub fn sweep_expired(&mut self) -> usize {
+        let now = scheduler::now_millis();
+        let expired = self.messages.remove_where(|stored| stored.is_expired(now));
+        let count = expired.len();
+        for stored in expired {
+            self.expire(stored);
+        }
+        count
     }
 
-    pub async fn adaptive_publish(&self, queue_name: &str, message: Message) -> Result<(), Box<dyn std::error::Error>> {
-        let priority = self.cluster_manager.ai_module.predict_message_priority(&message.content).await?;
-        let node_id = self.cluster_manager.assign_queue(queue_name).await;
-        
-        if priority > 0.8 {
-            // High priority message, use quantum-optimized routing
-            let optimized_route = self.cluster_manager.quantum_module.optimize_routing(vec![node_id.0]);
-            let target_node = NodeId::from(optimized_route[0]);
-            self.cluster_manager.publish_remote(target_node, queue_name, message).await?;
-        } else {
-            // Normal priority, use standard routing
-            self.publish(queue_name, message).await?;
+    fn expire(&mut self, stored: StoredMessage) {
+        metrics::MESSAGES_EXPIRED.inc();
+        let park = self.dead_letter_policy.as_ref().map_or(false, |policy| policy.dead_letter_expired);
+        match RapidMQMessage::decode(&stored.encoded[..]) {
+            Ok(proto_message) if park => self.dead_letter(stored.seq, proto_message.into(), "expired"),
+            _ => {
+                self.delivery_counts.remove(&stored.seq);
+                self.delete_message(stored.seq);
+            }
         }
-        
-        Ok(())
+    }
+
+    fn dead_letter(&mut self, seq: u64, message: Message, reason: &str) {
+        let delivery_count = self.delivery_counts.remove(&seq).unwrap_or(0);
+        match &self.dead_letter_policy {
+            Some(policy) => {
+                let message = dead_letter::mark(message, policy, &self.name, reason, delivery_count);
+                self.dead_letters.push(DeadLetter {
+                    seq,
+                    dead_letter_queue: policy.dead_letter_queue.clone(),
+                    message,
+                });
+            }
+            None => {
+                // Nowhere to park it; drop it rather than resurrecting it on every restart
+                eprintln!("Dropping message {} from queue '{}': {}", message.id, self.name, reason);
+                self.delete_message(seq);
+            }
+        }
+    }
+
+    // Makes an in-flight message visible again at the head of the queue
+    pub fn nack(&mut self, tag: &str) -> bool {
+        match self.in_flight.remove(tag) {
+            Some(entry) => {
+                self.messages.insert(entry.message);
+                self.ready.notify_waiters();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Notified when a message is published or nacked into this queue. Delayed
+    // messages and expired visibility timeouts are not signalled; they surface
+    // on the next `dequeue` after `next_wakeup`.
+    pub fn ready_signal(&self) -> Arc<Notify> {
+        self.ready.clone()
+    }
+
+    // The next moment a message becomes visible without a publish: a delayed
+    // message falling due or an in-flight message timing out
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        let timer = self.timers.next_due().map(|due| {
+            Instant::now() + Duration::from_millis(due.saturating_sub(scheduler::now_millis()))
+        });
+        let in_flight = self.in_flight.values().map(|entry| entry.deadline).min();
+        timer.into_iter().chain(in_flight).min()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn is_in_flight(&self, tag: &str) -> bool {
+        self.in_flight.contains_key(tag)
+    }
+
+    pub fn in_flight_count(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn scheduled_count(&self) -> usize {
+        self.timers.len()
+    }
+
+    // Moves delayed messages that are now due into the queue log, behind
+    // everything already visible
+    fn promote_due_timers(&mut self) {
+        for ((due, id), encoded) in self.timers.take_due(scheduler::now_millis()) {
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            let mut batch = WriteBatch::default();
+            batch.delete(self.timers.key(due, id).as_bytes());
+            batch.put(Queue::message_key(&self.name, seq).as_bytes(), &encoded);
+            self.db.write(batch).unwrap();
+            self.record(ReplicaOp::Promote { due, id, seq });
+            self.messages.push_back(Queue::stored(seq, encoded));
+        }
+    }
+
+    // Returns messages whose visibility timeout has passed to the queue, keeping sequence order
+    fn requeue_expired(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<String> = self.in_flight.iter()
+            .filter(|(_, entry)| entry.deadline <= now)
+            .map(|(tag, _)| tag.clone())
+            .collect();
+        for tag in expired {
+            if let Some(entry) = self.in_flight.remove(&tag) {
+                self.messages.insert(entry.message);
+            }
+        }
+    }
+
+    // Rebuilds the in-memory entry for a persisted record. Undecodable records
+    // land in priority 0 and are dead-lettered when reached.
+    fn stored(seq: u64, encoded: Vec<u8>) -> StoredMessage {
+        let (priority, expires_at) = match RapidMQMessage::decode(&encoded[..]) {
+            Ok(m) => (m.priority.min(priority::MAX_PRIORITY as u32) as u8, Some(m.expires_at).filter(|&at| at > 0)),
+            Err(_) => (0, None),
+        };
+        StoredMessage { seq, priority, expires_at, encoded }
+    }
+
+    fn message_key(name: &str, seq: u64) -> String {
+        format!("{}:{:020}", name, seq)
+    }
+
+    // Parses the sequence number out of a key, rejecting keys that belong to other queues
+    fn parse_seq(name: &str, key: &[u8]) -> Option<u64> {
+        let rest = key.strip_prefix(name.as_bytes())?.strip_prefix(b":")?;
+        if rest.len() != 20 || !rest.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        std::str::from_utf8(rest).ok()?.parse().ok()
+    }
+
+    fn delete_message(&mut self, seq: u64) {
+        let key = Queue::message_key(&self.name, seq);
+        self.db.delete(key.as_bytes()).unwrap();
+        self.record(ReplicaOp::Remove { seq });
+    }
+
+    fn record(&mut self, op: ReplicaOp) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(op);
+        }
+    }
+
+    fn persist_config(&self) {
+        let config = QueueConfig {
+            priority_mode: self.priority_mode,
+            dead_letter_policy: self.dead_letter_policy.clone(),
+            default_ttl: self.default_ttl,
+        };
+        let value = serde_json::to_vec(&config).unwrap();
+        self.db.put(Queue::config_key(&self.name).as_bytes(), value).unwrap();
+    }
+
+    fn config_key(name: &str) -> String {
+        format!("{}{}", QUEUE_CONFIG_PREFIX, name)
+    }
+
+    fn load_messages(name: &str, db: &DB) -> ReadyQueue {
+        let mut messages = ReadyQueue::new();
+        let prefix = format!("{}:", name);
+        let iter = db.iterator(rocksdb::IteratorMode::From(prefix.as_bytes(), rocksdb::Direction::Forward));
+        for item in iter {
+            let (key, value) = item.unwrap();
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            // Queues named `name:...` share the prefix; their keys are skipped here
+            if let Some(seq) = Queue::parse_seq(name, &key) {
+                messages.push_back(Queue::stored(seq, value.to_vec()));
+            }
+        }
+        messages
     }
 }
 
-// Tests
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tokio::runtime::Runtime;
+// Stream configuration is persisted as JSON under `__stream:<name>`
+const STREAM_PREFIX: &str = "__stream:";
 
-    fn setup() -> (RapidMQ, NodeId) {
-        let node_id = NodeId::from(1);
-        let peers = vec![NodeId::from(2), NodeId::from(3)];
-        (RapidMQ::new(node_id, peers), node_id)
+// How much history a stream keeps. Unset limits keep records forever.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub max_bytes: Option<u64>,
+    pub max_age_secs: Option<u64>,
+}
+
+// Turns a stream into a changelog that keeps only the newest record per key
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CompactionPolicy {
+    // How long a tombstone stays readable so consumers can observe the delete
+    #[serde(default = "default_tombstone_retention_secs")]
+    pub tombstone_retention_secs: u64,
+}
+
+fn default_tombstone_retention_secs() -> u64 {
+    24 * 60 * 60
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy {
+            tombstone_retention_secs: default_tombstone_retention_secs(),
+        }
     }
+}
 
-    #[test]
-    fn test_create_queue() {
-        let (mq, _) = setup();
-        mq.create_queue("test_queue");
-        assert!(mq.queues.lock().unwrap().contains_key("test_queue"));
-        assert_eq!(metrics::QUEUE_COUNT.get(), 1);
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct StreamConfig {
+    #[serde(default)]
+    retention: RetentionPolicy,
+    #[serde(default)]
+    compaction: Option<CompactionPolicy>,
+    // Head of the stream when records were last removed. Removed records may
+    // include the newest one, and their offsets must never be handed out again.
+    #[serde(default)]
+    min_next_offset: u64,
+}
+
+// Where a stream reader starts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartOffset {
+    Earliest,
+    Latest,
+    // First record stamped at or after this Unix time in milliseconds
+    Timestamp(u64),
+    Offset(u64),
+}
+
+impl std::str::FromStr for StartOffset {
+    type Err = String;
+
+    // Accepts `earliest`, `latest`, `timestamp:<millis>` or a plain offset
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "earliest" => Ok(StartOffset::Earliest),
+            "latest" => Ok(StartOffset::Latest),
+            _ => {
+                if let Some(ts) = s.strip_prefix("timestamp:") {
+                    ts.parse().map(StartOffset::Timestamp).map_err(|_| format!("Invalid timestamp '{}'", ts))
+                } else {
+                    s.parse().map(StartOffset::Offset).map_err(|_| format!("Invalid start offset '{}'", s))
+                }
+            }
+        }
     }
+}
 
-    #[test]
-    fn test_publish_consume() {
-        let rt = Runtime::new().unwrap();
-        rt.block_on(async {
-            let (mq, _) = setup();
-            mq.create_queue("test_queue");
+// Where a named consumer stands in a stream
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ConsumerOffset {
+    pub consumer: String,
+    // Next offset the consumer will read; None if it never committed
+    pub offset: Option<u64>,
+    pub next_offset: u64,
+    // Records between the committed offset and the head of the stream
+    pub lag: u64,
+}
 
-            let message = Message {
-                id: "1".to_string(),
-                content: "Test message".to_string(),
-            };
+// A message read from a stream together with its offset
+#[derive(Clone, Debug)]
+pub struct StreamRecord {
+    pub offset: u64,
+    pub message: Message,
+}
 
-            mq.publish("test_queue", message.clone()).await;
-            assert_eq!(metrics::MESSAGES_PUBLISHED.get(), 1);
-            assert_eq!(metrics::TOTAL_MESSAGES.get(), 1);
+struct RecordMeta {
+    timestamp: u64,
+    size: u64,
+    key: Option<String>,
+    // A keyed record with an empty payload, marking its key as deleted
+    tombstone: bool,
+}
 
-            let consumed = mq.consume("test_queue").await.unwrap();
-            assert_eq!(consumed.id, message.id);
-            assert_eq!(consumed.content, message.content);
-            assert_eq!(metrics::MESSAGES_CONSUMED.get(), 1);
-            assert_eq!(metrics::TOTAL_MESSAGES.get(), 0);
-        });
+impl RecordMeta {
+    fn new(message: &RapidMQMessage, size: u64) -> Self {
+        let key = Some(message.key.clone()).filter(|k| !k.is_empty());
+        RecordMeta {
+            timestamp: message.timestamp,
+            size,
+            tombstone: key.is_some() && message.payload.is_empty() && message.content.is_empty(),
+            key,
+        }
     }
+}
 
-    #[test]
-    fn test_subscribe() {
-        let rt = Runtime::new().unwrap();
-        rt.block_on(async {
-            let (mq, _) = setup();
-            mq.create_queue("main_queue");
-            mq.create_queue("subscriber_queue");
+// Append-only log of messages, stored under `name@stream:<offset>`.
+//
+// Reading never removes anything; every reader keeps its own offset. Records
+// are deleted from the front of the log when the retention policy says they
+// are too old or the stream is over its size limit. Compacted streams also
+// lose records superseded by a newer record with the same key, leaving gaps
+// in the offsets that readers skip over.
+pub struct Stream {
+    index: BTreeMap<u64, RecordMeta>,
+    config: StreamConfig,
+    next_offset: u64,
+    bytes: u64,
+    // Woken on every append, for readers tailing the head of the stream
+    appended: Arc<Notify>,
+    db: Arc<DB>,
+    name: String,
+}
+
+impl Stream {
+    pub fn open(name: &str, db: Arc<DB>) -> Self {
+        let config = db.get(Stream::config_key(name).as_bytes()).unwrap()
+            .and_then(|value| serde_json::from_slice::<StreamConfig>(&value).ok())
+            .unwrap_or_default();
+        let mut index = BTreeMap::new();
+        let mut bytes = 0;
+        let prefix = Stream::prefix(name);
+        let iter = db.iterator(rocksdb::IteratorMode::From(prefix.as_bytes(), rocksdb::Direction::Forward));
+        for item in iter {
+            let (key, value) = item.unwrap();
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            if let Some(offset) = Stream::parse_offset(&prefix, &key) {
+                let decoded = RapidMQMessage::decode(&value[..]).unwrap_or_default();
+                bytes += value.len() as u64;
+                index.insert(offset, RecordMeta::new(&decoded, value.len() as u64));
+            }
+        }
+        let next_offset = index.keys().next_back().map_or(0, |offset| offset + 1).max(config.min_next_offset);
+        let stream = Stream {
+            index,
+            config,
+            next_offset,
+            bytes,
+            appended: Arc::new(Notify::new()),
+            db,
+            name: name.to_string(),
+        };
+        stream.persist_config();
+        stream
+    }
 
-            mq.subscribe("main_queue", "subscriber_queue");
+    pub fn set_retention(&mut self, retention: RetentionPolicy) {
+        self.config.retention = retention;
+        self.persist_config();
+    }
 
-            let message = Message {
-                id: "1".to_string(),
-                content: "Test message".to_string(),
-            };
+    pub fn retention(&self) -> &RetentionPolicy {
+        &self.config.retention
+    }
 
-            mq.publish("main_queue", message.clone()).await;
+    // None turns compaction off; records already compacted away stay gone
+    pub fn set_compaction(&mut self, compaction: Option<CompactionPolicy>) {
+        self.config.compaction = compaction;
+        self.persist_config();
+    }
 
-            let consumed_main = mq.consume("main_queue").await.unwrap();
-            let consumed_sub = mq.consume("subscriber_queue").await.unwrap();
+    pub fn compaction(&self) -> Option<&CompactionPolicy> {
+        self.config.compaction.as_ref()
+    }
 
-            assert_eq!(consumed_main.id, message.id);
-            assert_eq!(consumed_sub.id, message.id);
-            assert_eq!(metrics::MESSAGES_PUBLISHED.get(), 1);
-            assert_eq!(metrics::MESSAGES_CONSUMED.get(), 2);
-        });
+    // Appends a message and returns its offset
+    pub fn append(&mut self, message: Message) -> u64 {
+        self.append_all(vec![message])[0]
+    }
+
+    // Appends messages in one write and returns their offsets
+    pub fn append_all(&mut self, messages: Vec<Message>) -> Vec<u64> {
+        let mut batch = WriteBatch::default();
+        let mut records = Vec::with_capacity(messages.len());
+        for (i, mut message) in messages.into_iter().enumerate() {
+            message.timestamp.get_or_insert_with(scheduler::now_millis);
+            let proto_message = RapidMQMessage::from(message);
+            let encoded = proto_message.encode_to_vec();
+            let offset = self.next_offset + i as u64;
+            batch.put(self.key(offset).as_bytes(), &encoded);
+            records.push((offset, RecordMeta::new(&proto_message, encoded.len() as u64)));
+        }
+        self.db.write(batch).unwrap();
+        let mut offsets = Vec::with_capacity(records.len());
+        for (offset, meta) in records {
+            self.bytes += meta.size;
+            self.index.insert(offset, meta);
+            offsets.push(offset);
+        }
+        self.next_offset += offsets.len() as u64;
+        self.appended.notify_waiters();
+        offsets
+    }
+
+    pub fn appended_signal(&self) -> Arc<Notify> {
+        self.appended.clone()
+    }
+
+    // Up to `max` records starting at `from`, skipping offsets already removed
+    pub fn read(&self, from: u64, max: usize) -> Vec<StreamRecord> {
+        let mut records = Vec::new();
+        if max == 0 {
+            return records;
+        }
+        let prefix = Stream::prefix(&self.name);
+        let start = self.key(from);
+        let iter = self.db.iterator(rocksdb::IteratorMode::From(start.as_bytes(), rocksdb::Direction::Forward));
+        for item in iter {
+            let (key, value) = item.unwrap();
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            let Some(offset) = Stream::parse_offset(&prefix, &key) else {
+                continue;
+            };
+            match RapidMQMessage::decode(&value[..]) {
+                Ok(decoded) => records.push(StreamRecord { offset, message: decoded.into() }),
+                Err(e) => eprintln!("Skipping undecodable record {} in stream '{}': {}", offset, self.name, e),
+            }
+            if records.len() >= max {
+                break;
+            }
+        }
+        records
+    }
+
+    // Turns a start position into a concrete offset between the first retained
+    // record and the head
+    pub fn resolve(&self, start: StartOffset) -> u64 {
+        match start {
+            StartOffset::Earliest => self.first_offset(),
+            StartOffset::Latest => self.next_offset,
+            StartOffset::Timestamp(ts) => self.index.iter()
+                .find(|(_, meta)| meta.timestamp >= ts)
+                .map_or(self.next_offset, |(offset, _)| *offset),
+            StartOffset::Offset(offset) => offset.clamp(self.first_offset(), self.next_offset),
+        }
+    }
+
+    // Offset of the oldest retained record, or the head when the stream is empty
+    pub fn first_offset(&self) -> u64 {
+        self.index.keys().next().copied().unwrap_or(self.next_offset)
+    }
+
+    // Offset the next appended message will get
+    pub fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn size_bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    // Drops records from the front until the stream satisfies its retention
+    // policy. Returns how many records were removed.
+    pub fn enforce_retention(&mut self, now: u64) -> usize {
+        let retention = self.config.retention.clone();
+        let cutoff = retention.max_age_secs.map(|secs| now.saturating_sub(secs * 1000));
+        let mut batch = WriteBatch::default();
+        let mut removed = 0;
+        while let Some((&offset, meta)) = self.index.iter().next() {
+            let too_big = retention.max_bytes.map_or(false, |max| self.bytes > max);
+            let too_old = cutoff.map_or(false, |cutoff| meta.timestamp < cutoff);
+            if !too_big && !too_old {
+                break;
+            }
+            self.bytes -= meta.size;
+            self.index.remove(&offset);
+            batch.delete(self.key(offset).as_bytes());
+            removed += 1;
+        }
+        if removed > 0 {
+            self.db.write(batch).unwrap();
+            self.config.min_next_offset = self.next_offset;
+            self.persist_config();
+        }
+        removed
+    }
+
+    // Drops every keyed record that has a newer record with the same key, and
+    // tombstones older than the policy's tombstone retention. Records without a
+    // key are never compacted. Returns how many records were removed.
+    pub fn compact(&mut self, now: u64) -> usize {
+        let Some(compaction) = self.config.compaction.clone() else {
+            return 0;
+        };
+        let tombstone_cutoff = now.saturating_sub(compaction.tombstone_retention_secs * 1000);
+        let mut latest: HashMap<&str, u64> = HashMap::new();
+        for (offset, meta) in &self.index {
+            if let Some(key) = &meta.key {
+                latest.insert(key, *offset);
+            }
+        }
+        let superseded: Vec<u64> = self.index.iter()
+            .filter(|(offset, meta)| match &meta.key {
+                Some(key) => latest[key.as_str()] != **offset || (meta.tombstone && meta.timestamp < tombstone_cutoff),
+                None => false,
+            })
+            .map(|(offset, _)| *offset)
+            .collect();
+        if superseded.is_empty() {
+            return 0;
+        }
+
+        let mut batch = WriteBatch::default();
+        for offset in &superseded {
+            if let Some(meta) = self.index.remove(offset) {
+                self.bytes -= meta.size;
+            }
+            batch.delete(self.key(*offset).as_bytes());
+        }
+        self.db.write(batch).unwrap();
+        self.config.min_next_offset = self.next_offset;
+        self.persist_config();
+        superseded.len()
+    }
+
+    // Names of the streams recorded in the database
+    pub fn list(db: &DB) -> Vec<String> {
+        let mut names = Vec::new();
+        let iter = db.iterator(rocksdb::IteratorMode::From(STREAM_PREFIX.as_bytes(), rocksdb::Direction::Forward));
+        for item in iter {
+            let (key, _) = item.unwrap();
+            match key.strip_prefix(STREAM_PREFIX.as_bytes()) {
+                Some(name) => names.push(String::from_utf8_lossy(name).into_owned()),
+                None => break,
+            }
+        }
+        names
+    }
+
+    fn persist_config(&self) {
+        let value = serde_json::to_vec(&self.config).unwrap();
+        self.db.put(Stream::config_key(&self.name).as_bytes(), value).unwrap();
+    }
+
+    fn key(&self, offset: u64) -> String {
+        format!("{}{:020}", Stream::prefix(&self.name), offset)
+    }
+
+    fn prefix(name: &str) -> String {
+        format!("{}@stream:", name)
+    }
+
+    fn config_key(name: &str) -> String {
+        format!("{}{}", STREAM_PREFIX, name)
+    }
+
+    fn parse_offset(prefix: &str, key: &[u8]) -> Option<u64> {
+        let rest = key.strip_prefix(prefix.as_bytes())?;
+        if rest.len() != 20 || !rest.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        std::str::from_utf8(rest).ok()?.parse().ok()
+    }
+}
+
+// Where a tap stream gets its copies from
+enum TapSource {
+    Queue(String),
+    Exchange(String, Binding),
+}
+
+// An open feed on a tap stream. The tap stops receiving copies once the last
+// feed on it is dropped.
+pub struct Tap {
+    stream: String,
+    source: TapSource,
+    rapidmq: RapidMQ,
+}
+
+impl Tap {
+    // The stream to read the copies from
+    pub fn stream(&self) -> &str {
+        &self.stream
+    }
+}
+
+impl Drop for Tap {
+    fn drop(&mut self) {
+        self.rapidmq.close_tap(self);
+    }
+}
+
+// RapidMQ struct to manage the overall messaging system
+#[derive(Clone)]
+pub struct RapidMQ {
+    queues: Arc<Mutex<HashMap<String, Queue>>>,
+    streams: Arc<Mutex<HashMap<String, Stream>>>,
+    // Group members served by this node; membership itself is cluster state
+    consumer_groups: Arc<Mutex<ConsumerGroupManager>>,
+    dedup: Arc<Mutex<DedupIndex>>,
+    transactions: Arc<Mutex<TransactionManager>>,
+    // Per replicated queue led here: the followers brought up to date since this
+    // node took over. Locked while a round of changes is shipped, so rounds
+    // reach followers in order.
+    replication: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<HashSet<NodeId>>>>>>,
+    // Open feeds per tap stream
+    tap_feeds: Arc<Mutex<HashMap<String, usize>>>,
+    db: Arc<DB>,
+    cluster_manager: Arc<ClusterManager>,
+}
+
+impl RapidMQ {
+    pub fn new(node_id: NodeId, peers: Vec<NodeId>) -> Self {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        let db = Arc::new(DB::open(&opts, format!("rapidmq_storage_{}", node_id)).unwrap());
+
+        metrics::register_metrics();
+
+        let queues: Arc<Mutex<HashMap<String, Queue>>> = Arc::new(Mutex::new(HashMap::new()));
+        let replication: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<HashSet<NodeId>>>>>> = Arc::new(Mutex::new(HashMap::new()));
+        let streams: Arc<Mutex<HashMap<String, Stream>>> = Arc::new(Mutex::new(
+            Stream::list(&db)
+                .into_iter()
+                .map(|name| (name.clone(), Stream::open(&name, db.clone())))
+                .collect(),
+        ));
+        // Queues placed on this node by any member are opened once the placement applies
+        let on_apply = {
+            let queues = queues.clone();
+            let streams = streams.clone();
+            let replication = replication.clone();
+            let db = db.clone();
+            Box::new(move |command: &ClusterCommand| match command {
+                ClusterCommand::CreateQueue { queue_name, node_id: owner } if *owner == node_id => {
+                    queues.lock().unwrap().entry(queue_name.clone()).or_insert_with(|| Queue::new(queue_name, db.clone()));
+                }
+                ClusterCommand::CreateStream { stream_name, node_id: owner, retention } if *owner == node_id => {
+                    streams.lock().unwrap().entry(stream_name.clone())
+                        .or_insert_with(|| Stream::open(stream_name, db.clone()))
+                        .set_retention(retention.clone());
+                }
+                ClusterCommand::CreateReplicatedQueue { queue_name, replica_set } if replica_set.replicas.contains(&node_id) => {
+                    let mut queues = queues.lock().unwrap();
+                    let queue = queues.entry(queue_name.clone()).or_insert_with(|| Queue::new(queue_name, db.clone()));
+                    queue.set_replicated(replica_set.leader() == node_id);
+                }
+                ClusterCommand::PromoteReplica { queue_name, node_id: leader } => {
+                    if let Some(queue) = queues.lock().unwrap().get_mut(queue_name) {
+                        // Failovers can be proposed more than once; only act on a real change
+                        if queue.is_replicated() != (*leader == node_id) {
+                            // A new leader starts over bringing every follower up to date
+                            replication.lock().unwrap().remove(queue_name);
+                            queue.set_replicated(*leader == node_id);
+                        }
+                    }
+                }
+                _ => {}
+            })
+        };
+        let cluster_manager = Arc::new(ClusterManager::new(node_id, peers, db.clone(), on_apply));
+        // Subscriptions and offsets live in the cluster state now. Older nodes
+        // kept their own copies, which are handed over once and then dropped.
+        let subscriptions = RapidMQ::load_subscriptions(&db);
+        let legacy_keys: Vec<String> = subscriptions.keys().map(|name| format!("{}{}", SUBSCRIPTION_PREFIX, name)).collect();
+        match cluster_manager.restore_subscriptions(subscriptions) {
+            Ok(()) => RapidMQ::delete_keys(&db, &legacy_keys),
+            Err(e) => eprintln!("Failed to restore subscriptions: {}", e),
+        }
+        let offsets = RapidMQ::load_offsets(&db);
+        let legacy_keys: Vec<String> = offsets.keys().map(|name| format!("{}{}", OFFSETS_PREFIX, name)).collect();
+        match cluster_manager.restore_offsets(offsets) {
+            Ok(()) => RapidMQ::delete_keys(&db, &legacy_keys),
+            Err(e) => eprintln!("Failed to restore committed offsets: {}", e),
+        }
+        let exchanges = ExchangeRegistry::load_legacy(&db);
+        let legacy_keys: Vec<String> = exchanges.iter().map(|exchange| ExchangeRegistry::legacy_key(&exchange.name)).collect();
+        match cluster_manager.restore_exchanges(exchanges) {
+            Ok(()) => RapidMQ::delete_keys(&db, &legacy_keys),
+            Err(e) => eprintln!("Failed to restore exchanges: {}", e),
+        }
+
+        let dedup = DedupIndex::load(db.clone());
+        let transactions = TransactionManager::load(db.clone());
+        // Placements applied before a restart are part of the saved cluster
+        // state and do not pass through the hook again
+        {
+            let state = cluster_manager.get_state();
+            let streams = streams.lock().unwrap();
+            let mut queues = queues.lock().unwrap();
+            for (queue_name, &owner) in &state.queue_assignments {
+                if owner == node_id && !streams.contains_key(queue_name) {
+                    queues.entry(queue_name.clone()).or_insert_with(|| Queue::new(queue_name, db.clone()));
+                }
+            }
+            for (queue_name, replica_set) in &state.replica_sets {
+                if replica_set.replicas.contains(&node_id) {
+                    let queue = queues.entry(queue_name.clone()).or_insert_with(|| Queue::new(queue_name, db.clone()));
+                    queue.set_replicated(replica_set.leader() == node_id);
+                }
+            }
+        }
+
+        RapidMQ {
+            queues,
+            streams,
+            consumer_groups: Arc::new(Mutex::new(ConsumerGroupManager::default())),
+            dedup: Arc::new(Mutex::new(dedup)),
+            transactions: Arc::new(Mutex::new(transactions)),
+            replication,
+            tap_feeds: Arc::new(Mutex::new(HashMap::new())),
+            db,
+            cluster_manager,
+        }
+    }
+
+    // Places the queue through the cluster log; the chosen node opens it when
+    // it applies the placement
+    pub fn create_queue(&self, queue_name: &str) {
+        match self.cluster_manager.place_queue(queue_name) {
+            Ok(_) => metrics::QUEUE_COUNT.inc(),
+            Err(e) => eprintln!("Failed to create queue '{}': {}", queue_name, e),
+        }
+    }
+
+    // Creates a queue on a chosen node instead of letting the cluster place it.
+    // The placement goes through the cluster log, and the chosen node opens the
+    // queue when it applies it.
+    pub fn create_queue_on(&self, queue_name: &str, node_id: NodeId) {
+        self.cluster_manager.create_queue(queue_name, node_id);
+    }
+
+    // Creates a queue with a copy on `replication_factor` nodes. Publishes and
+    // acks succeed once `write_quorum` copies, counting the leader's, have them.
+    // Returns the chosen nodes, leader first; they open their copies once the
+    // placement applies.
+    pub fn create_replicated_queue(&self, queue_name: &str, replication_factor: usize, write_quorum: usize) -> Result<Vec<NodeId>, String> {
+        let replicas = self.cluster_manager.create_replicated_queue(queue_name, replication_factor, write_quorum)?;
+        metrics::QUEUE_COUNT.inc();
+        Ok(replicas)
+    }
+
+    // The nodes holding a replicated queue, as far as this node has applied the cluster log
+    pub fn replica_set(&self, queue_name: &str) -> Option<ReplicaSet> {
+        self.cluster_manager.replica_set(queue_name)
+    }
+
+    // The node serving `queue_name`, as far as this node has applied the cluster log
+    pub fn queue_node(&self, queue_name: &str) -> Option<NodeId> {
+        self.cluster_manager.get_queue_node(queue_name)
+    }
+
+    // The node currently leading the cluster metadata group, if known
+    pub fn cluster_leader(&self) -> Option<NodeId> {
+        self.cluster_manager.leader()
+    }
+
+    // Publishes a message to a queue and its subscribers. A retry of a message
+    // the queue already took inside the dedup window is reported as a duplicate
+    // and not stored again.
+    pub async fn publish(&self, queue_name: &str, mut message: Message) -> PublishOutcome {
+        if message.timestamp.is_none() {
+            message.timestamp = Some(scheduler::now_millis());
+        }
+
+        // Subscriptions are replicated cluster state, so whichever node takes the
+        // publish fans it out to the same set of queues. Each subscriber dedups
+        // on its own, so a retry completes a fan-out that was cut short.
+        for subscriber in self.cluster_manager.subscribers_of(queue_name) {
+            let target = self.resolve_partition(&subscriber, &message);
+            self.deliver(&target, message.clone()).await;
+        }
+        let target = self.resolve_partition(queue_name, &message);
+        let outcome = self.deliver(&target, message).await;
+
+        if outcome == PublishOutcome::Published {
+            metrics::MESSAGES_PUBLISHED.inc();
+            metrics::TOTAL_MESSAGES.inc();
+        }
+        outcome
+    }
+
+    // How long published message ids are remembered for deduplication
+    pub fn set_dedup_window(&self, window: Duration) {
+        self.dedup.lock().unwrap().set_window(window);
+    }
+
+    pub fn dedup_window(&self) -> Duration {
+        self.dedup.lock().unwrap().window()
+    }
+
+    // Forgets message ids that left the dedup window. Returns how many were removed.
+    pub fn sweep_dedup(&self) -> usize {
+        self.dedup.lock().unwrap().sweep(scheduler::now_millis())
+    }
+
+    // Starts a transaction. Publishes and acks added to it take effect together
+    // on commit, or not at all.
+    pub fn begin_transaction(&self) -> String {
+        self.transactions.lock().unwrap().begin()
+    }
+
+    // Buffers a publish. Subscribers and partitions are resolved here, so the
+    // commit writes to exactly the queues a plain publish would.
+    pub fn transactional_publish(&self, tx_id: &str, queue_name: &str, mut message: Message) -> Result<(), String> {
+        if message.timestamp.is_none() {
+            message.timestamp = Some(scheduler::now_millis());
+        }
+        let mut targets: Vec<String> = self.cluster_manager.subscribers_of(queue_name)
+            .iter()
+            .map(|subscriber| self.resolve_partition(subscriber, &message))
+            .collect();
+        targets.push(self.resolve_partition(queue_name, &message));
+        let mut transactions = self.transactions.lock().unwrap();
+        for target in targets {
+            transactions.add(tx_id, TxOp::Publish { queue_name: target, message: message.clone() })?;
+        }
+        Ok(())
+    }
+
+    // Buffers the ack of a consumed message, e.g. the input of a consume-transform-produce step
+    pub fn transactional_ack(&self, tx_id: &str, queue_name: &str, delivery_tag: &str) -> Result<(), String> {
+        self.transactions.lock().unwrap().add(tx_id, TxOp::Ack {
+            queue_name: queue_name.to_string(),
+            delivery_tag: delivery_tag.to_string(),
+        })
+    }
+
+    // Applies a transaction. When every queue involved lives on this node the
+    // whole transaction is one RocksDB write batch. Otherwise each node holding
+    // one of the queues first prepares its share, and the transaction is only
+    // committed once all of them have voted yes.
+    pub async fn commit_transaction(&self, tx_id: &str) -> Result<(), String> {
+        let ops = self.transactions.lock().unwrap().take(tx_id)
+            .ok_or_else(|| format!("Transaction '{}' not found", tx_id))?;
+        let local_id = self.cluster_manager.node_id();
+        let mut by_node: HashMap<NodeId, Vec<TxOp>> = HashMap::new();
+        for op in ops {
+            let node_id = self.cluster_manager.get_queue_node(op.queue_name())
+                .ok_or_else(|| format!("Queue '{}' not found", op.queue_name()))?;
+            by_node.entry(node_id).or_default().push(op);
+        }
+        if by_node.keys().all(|&node_id| node_id == local_id) {
+            let ops = by_node.remove(&local_id).unwrap_or_default();
+            return self.apply_transaction(ops, WriteBatch::default(), true).await;
+        }
+
+        // Phase one: stop at the first participant that votes no
+        let mut prepared = Vec::new();
+        let mut failure = None;
+        for (node_id, ops) in by_node {
+            let vote = if node_id == local_id {
+                self.prepare_transaction(tx_id, ops)
+            } else {
+                self.cluster_manager.prepare_remote(node_id, tx_id, ops).await.map_err(|e| e.to_string())
+            };
+            match vote {
+                Ok(()) => prepared.push(node_id),
+                Err(e) => {
+                    failure = Some(e);
+                    break;
+                }
+            }
+        }
+
+        // Phase two: tell every prepared participant the outcome. A commit
+        // that some participant could not get confirmed by its replicas is
+        // reported as failed, though it stays applied where it was written.
+        let commit = failure.is_none();
+        for node_id in prepared {
+            if node_id == local_id {
+                if !commit {
+                    self.abort_prepared(tx_id);
+                } else if let Err(e) = self.commit_prepared(tx_id).await {
+                    failure.get_or_insert(e);
+                }
+                continue;
+            }
+            let result = if commit {
+                self.cluster_manager.commit_prepared_remote(node_id, tx_id).await
+            } else {
+                self.cluster_manager.abort_prepared_remote(node_id, tx_id).await
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to deliver decision for transaction '{}' to node {}: {}", tx_id, node_id, e);
+                if commit {
+                    failure.get_or_insert(format!("Transaction '{}' not confirmed by node {}: {}", tx_id, node_id, e));
+                }
+            }
+        }
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // Drops an open transaction. Messages it was going to ack become visible again.
+    pub async fn abort_transaction(&self, tx_id: &str) -> bool {
+        let Some(ops) = self.transactions.lock().unwrap().take(tx_id) else {
+            return false;
+        };
+        for op in ops {
+            if let TxOp::Ack { queue_name, delivery_tag } = op {
+                self.nack(&queue_name, &delivery_tag).await;
+            }
+        }
+        true
+    }
+
+    // First phase on a participant: checks that every operation can be applied
+    // here and persists them until the coordinator decides
+    pub fn prepare_transaction(&self, tx_id: &str, ops: Vec<TxOp>) -> Result<(), String> {
+        {
+            let queues = self.queues.lock().unwrap();
+            for op in &ops {
+                let queue = queues.get(op.queue_name())
+                    .ok_or_else(|| format!("Queue '{}' not found on this node", op.queue_name()))?;
+                if let TxOp::Ack { delivery_tag, .. } = op {
+                    if !queue.is_in_flight(delivery_tag) {
+                        return Err(format!("Unknown or expired delivery tag '{}'", delivery_tag));
+                    }
+                }
+            }
+        }
+        self.transactions.lock().unwrap().prepare(tx_id, ops);
+        Ok(())
+    }
+
+    // Second phase on a participant. A yes vote cannot be taken back, so acks
+    // whose delivery expired since the prepare are skipped rather than failing.
+    // Returns false for an unknown transaction, and fails if a replicated
+    // queue missed its write quorum.
+    pub async fn commit_prepared(&self, tx_id: &str) -> Result<bool, String> {
+        let Some(ops) = self.transactions.lock().unwrap().take_prepared(tx_id) else {
+            return Ok(false);
+        };
+        let mut batch = WriteBatch::default();
+        batch.delete(TransactionManager::prepared_key(tx_id).as_bytes());
+        self.apply_transaction(ops, batch, false).await?;
+        Ok(true)
+    }
+
+    pub fn abort_prepared(&self, tx_id: &str) -> bool {
+        let Some(ops) = self.transactions.lock().unwrap().take_prepared(tx_id) else {
+            return false;
+        };
+        self.db.delete(TransactionManager::prepared_key(tx_id).as_bytes()).unwrap();
+        let mut queues = self.queues.lock().unwrap();
+        for op in ops {
+            if let TxOp::Ack { queue_name, delivery_tag } = op {
+                if let Some(queue) = queues.get_mut(&queue_name) {
+                    queue.nack(&delivery_tag);
+                }
+            }
+        }
+        true
+    }
+
+    // Prepared transactions on this node still waiting for their coordinator
+    pub fn in_doubt_transactions(&self) -> Vec<String> {
+        self.transactions.lock().unwrap().in_doubt()
+    }
+
+    // Writes all operations, plus whatever `batch` already holds, in one batch.
+    // With `strict`, a missing queue or delivery tag fails the whole transaction
+    // before anything is written; otherwise that operation is skipped. The
+    // batch is then shipped to the followers of every replicated queue it
+    // touched, and the transaction fails if one of them misses its write quorum.
+    async fn apply_transaction(&self, ops: Vec<TxOp>, batch: WriteBatch, strict: bool) -> Result<(), String> {
+        let touched = self.write_transaction(ops, batch, strict)?;
+        for queue_name in touched {
+            if !self.replicate(&queue_name).await {
+                return Err(format!("Transaction stored, but not confirmed by a write quorum of '{}'", queue_name));
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the queues the write changed
+    fn write_transaction(&self, ops: Vec<TxOp>, mut batch: WriteBatch, strict: bool) -> Result<Vec<String>, String> {
+        let mut queues = self.queues.lock().unwrap();
+        let mut staged = Vec::new();
+        for op in ops {
+            let Some(queue) = queues.get_mut(op.queue_name()) else {
+                if strict {
+                    return Err(format!("Queue '{}' not found on this node", op.queue_name()));
+                }
+                continue;
+            };
+            match op {
+                TxOp::Publish { queue_name, message } => {
+                    staged.push((queue_name, queue.stage_enqueue(&mut batch, message)));
+                }
+                TxOp::Ack { queue_name, delivery_tag } => match queue.stage_ack(&mut batch, &delivery_tag) {
+                    Some(write) => staged.push((queue_name, write)),
+                    None if strict => return Err(format!("Unknown or expired delivery tag '{}'", delivery_tag)),
+                    None => {}
+                },
+            }
+        }
+        self.db.write(batch).unwrap();
+        let mut touched = Vec::new();
+        for (queue_name, write) in staged {
+            if let Some(queue) = queues.get_mut(&queue_name) {
+                queue.apply(write);
+            }
+            if !touched.contains(&queue_name) {
+                touched.push(queue_name);
+            }
+        }
+        Ok(touched)
+    }
+
+    // Creates a stream: publishes to it are appended and kept for replay instead
+    // of being removed by consumers. The stream is placed through the cluster and
+    // its owner opens it once the placement applies; an existing local stream
+    // takes the new retention.
+    pub fn create_stream(&self, stream_name: &str, retention: RetentionPolicy) -> Result<NodeId, String> {
+        if let Some(node_id) = self.cluster_manager.get_queue_node(stream_name) {
+            if node_id == self.cluster_manager.node_id() && !self.set_stream_retention(stream_name, retention) {
+                return Err(format!("'{}' is a queue, not a stream", stream_name));
+            }
+            return Ok(node_id);
+        }
+        self.cluster_manager.place_stream(stream_name, retention)
+    }
+
+    pub fn set_stream_retention(&self, stream_name: &str, retention: RetentionPolicy) -> bool {
+        let mut streams = self.streams.lock().unwrap();
+        match streams.get_mut(stream_name) {
+            Some(stream) => {
+                stream.set_retention(retention);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Reads up to `max` records from a local stream. None if the stream is not on this node.
+    pub fn read_stream(&self, stream_name: &str, start: StartOffset, max: usize) -> Option<Vec<StreamRecord>> {
+        let streams = self.streams.lock().unwrap();
+        streams.get(stream_name).map(|stream| stream.read(stream.resolve(start), max))
+    }
+
+    // Like `read_stream`, but when nothing is there yet waits up to `wait` for
+    // records to be appended. `Latest` is resolved once, before waiting.
+    pub async fn read_stream_wait(&self, stream_name: &str, start: StartOffset, max: usize, wait: Duration) -> Option<Vec<StreamRecord>> {
+        let from = self.seek_stream(stream_name, start)?.0;
+        let deadline = Instant::now() + wait;
+        loop {
+            let appended = self.streams.lock().unwrap().get(stream_name).map(Stream::appended_signal)?;
+            let notified = appended.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let records = self.read_stream(stream_name, StartOffset::Offset(from), max)?;
+            if !records.is_empty() || Instant::now() >= deadline {
+                return Some(records);
+            }
+            let _ = tokio::time::timeout_at(deadline.into(), notified).await;
+        }
+    }
+
+    // Opens a feed on a stream receiving a copy of everything published to
+    // `queue_name`, for watching a queue without consuming from it. The tap
+    // lives next to the queue, so only the node serving the queue can open one.
+    pub fn tap_queue(&self, queue_name: &str) -> Result<Tap, String> {
+        match self.cluster_manager.get_queue_node(queue_name) {
+            None => return Err(format!("Queue '{}' not found", queue_name)),
+            Some(node_id) if node_id != self.cluster_manager.node_id() => {
+                return Err(format!("Queue '{}' is served by node {}", queue_name, node_id));
+            }
+            Some(_) => {}
+        }
+        let tap = format!("{}{}", queue_name, TAP_SUFFIX);
+        let source = TapSource::Queue(queue_name.to_string());
+        self.open_tap(tap, source)
+    }
+
+    // Opens a feed on a stream receiving a copy of every message the exchange
+    // routes with `pattern` as the binding key
+    pub fn tap_exchange(&self, exchange: &str, pattern: &str) -> Result<Tap, String> {
+        if self.cluster_manager.exchange(exchange).is_none() {
+            return Err(format!("Exchange '{}' not found", exchange));
+        }
+        let tap = format!("{}{}/{}", exchange, TAP_SUFFIX, pattern);
+        let source = TapSource::Exchange(exchange.to_string(), Binding::new(&tap, pattern));
+        self.open_tap(tap, source)
+    }
+
+    // Creates the tap stream on this node and connects it to its source when
+    // the first feed opens
+    fn open_tap(&self, tap: String, source: TapSource) -> Result<Tap, String> {
+        let mut feeds = self.tap_feeds.lock().unwrap();
+        let count = feeds.entry(tap.clone()).or_insert(0);
+        if *count == 0 {
+            self.streams.lock().unwrap().entry(tap.clone())
+                .or_insert_with(|| Stream::open(&tap, self.db.clone()))
+                .set_retention(RetentionPolicy {
+                    max_bytes: None,
+                    max_age_secs: Some(TAP_RETENTION_SECS),
+                });
+            let node_id = self.cluster_manager.node_id();
+            if self.cluster_manager.get_queue_node(&tap) != Some(node_id) {
+                self.cluster_manager.assign_queue_to(&tap, node_id);
+            }
+            let connected = match &source {
+                TapSource::Queue(queue_name) => self.subscribe(queue_name, &tap).map(|_| ()),
+                TapSource::Exchange(exchange, binding) => self.bind_queue(exchange, binding.clone()).map(|_| ()),
+            };
+            if let Err(e) = connected {
+                feeds.remove(&tap);
+                return Err(e);
+            }
+        }
+        *feeds.get_mut(&tap).unwrap() += 1;
+        Ok(Tap { stream: tap, source, rapidmq: self.clone() })
+    }
+
+    // Disconnects a tap from its source once its last feed is gone. The stream
+    // itself is left to expire through its retention.
+    fn close_tap(&self, tap: &Tap) {
+        let mut feeds = self.tap_feeds.lock().unwrap();
+        let Some(count) = feeds.get_mut(&tap.stream) else {
+            return;
+        };
+        *count -= 1;
+        if *count > 0 {
+            return;
+        }
+        feeds.remove(&tap.stream);
+        match &tap.source {
+            TapSource::Queue(queue_name) => {
+                if let Err(e) = self.unsubscribe(queue_name, &tap.stream) {
+                    eprintln!("Failed to unsubscribe tap '{}': {}", tap.stream, e);
+                }
+            }
+            TapSource::Exchange(exchange, binding) => {
+                if let Err(e) = self.unbind_queue(exchange, binding) {
+                    eprintln!("Failed to unbind tap '{}': {}", tap.stream, e);
+                }
+            }
+        }
+    }
+
+    // Resolves a start position to an offset. Returns it with the stream's first
+    // retained offset and head.
+    pub fn seek_stream(&self, stream_name: &str, start: StartOffset) -> Option<(u64, u64, u64)> {
+        let streams = self.streams.lock().unwrap();
+        streams.get(stream_name).map(|stream| (stream.resolve(start), stream.first_offset(), stream.next_offset()))
+    }
+
+    // Records that `consumer` has processed everything before `offset`. The offset
+    // is replicated through the cluster state so any node can resume the consumer.
+    pub fn commit_offset(&self, stream_name: &str, consumer: &str, offset: u64) -> Result<(), String> {
+        if let Some(stream) = self.streams.lock().unwrap().get(stream_name) {
+            if offset > stream.next_offset() {
+                return Err(format!("Offset {} is past the head of stream '{}' ({})", offset, stream_name, stream.next_offset()));
+            }
+        }
+        self.cluster_manager.commit_offset(stream_name, consumer, offset)
+    }
+
+    pub fn committed_offset(&self, stream_name: &str, consumer: &str) -> Option<u64> {
+        self.cluster_manager.committed_offset(stream_name, consumer)
+    }
+
+    // Committed offset and lag of one consumer. None if the stream is not on this node.
+    pub fn consumer_offset(&self, stream_name: &str, consumer: &str) -> Option<ConsumerOffset> {
+        let streams = self.streams.lock().unwrap();
+        let stream = streams.get(stream_name)?;
+        Some(RapidMQ::describe_offset(stream, consumer, self.committed_offset(stream_name, consumer)))
+    }
+
+    // Every consumer that committed on a local stream, sorted by name
+    pub fn consumer_offsets(&self, stream_name: &str) -> Option<Vec<ConsumerOffset>> {
+        let streams = self.streams.lock().unwrap();
+        let stream = streams.get(stream_name)?;
+        let mut offsets: Vec<ConsumerOffset> = self.cluster_manager.committed_offsets_of(stream_name)
+            .into_iter()
+            .map(|(consumer, offset)| RapidMQ::describe_offset(stream, &consumer, Some(offset)))
+            .collect();
+        offsets.sort_by(|a, b| a.consumer.cmp(&b.consumer));
+        Some(offsets)
+    }
+
+    // A consumer that never committed is behind by everything still retained
+    fn describe_offset(stream: &Stream, consumer: &str, offset: Option<u64>) -> ConsumerOffset {
+        let from = offset.unwrap_or(0).max(stream.first_offset());
+        ConsumerOffset {
+            consumer: consumer.to_string(),
+            offset,
+            next_offset: stream.next_offset(),
+            lag: stream.next_offset().saturating_sub(from),
+        }
+    }
+
+    fn load_offsets(db: &DB) -> HashMap<String, HashMap<String, u64>> {
+        let mut offsets = HashMap::new();
+        let iter = db.iterator(rocksdb::IteratorMode::From(OFFSETS_PREFIX.as_bytes(), rocksdb::Direction::Forward));
+        for item in iter {
+            let (key, value) = item.unwrap();
+            let stream_name = match key.strip_prefix(OFFSETS_PREFIX.as_bytes()) {
+                Some(name) => String::from_utf8_lossy(name).into_owned(),
+                None => break,
+            };
+            if let Ok(consumers) = serde_json::from_slice::<HashMap<String, u64>>(&value) {
+                offsets.insert(stream_name, consumers);
+            }
+        }
+        offsets
+    }
+
+    pub fn set_stream_compaction(&self, stream_name: &str, compaction: Option<CompactionPolicy>) -> bool {
+        let mut streams = self.streams.lock().unwrap();
+        match streams.get_mut(stream_name) {
+            Some(stream) => {
+                stream.set_compaction(compaction);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Compacts every local stream that has a compaction policy. Returns how many records were removed.
+    pub fn compact_streams(&self) -> usize {
+        let now = scheduler::now_millis();
+        let mut streams = self.streams.lock().unwrap();
+        streams.values_mut().map(|stream| stream.compact(now)).sum()
+    }
+
+    // Applies every local stream's retention policy. Returns how many records were removed.
+    pub fn enforce_stream_retention(&self) -> usize {
+        let now = scheduler::now_millis();
+        let mut streams = self.streams.lock().unwrap();
+        streams.values_mut().map(|stream| stream.enforce_retention(now)).sum()
+    }
+
+    // Creates a queue split into `partitions` ordinary queues that consumer groups
+    // divide between their members. Each partition is placed like any other queue.
+    pub fn create_partitioned_queue(&self, queue_name: &str, partitions: u32) {
+        let partitions = partitions.max(1);
+        self.cluster_manager.set_partitions(queue_name, partitions);
+        for partition in 0..partitions {
+            self.create_queue(&consumer_group::partition_queue_name(queue_name, partition));
+        }
+    }
+
+    pub fn partition_count(&self, queue_name: &str) -> Option<u32> {
+        self.cluster_manager.get_partitions(queue_name)
+    }
+
+    // Maps a publish to a partitioned queue onto one of its partitions
+    fn resolve_partition(&self, queue_name: &str, message: &Message) -> String {
+        match self.cluster_manager.get_partitions(queue_name) {
+            Some(partitions) => {
+                let key = message.headers.get(consumer_group::PARTITION_KEY_HEADER).unwrap_or(&message.id);
+                consumer_group::partition_queue_name(queue_name, consumer_group::partition_for(key, partitions))
+            }
+            None => queue_name.to_string(),
+        }
+    }
+
+    // Adds a member (a fresh id when none is given) and waits for the cluster to
+    // rebalance the group. A group is tied to the queue it was first joined with.
+    pub async fn join_group(&self, group: &str, queue_name: &str, member_id: Option<String>) -> Result<Assignment, String> {
+        let partitions = self.partition_count(queue_name)
+            .ok_or_else(|| format!("Queue '{}' is not partitioned", queue_name))?;
+        if let Some(state) = self.cluster_manager.consumer_group(group) {
+            if state.queue_name != queue_name {
+                return Err(format!("Group '{}' already consumes queue '{}'", group, state.queue_name));
+            }
+        }
+        let member_id = member_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        self.cluster_manager.join_group(group, queue_name, partitions, &member_id)?;
+        self.consumer_groups.lock().unwrap().heartbeat(group, &member_id);
+
+        // Followers see the new assignment once the join has gone through the log
+        let deadline = Instant::now() + GROUP_JOIN_WAIT;
+        loop {
+            if let Some(state) = self.cluster_manager.consumer_group(group) {
+                if let Some(assignment) = state.assignment(&member_id) {
+                    return Ok(assignment);
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(format!("Joining group '{}' was not applied in time; retry", group));
+            }
+            tokio::time::sleep(GROUP_JOIN_POLL_INTERVAL).await;
+        }
+    }
+
+    // Keeps a member alive and tells it its current partitions. An error means the
+    // member was dropped and has to join again.
+    pub fn heartbeat(&self, group: &str, member_id: &str) -> Result<Assignment, String> {
+        let (_, assignment) = self.group_member(group, member_id)?;
+        if self.consumer_groups.lock().unwrap().heartbeat(group, member_id) {
+            self.cluster_manager.group_heartbeat(group, member_id)?;
+        }
+        Ok(assignment)
+    }
+
+    // Fails if the cluster did not take the proposal, e.g. while no leader is known
+    pub fn leave_group(&self, group: &str, member_id: &str) -> Result<bool, String> {
+        self.consumer_groups.lock().unwrap().forget(group, member_id);
+        if self.group_member(group, member_id).is_err() {
+            return Ok(false);
+        }
+        self.cluster_manager.leave_group(group, vec![member_id.to_string()])?;
+        Ok(true)
+    }
+
+    pub fn describe_group(&self, group: &str) -> Option<GroupDescription> {
+        self.cluster_manager.consumer_group(group).map(|state| state.describe(group))
+    }
+
+    // The group's queue and the member's assignment, as far as this node has
+    // applied the cluster log
+    fn group_member(&self, group: &str, member_id: &str) -> Result<(String, Assignment), String> {
+        let state = self.cluster_manager.consumer_group(group)
+            .ok_or_else(|| format!("Group '{}' not found", group))?;
+        let assignment = state.assignment(member_id)
+            .ok_or_else(|| format!("Member '{}' is not part of group '{}'", member_id, group))?;
+        Ok((state.queue_name, assignment))
+    }
+
+    // Consumes the next message from one of the partitions assigned to the member.
+    // Consuming counts as a heartbeat.
+    pub async fn consume_group(&self, group: &str, member_id: &str) -> Result<Option<GroupDelivery>, String> {
+        let (queue_name, assignment) = self.group_member(group, member_id)?;
+        let partitions = {
+            let mut consumer_groups = self.consumer_groups.lock().unwrap();
+            if consumer_groups.heartbeat(group, member_id) {
+                self.cluster_manager.group_heartbeat(group, member_id)?;
+            }
+            consumer_groups.next_partitions(group, &queue_name, &assignment)
+        };
+        for (partition, queue_name) in partitions {
+            if let Some(delivery) = self.consume(&queue_name).await {
+                return Ok(Some(GroupDelivery { partition, queue_name, delivery }));
+            }
+        }
+        Ok(None)
+    }
+
+    // Stores a message in one queue, on this node or on the node that owns it.
+    // Unlike `publish`, no fan-out happens here. Deduplication happens on the
+    // owning node, so retries are caught whichever node they enter through.
+    pub async fn deliver(&self, queue_name: &str, message: Message) -> PublishOutcome {
+        let mut retry_until = None;
+        while let Some(node_id) = self.remote_node(queue_name) {
+            // Forward the message to the appropriate node
+            match self.cluster_manager.publish_remote(node_id, queue_name, message.clone()).await {
+                Ok(outcome) => return outcome,
+                Err(e) => eprintln!("Failed to publish message to remote node: {}", e),
+            }
+            if !self.retry_after_failure(queue_name, &mut retry_until).await {
+                return PublishOutcome::Unavailable;
+            }
+        }
+        self.deliver_replicated(queue_name, vec![message]).await.pop().unwrap_or(PublishOutcome::Published)
+    }
+
+    // Publishes several messages with one RocksDB write per target queue. Returns
+    // an outcome per message, in order.
+    pub async fn publish_batch(&self, queue_name: &str, mut messages: Vec<Message>) -> Vec<PublishOutcome> {
+        for message in messages.iter_mut() {
+            if message.timestamp.is_none() {
+                message.timestamp = Some(scheduler::now_millis());
+            }
+        }
+
+        for subscriber in self.cluster_manager.subscribers_of(queue_name) {
+            for (target, group) in self.group_by_partition(&subscriber, &messages) {
+                let copies = group.into_iter().map(|i| messages[i].clone()).collect();
+                self.deliver_batch(&target, copies).await;
+            }
+        }
+
+        let mut outcomes = vec![PublishOutcome::Published; messages.len()];
+        for (target, group) in self.group_by_partition(queue_name, &messages) {
+            let batch = group.iter().map(|&i| messages[i].clone()).collect();
+            let results = self.deliver_batch(&target, batch).await;
+            for (i, outcome) in group.into_iter().zip(results) {
+                outcomes[i] = outcome;
+            }
+        }
+
+        for outcome in &outcomes {
+            if *outcome == PublishOutcome::Published {
+                metrics::MESSAGES_PUBLISHED.inc();
+                metrics::TOTAL_MESSAGES.inc();
+            }
+        }
+        outcomes
+    }
+
+    // Indexes of `messages` per target queue, keeping publish order within each
+    fn group_by_partition(&self, queue_name: &str, messages: &[Message]) -> Vec<(String, Vec<usize>)> {
+        let mut groups: Vec<(String, Vec<usize>)> = Vec::new();
+        for (i, message) in messages.iter().enumerate() {
+            let target = self.resolve_partition(queue_name, message);
+            match groups.iter_mut().find(|(name, _)| *name == target) {
+                Some((_, indexes)) => indexes.push(i),
+                None => groups.push((target, vec![i])),
+            }
+        }
+        groups
+    }
+
+    // Batch counterpart of `deliver`
+    pub async fn deliver_batch(&self, queue_name: &str, messages: Vec<Message>) -> Vec<PublishOutcome> {
+        let mut retry_until = None;
+        while let Some(node_id) = self.remote_node(queue_name) {
+            match self.cluster_manager.publish_batch_remote(node_id, queue_name, messages.clone()).await {
+                Ok(outcomes) => return outcomes,
+                Err(e) => eprintln!("Failed to publish batch to remote node: {}", e),
+            }
+            if !self.retry_after_failure(queue_name, &mut retry_until).await {
+                return vec![PublishOutcome::Unavailable; messages.len()];
+            }
+        }
+        self.deliver_replicated(queue_name, messages).await
+    }
+
+    // The node serving `queue_name` if that is not this one
+    fn remote_node(&self, queue_name: &str) -> Option<NodeId> {
+        self.cluster_manager.get_queue_node(queue_name).filter(|&node_id| node_id != self.cluster_manager.node_id())
+    }
+
+    // Whether to try a request again after the node serving `queue_name` did not
+    // answer. Replicated queues are retried against whichever node serves them
+    // by then, for up to FAILOVER_WAIT after the first failure, so a replica has
+    // time to take over from a leader that went down. Other queues have nowhere
+    // else to go.
+    async fn retry_after_failure(&self, queue_name: &str, retry_until: &mut Option<Instant>) -> bool {
+        let replicated = self.cluster_manager.replica_set(queue_name).map_or(false, |replica_set| replica_set.replicas.len() > 1);
+        if !replicated {
+            return false;
+        }
+        let deadline = *retry_until.get_or_insert_with(|| Instant::now() + FAILOVER_WAIT);
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(FAILOVER_RETRY_INTERVAL).await;
+        true
+    }
+
+    // Stores messages in a local queue and waits for its followers, if it has
+    // any. Messages that miss the write quorum stay stored here but are reported
+    // as unreplicated and forgotten by the dedup index, so a retry goes through.
+    async fn deliver_replicated(&self, queue_name: &str, messages: Vec<Message>) -> Vec<PublishOutcome> {
+        let keys: Vec<Option<String>> = messages.iter().map(|message| dedup::dedup_key(queue_name, message)).collect();
+        let mut outcomes = self.deliver_local(queue_name, messages);
+        if !self.replicate(queue_name).await {
+            let mut dedup = self.dedup.lock().unwrap();
+            for (outcome, key) in outcomes.iter_mut().zip(keys) {
+                if *outcome == PublishOutcome::Published {
+                    if let Some(key) = key {
+                        dedup.forget(&key);
+                    }
+                    *outcome = PublishOutcome::Unreplicated;
+                }
+            }
+        }
+        outcomes
+    }
+
+    // Ships the changes a replicated queue led by this node recorded since the
+    // last round to its followers. Followers this node has not brought up to
+    // date yet get a full copy instead. Returns whether at least the write
+    // quorum of copies, counting this one, have the changes; queues without
+    // followers always do.
+    async fn replicate(&self, queue_name: &str) -> bool {
+        let Some(replica_set) = self.cluster_manager.replica_set(queue_name) else {
+            return true;
+        };
+        if replica_set.leader() != self.cluster_manager.node_id() || replica_set.followers().is_empty() {
+            return true;
+        }
+        let progress = self.replication.lock().unwrap().entry(queue_name.to_string()).or_default().clone();
+        let mut synced = progress.lock_owned().await;
+
+        let (ops, snapshot) = {
+            let mut queues = self.queues.lock().unwrap();
+            let Some(queue) = queues.get_mut(queue_name) else {
+                return false;
+            };
+            let ops = queue.take_journal();
+            let lagging = replica_set.followers().iter().any(|follower| !synced.contains(follower));
+            (ops, lagging.then(|| queue.replica_snapshot()))
+        };
+
+        // The round runs to completion even if the caller stops waiting once the
+        // quorum is reached, and holds the lock until every follower answered
+        let write_quorum = replica_set.write_quorum;
+        let (confirmations, mut confirmed) = tokio::sync::mpsc::unbounded_channel();
+        let cluster_manager = self.cluster_manager.clone();
+        let queue_name = queue_name.to_string();
+        tokio::spawn(async move {
+            let mut sends = tokio::task::JoinSet::new();
+            for &follower in replica_set.followers() {
+                let reset = !synced.contains(&follower);
+                let ops = if reset { snapshot.clone().unwrap_or_default() } else { ops.clone() };
+                if !reset && ops.is_empty() {
+                    let _ = confirmations.send(true);
+                    continue;
+                }
+                let cluster_manager = cluster_manager.clone();
+                let queue_name = queue_name.clone();
+                sends.spawn(async move {
+                    let sent = tokio::time::timeout(REPLICATION_TIMEOUT, cluster_manager.replicate_remote(follower, &queue_name, ops, reset)).await;
+                    let applied = match sent {
+                        Ok(Ok(applied)) => applied,
+                        Ok(Err(e)) => {
+                            eprintln!("Failed to replicate queue '{}' to node {}: {}", queue_name, follower, e);
+                            false
+                        }
+                        Err(_) => {
+                            eprintln!("Timed out replicating queue '{}' to node {}", queue_name, follower);
+                            false
+                        }
+                    };
+                    (follower, applied)
+                });
+            }
+            while let Some(result) = sends.join_next().await {
+                let Ok((follower, applied)) = result else {
+                    continue;
+                };
+                if applied {
+                    synced.insert(follower);
+                } else {
+                    synced.remove(&follower);
+                }
+                if applied != replica_set.is_in_sync(follower) {
+                    cluster_manager.set_in_sync(&queue_name, follower, applied);
+                }
+                let _ = confirmations.send(applied);
+            }
+        });
+
+        let mut copies = 1;
+        while copies < write_quorum {
+            match confirmed.recv().await {
+                Some(true) => copies += 1,
+                Some(false) => {}
+                None => break,
+            }
+        }
+        copies >= write_quorum
+    }
+
+    // Applies changes shipped by `leader` to this node's copy of a replicated
+    // queue. Refused unless this node knows the queue as replicated, with
+    // `leader` still leading it and this node among its replicas, so neither a
+    // leader that was failed over nor an unrelated node can overwrite the copy.
+    // A follower that has not applied the placement yet refuses too; the
+    // leader then sends it a full copy once it has.
+    pub fn apply_replica_ops(&self, queue_name: &str, leader: NodeId, ops: Vec<ReplicaOp>, reset: bool) -> bool {
+        let node_id = self.cluster_manager.node_id();
+        let Some(replica_set) = self.cluster_manager.replica_set(queue_name) else {
+            return false;
+        };
+        if replica_set.leader() != leader || !replica_set.replicas.contains(&node_id) {
+            return false;
+        }
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(queue_name.to_string()).or_insert_with(|| Queue::new(queue_name, self.db.clone()));
+        if reset {
+            queue.reset_replica(ops);
+        } else {
+            for op in ops {
+                queue.apply_replica(op);
+            }
+        }
+        true
+    }
+
+    // Stores messages in a local queue or stream with one write, skipping
+    // duplicates, including repeats within the batch itself. Reports the rest
+    // as unavailable if neither is open on this node.
+    fn deliver_local(&self, queue_name: &str, messages: Vec<Message>) -> Vec<PublishOutcome> {
+        let now = scheduler::now_millis();
+        let mut dedup = self.dedup.lock().unwrap();
+        let mut outcomes = Vec::with_capacity(messages.len());
+        let mut keys: Vec<String> = Vec::new();
+        let mut accepted = Vec::new();
+        for message in messages {
+            if let Some(key) = dedup::dedup_key(queue_name, &message) {
+                if dedup.is_duplicate(&key, now) || keys.contains(&key) {
+                    outcomes.push(PublishOutcome::Duplicate);
+                    continue;
+                }
+                keys.push(key);
+            }
+            outcomes.push(PublishOutcome::Published);
+            accepted.push(message);
+        }
+
+        // Stored before they are recorded, so a crash in between can at worst
+        // let a retry through again, never lose a message
+        if let Some(stream) = self.streams.lock().unwrap().get_mut(queue_name) {
+            stream.append_all(accepted);
+        } else if let Some(queue) = self.queues.lock().unwrap().get_mut(queue_name) {
+            queue.enqueue_all(accepted);
+        } else {
+            // Nothing here to store them in
+            for outcome in outcomes.iter_mut() {
+                if *outcome == PublishOutcome::Published {
+                    *outcome = PublishOutcome::Unavailable;
+                }
+            }
+            return outcomes;
+        }
+        for key in keys {
+            dedup.record(&key, now);
+        }
+        outcomes
+    }
+
+    // Hands out up to `max` messages. If the queue has none, waits up to `wait`
+    // for the first to arrive and returns whatever is visible at that point.
+    pub async fn consume_batch(&self, queue_name: &str, max: usize, wait: Duration) -> Vec<Delivery> {
+        let mut retry_until = None;
+        while let Some(node_id) = self.remote_node(queue_name) {
+            // The owning node does the waiting
+            match self.cluster_manager.consume_batch_remote(node_id, queue_name, max, wait).await {
+                Ok(deliveries) => {
+                    for _ in &deliveries {
+                        metrics::MESSAGES_CONSUMED.inc();
+                        metrics::TOTAL_MESSAGES.dec();
+                    }
+                    return deliveries;
+                }
+                Err(e) => eprintln!("Failed to consume batch from remote node: {}", e),
+            }
+            if !self.retry_after_failure(queue_name, &mut retry_until).await {
+                return Vec::new();
+            }
+        }
+        if self.cluster_manager.get_queue_node(queue_name).is_none() {
+            return Vec::new();
+        }
+
+        self.wait_for_messages(queue_name, wait, || {
+            Some(self.dequeue_local(queue_name, max)).filter(|deliveries| !deliveries.is_empty())
+        }).await.unwrap_or_default()
+    }
+
+    // Calls `attempt` until it returns something or `wait` runs out. In between
+    // it sleeps until the queue signals a new message, or until a delayed message
+    // or visibility timeout is due, so an idle consumer costs nothing.
+    async fn wait_for_messages<T>(&self, queue_name: &str, wait: Duration, mut attempt: impl FnMut() -> Option<T>) -> Option<T> {
+        let deadline = Instant::now() + wait;
+        loop {
+            let ready = self.queues.lock().unwrap().get(queue_name).map(Queue::ready_signal);
+            let Some(ready) = ready else {
+                return attempt();
+            };
+            // Registered before the attempt so a publish in between is not missed
+            let notified = ready.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(result) = attempt() {
+                return Some(result);
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            let next_wakeup = self.queues.lock().unwrap().get(queue_name).and_then(Queue::next_wakeup);
+            let wake_at = next_wakeup.map_or(deadline, |at| at.min(deadline));
+            tokio::select! {
+                _ = &mut notified => {}
+                _ = tokio::time::sleep_until(wake_at.into()) => {}
+            }
+        }
+    }
+
+    fn dequeue_local(&self, queue_name: &str, max: usize) -> Vec<Delivery> {
+        let mut queues = self.queues.lock().unwrap();
+        let mut deliveries = Vec::new();
+        if let Some(queue) = queues.get_mut(queue_name) {
+            while deliveries.len() < max {
+                match queue.dequeue() {
+                    Some(delivery) => deliveries.push(delivery),
+                    None => break,
+                }
+            }
+        }
+        RapidMQ::route_dead_letters(&mut queues, queue_name);
+        for _ in &deliveries {
+            metrics::MESSAGES_CONSUMED.inc();
+            metrics::TOTAL_MESSAGES.dec();
+        }
+        deliveries
+    }
+
+    pub async fn consume(&self, queue_name: &str) -> Option<Delivery> {
+        self.consume_wait(queue_name, Duration::ZERO).await
+    }
+
+    // Like `consume`, but when the queue is empty waits up to `wait` for a
+    // message to become visible
+    pub async fn consume_wait(&self, queue_name: &str, wait: Duration) -> Option<Delivery> {
+        let mut retry_until = None;
+        while let Some(node_id) = self.remote_node(queue_name) {
+            // Forward the consume request to the appropriate node, which does the waiting
+            match self.cluster_manager.consume_remote(node_id, queue_name, wait).await {
+                Ok(message) => {
+                    if message.is_some() {
+                        metrics::MESSAGES_CONSUMED.inc();
+                        metrics::TOTAL_MESSAGES.dec();
+                    }
+                    return message;
+                }
+                Err(e) => eprintln!("Failed to consume message from remote node: {}", e),
+            }
+            if !self.retry_after_failure(queue_name, &mut retry_until).await {
+                return None;
+            }
+        }
+        self.cluster_manager.get_queue_node(queue_name)?;
+        self.wait_for_messages(queue_name, wait, || self.dequeue_local(queue_name, 1).pop()).await
+    }
+
+    pub async fn ack(&self, queue_name: &str, delivery_tag: &str) -> bool {
+        match self.cluster_manager.get_queue_node(queue_name) {
+            Some(node_id) if node_id == self.cluster_manager.node_id() => {
+                let acked = {
+                    let mut queues = self.queues.lock().unwrap();
+                    queues.get_mut(queue_name).map_or(false, |queue| queue.ack(delivery_tag))
+                };
+                // Followers drop the message too, so a new leader does not redeliver it
+                if acked && !self.replicate(queue_name).await {
+                    eprintln!("Ack on queue '{}' missed the write quorum", queue_name);
+                }
+                acked
+            }
+            Some(node_id) => {
+                match self.cluster_manager.ack_remote(node_id, queue_name, delivery_tag).await {
+                    Ok(acked) => acked,
+                    Err(e) => {
+                        eprintln!("Failed to ack message on remote node: {}", e);
+                        false
+                    }
+                }
+            }
+            None => false,
+        }
+    }
+
+    pub async fn nack(&self, queue_name: &str, delivery_tag: &str) -> bool {
+        match self.cluster_manager.get_queue_node(queue_name) {
+            Some(node_id) if node_id == self.cluster_manager.node_id() => {
+                let mut queues = self.queues.lock().unwrap();
+                queues.get_mut(queue_name).map_or(false, |queue| queue.nack(delivery_tag))
+            }
+            Some(node_id) => {
+                match self.cluster_manager.nack_remote(node_id, queue_name, delivery_tag).await {
+                    Ok(requeued) => requeued,
+                    Err(e) => {
+                        eprintln!("Failed to nack message on remote node: {}", e);
+                        false
+                    }
+                }
+            }
+            None => false,
+        }
+    }
+
+    pub async fn reject(&self, queue_name: &str, delivery_tag: &str, reason: &str) -> bool {
+        match self.cluster_manager.get_queue_node(queue_name) {
+            Some(node_id) if node_id == self.cluster_manager.node_id() => {
+                let rejected = {
+                    let mut queues = self.queues.lock().unwrap();
+                    let rejected = queues.get_mut(queue_name).map_or(false, |queue| queue.reject(delivery_tag, reason));
+                    RapidMQ::route_dead_letters(&mut queues, queue_name);
+                    rejected
+                };
+                if rejected && !self.replicate(queue_name).await {
+                    eprintln!("Reject on queue '{}' missed the write quorum", queue_name);
+                }
+                rejected
+            }
+            Some(node_id) => {
+                match self.cluster_manager.reject_remote(node_id, queue_name, delivery_tag, reason).await {
+                    Ok(rejected) => rejected,
+                    Err(e) => {
+                        eprintln!("Failed to reject message on remote node: {}", e);
+                        false
+                    }
+                }
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_visibility_timeout(&self, queue_name: &str, timeout: Duration) {
+        let mut queues = self.queues.lock().unwrap();
+        if let Some(queue) = queues.get_mut(queue_name) {
+            queue.set_visibility_timeout(timeout);
+        }
+    }
+
+    pub fn set_default_ttl(&self, queue_name: &str, ttl: Option<Duration>) -> bool {
+        let mut queues = self.queues.lock().unwrap();
+        match queues.get_mut(queue_name) {
+            Some(queue) => {
+                queue.set_default_ttl(ttl);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_priority_mode(&self, queue_name: &str, mode: PriorityMode) -> bool {
+        let mut queues = self.queues.lock().unwrap();
+        match queues.get_mut(queue_name) {
+            Some(queue) => {
+                queue.set_priority_mode(mode);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Attaches a dead-letter policy to a local queue. The dead-letter queue is
+    // created on the same node so failing messages never leave it.
+    pub fn set_dead_letter_policy(&self, queue_name: &str, policy: DeadLetterPolicy) -> bool {
+        if !self.queues.lock().unwrap().contains_key(queue_name) {
+            return false;
+        }
+        let dlq = policy.dead_letter_queue.clone();
+        // Not under the queues lock: applying cluster commands may need it
+        self.cluster_manager.colocate_queue(&dlq, queue_name);
+        let mut queues = self.queues.lock().unwrap();
+        queues.entry(dlq.clone()).or_insert_with(|| Queue::new(&dlq, self.db.clone()));
+        if let Some(queue) = queues.get_mut(queue_name) {
+            queue.set_dead_letter_policy(Some(policy));
+        }
+        true
+    }
+
+    // Lists visible messages of this node's copy of a queue without delivering them
+    pub fn peek_local(&self, queue_name: &str, limit: usize) -> Vec<Message> {
+        let queues = self.queues.lock().unwrap();
+        queues.get(queue_name).map_or_else(Vec::new, |queue| queue.peek(limit))
+    }
+
+    // Lists messages parked in a dead-letter queue without consuming them
+    pub fn peek_dead_letters(&self, dead_letter_queue: &str, limit: usize) -> Vec<Message> {
+        let queues = self.queues.lock().unwrap();
+        queues.get(dead_letter_queue).map_or_else(Vec::new, |queue| queue.peek(limit))
+    }
+
+    // Moves up to `limit` messages from a dead-letter queue back to the queues they
+    // came from. Returns the number of messages re-driven.
+    pub async fn redrive(&self, dead_letter_queue: &str, limit: usize) -> usize {
+        let mut redriven = 0;
+        while redriven < limit {
+            let delivery = {
+                let mut queues = self.queues.lock().unwrap();
+                match queues.get_mut(dead_letter_queue).and_then(|queue| queue.dequeue()) {
+                    Some(delivery) => delivery,
+                    None => break,
+                }
+            };
+
+            let reason_header = self.reason_header_for(&delivery.message);
+            let mut message = delivery.message;
+            match dead_letter::unmark(&mut message, &reason_header) {
+                Some(source_queue) => {
+                    // The source queue sits on this node next to its dead-letter
+                    // queue; forget the original publish so the dedup window
+                    // does not swallow the re-driven copy
+                    if let Some(key) = dedup::dedup_key(&source_queue, &message) {
+                        self.dedup.lock().unwrap().forget(&key);
+                    }
+                    self.publish(&source_queue, message).await;
+                    self.ack(dead_letter_queue, &delivery.tag).await;
+                    redriven += 1;
+                }
+                None => {
+                    // Not a dead-lettered message; leave it where it is
+                    self.nack(dead_letter_queue, &delivery.tag).await;
+                    break;
+                }
+            }
+        }
+        redriven
+    }
+
+    // The reason header is configurable per source queue, so look it up from the origin
+    fn reason_header_for(&self, message: &Message) -> String {
+        let queues = self.queues.lock().unwrap();
+        message.headers.get(dead_letter::ORIGINAL_QUEUE_HEADER)
+            .and_then(|source| queues.get(source))
+            .and_then(|queue| queue.dead_letter_policy())
+            .map_or_else(|| dead_letter::DEFAULT_REASON_HEADER.to_string(), |policy| policy.reason_header.clone())
+    }
+
+    // Stores messages set aside by a queue in their dead-letter queues, then
+    // deletes them from the source queue
+    fn route_dead_letters(queues: &mut HashMap<String, Queue>, queue_name: &str) {
+        let dead_letters = match queues.get_mut(queue_name) {
+            Some(queue) => queue.take_dead_letters(),
+            None => return,
+        };
+        for dead in dead_letters {
+            match queues.get_mut(&dead.dead_letter_queue) {
+                Some(dlq) => dlq.enqueue(dead.message),
+                None => {
+                    eprintln!("Dead-letter queue '{}' does not exist on this node", dead.dead_letter_queue);
+                    continue;
+                }
+            }
+            if let Some(queue) = queues.get_mut(queue_name) {
+                queue.remove_dead_lettered(dead.seq);
+            }
+            metrics::MESSAGES_DEAD_LETTERED.inc();
+        }
+    }
+
+    // Exchanges and bindings go through the cluster, so these fail like
+    // subscribe does when the proposal is not taken
+    pub fn declare_exchange(&self, name: &str, kind: ExchangeKind) -> Result<(), String> {
+        self.cluster_manager.declare_exchange(name, kind)
+    }
+
+    pub fn delete_exchange(&self, name: &str) -> Result<bool, String> {
+        self.cluster_manager.delete_exchange(name)
+    }
+
+    // Returns whether the binding is new
+    pub fn bind_queue(&self, exchange: &str, binding: Binding) -> Result<bool, String> {
+        self.cluster_manager.bind_queue(exchange, binding)
+    }
+
+    pub fn unbind_queue(&self, exchange: &str, binding: &Binding) -> Result<bool, String> {
+        self.cluster_manager.unbind_queue(exchange, binding)
+    }
+
+    pub fn list_exchanges(&self) -> Vec<Exchange> {
+        self.cluster_manager.list_exchanges()
+    }
+
+    // Publishes a copy of the message to every queue bound to the exchange that
+    // matches the routing key. Returns the number of queues it was routed to.
+    pub async fn publish_to_exchange(&self, exchange: &str, routing_key: &str, message: Message) -> Result<usize, String> {
+        let targets = self.cluster_manager
+            .route(exchange, routing_key, &message.headers)
+            .ok_or_else(|| format!("Exchange '{}' not found", exchange))?;
+        for queue_name in &targets {
+            self.publish(queue_name, message.clone()).await;
+        }
+        Ok(targets.len())
+    }
+
+    // Copies every message published to `queue_name` into `subscriber_queue`.
+    // Subscribing twice is a no-op; returns whether the subscription is new.
+    // Fails if the cluster did not take the proposal, e.g. while no leader is known.
+    pub fn subscribe(&self, queue_name: &str, subscriber_queue: &str) -> Result<bool, String> {
+        self.cluster_manager.subscribe(queue_name, subscriber_queue)
+    }
+
+    pub fn unsubscribe(&self, queue_name: &str, subscriber_queue: &str) -> Result<bool, String> {
+        self.cluster_manager.unsubscribe(queue_name, subscriber_queue)
+    }
+
+    pub fn subscribers_of(&self, queue_name: &str) -> Vec<String> {
+        self.cluster_manager.subscribers_of(queue_name)
+    }
+
+    pub fn subscriptions(&self) -> HashMap<String, Vec<String>> {
+        self.cluster_manager.get_state().subscriptions
+    }
+
+    fn load_subscriptions(db: &DB) -> HashMap<String, Vec<String>> {
+        let mut subscriptions = HashMap::new();
+        let iter = db.iterator(rocksdb::IteratorMode::From(SUBSCRIPTION_PREFIX.as_bytes(), rocksdb::Direction::Forward));
+        for item in iter {
+            let (key, value) = item.unwrap();
+            let queue_name = match key.strip_prefix(SUBSCRIPTION_PREFIX.as_bytes()) {
+                Some(name) => String::from_utf8_lossy(name).into_owned(),
+                None => break,
+            };
+            if let Ok(subscribers) = serde_json::from_slice::<Vec<String>>(&value) {
+                subscriptions.insert(queue_name, subscribers);
+            }
+        }
+        subscriptions
+    }
+
+    fn delete_keys(db: &DB, keys: &[String]) {
+        let mut batch = WriteBatch::default();
+        for key in keys {
+            batch.delete(key.as_bytes());
+        }
+        db.write(batch).unwrap();
+    }
+
+    pub async fn run(&self) {
+        let sweeper = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(EXPIRY_SWEEP_INTERVAL).await;
+                sweeper.sweep_expired();
+                sweeper.enforce_stream_retention();
+                sweeper.sweep_dedup();
+            }
+        });
+        let compactor = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(COMPACTION_INTERVAL).await;
+                compactor.compact_streams();
+            }
+        });
+        self.cluster_manager.run(self.clone()).await;
+    }
+
+    // Serves the node-to-node gRPC API on `addr` over plain HTTP/2, e.g. for
+    // nodes running in one process
+    pub async fn serve_rpc(&self, addr: std::net::SocketAddr) -> Result<(), tonic::transport::Error> {
+        self.cluster_manager.serve(self.clone(), addr).await
+    }
+
+    // Runs this node's raft group over an in-process network instead of gRPC.
+    // Must be called before `serve_rpc` or `run`, which would start it over gRPC.
+    pub fn join_network(&self, network: &Arc<InMemoryNetwork>) {
+        let node_id = self.cluster_manager.node_id();
+        network.register(node_id, self.cluster_manager.clone());
+        ClusterManager::drive(self.cluster_manager.clone(), network.transport(node_id));
+    }
+
+    // Removes expired messages from every local queue. Returns how many were
+    // removed. Copies of replicated queues led elsewhere are left to their
+    // leader, which ships the removals.
+    pub fn sweep_expired(&self) -> usize {
+        let node_id = self.cluster_manager.node_id();
+        let state = self.cluster_manager.get_state();
+        let mut queues = self.queues.lock().unwrap();
+        let names: Vec<String> = queues.keys()
+            .filter(|name| state.replica_sets.get(*name).map_or(true, |replica_set| replica_set.leader() == node_id))
+            .cloned()
+            .collect();
+        let mut swept = 0;
+        for name in names {
+            if let Some(queue) = queues.get_mut(&name) {
+                swept += queue.sweep_expired();
+            }
+            RapidMQ::route_dead_letters(&mut queues, &name);
+        }
+        swept
+    }
+
+    pub fn add_node(&self, node_id: NodeId, address: String) {
+        self.cluster_manager.add_node(node_id, address);
+    }
+
+    pub fn remove_node(&self, node_id: NodeId) {
+        self.cluster_manager.remove_node(node_id);
+    }
+
+    pub async fn adaptive_publish(&self, queue_name: &str, mut message: Message) -> Result<(), Box<dyn std::error::Error>> {
+        let text = String::from_utf8_lossy(&message.payload).into_owned();
+        let score = self.cluster_manager.ai_module.predict_message_priority(&text).await?;
+
+        // Map the model score onto the queue's priority levels, so high priority
+        // messages jump ahead on queues running in a priority mode
+        message.priority = (score.clamp(0.0, 1.0) * priority::MAX_PRIORITY as f32).round() as u8;
+        self.publish(queue_name, message).await;
+
+        Ok(())
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    fn setup() -> (RapidMQ, NodeId) {
+        let node_id = NodeId::from(1);
+        // A single-node cluster commits metadata changes on its own; with peers
+        // that never start, nothing would ever be committed
+        let mq = RapidMQ::new(node_id, vec![]);
+        // The node's database outlives each test, and tests reuse message ids
+        mq.set_dedup_window(Duration::ZERO);
+        (mq, node_id)
+    }
+
+    #[test]
+    fn test_create_queue() {
+        let (mq, _) = setup();
+        mq.create_queue("test_queue");
+        assert!(mq.queues.lock().unwrap().contains_key("test_queue"));
+        assert_eq!(metrics::QUEUE_COUNT.get(), 1);
+    }
+
+    #[test]
+    fn test_publish_consume() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            mq.create_queue("test_queue");
+
+            let message = Message {
+                id: "1".to_string(),
+                payload: b"Test message".to_vec(),
+                ..Default::default()
+            };
+
+            mq.publish("test_queue", message.clone()).await;
+            assert_eq!(metrics::MESSAGES_PUBLISHED.get(), 1);
+            assert_eq!(metrics::TOTAL_MESSAGES.get(), 1);
+
+            let consumed = mq.consume("test_queue").await.unwrap();
+            assert_eq!(consumed.message.id, message.id);
+            assert_eq!(consumed.message.payload, message.payload);
+            assert!(mq.ack("test_queue", &consumed.tag).await);
+            assert_eq!(metrics::MESSAGES_CONSUMED.get(), 1);
+            assert_eq!(metrics::TOTAL_MESSAGES.get(), 0);
+        });
+    }
+
+    #[test]
+    fn test_ack_and_nack() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            mq.create_queue("ack_queue");
+
+            let message = Message {
+                id: "1".to_string(),
+                payload: b"Test message".to_vec(),
+                ..Default::default()
+            };
+            mq.publish("ack_queue", message.clone()).await;
+
+            // A delivered message is hidden from other consumers
+            let first = mq.consume("ack_queue").await.unwrap();
+            assert!(mq.consume("ack_queue").await.is_none());
+
+            // Nack makes it visible again under a new tag
+            assert!(mq.nack("ack_queue", &first.tag).await);
+            let second = mq.consume("ack_queue").await.unwrap();
+            assert_eq!(second.message.id, message.id);
+            assert_ne!(second.tag, first.tag);
+
+            assert!(mq.ack("ack_queue", &second.tag).await);
+            assert!(!mq.ack("ack_queue", &second.tag).await);
+            assert!(mq.consume("ack_queue").await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_visibility_timeout_redelivers() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            mq.create_queue("timeout_queue");
+            mq.set_visibility_timeout("timeout_queue", Duration::from_millis(10));
+
+            let message = Message {
+                id: "1".to_string(),
+                payload: b"Test message".to_vec(),
+                ..Default::default()
+            };
+            mq.publish("timeout_queue", message.clone()).await;
+
+            let first = mq.consume("timeout_queue").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+
+            let redelivered = mq.consume("timeout_queue").await.unwrap();
+            assert_eq!(redelivered.message.id, message.id);
+            // The stale tag no longer acks anything
+            assert!(!mq.ack("timeout_queue", &first.tag).await);
+            assert!(mq.ack("timeout_queue", &redelivered.tag).await);
+        });
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            mq.create_queue("main_queue");
+            mq.create_queue("subscriber_queue");
+
+            assert!(mq.subscribe("main_queue", "subscriber_queue").unwrap());
+            // Subscribing again must not deliver a second copy
+            assert!(!mq.subscribe("main_queue", "subscriber_queue").unwrap());
+
+            let message = Message {
+                id: "1".to_string(),
+                payload: b"Test message".to_vec(),
+                ..Default::default()
+            };
+
+            mq.publish("main_queue", message.clone()).await;
+
+            let consumed_main = mq.consume("main_queue").await.unwrap();
+            let consumed_sub = mq.consume("subscriber_queue").await.unwrap();
+
+            assert_eq!(consumed_main.message.id, message.id);
+            assert_eq!(consumed_sub.message.id, message.id);
+            assert_eq!(metrics::MESSAGES_PUBLISHED.get(), 1);
+            assert_eq!(metrics::MESSAGES_CONSUMED.get(), 2);
+        });
+    }
+
+    #[test]
+    fn test_max_deliveries_moves_to_dead_letter_queue() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            mq.create_queue("work_queue");
+            assert!(mq.set_dead_letter_policy("work_queue", DeadLetterPolicy::new(2, "work_queue.dlq")));
+
+            let message = Message {
+                id: "poison".to_string(),
+                payload: b"Test message".to_vec(),
+                ..Default::default()
+            };
+            mq.publish("work_queue", message.clone()).await;
+
+            for attempt in 1..=2 {
+                let delivery = mq.consume("work_queue").await.unwrap();
+                assert_eq!(delivery.delivery_count, attempt);
+                assert!(mq.nack("work_queue", &delivery.tag).await);
+            }
+            assert!(mq.consume("work_queue").await.is_none());
+
+            let parked = mq.peek_dead_letters("work_queue.dlq", 10);
+            assert_eq!(parked.len(), 1);
+            assert_eq!(parked[0].id, message.id);
+            assert_eq!(parked[0].headers[dead_letter::DEFAULT_REASON_HEADER], "max deliveries exceeded");
+            assert_eq!(parked[0].headers[dead_letter::ORIGINAL_QUEUE_HEADER], "work_queue");
+            assert_eq!(parked[0].headers[dead_letter::DELIVERY_COUNT_HEADER], "2");
+        });
+    }
+
+    #[test]
+    fn test_reject_and_redrive() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            mq.create_queue("jobs");
+            let mut policy = DeadLetterPolicy::new(5, "jobs.dlq");
+            policy.reason_header = "x-failure".to_string();
+            mq.set_dead_letter_policy("jobs", policy);
+
+            let message = Message {
+                id: "1".to_string(),
+                payload: b"Test message".to_vec(),
+                ..Default::default()
+            };
+            mq.publish("jobs", message.clone()).await;
+
+            let delivery = mq.consume("jobs").await.unwrap();
+            assert!(mq.reject("jobs", &delivery.tag, "plugin failed").await);
+            assert_eq!(mq.peek_dead_letters("jobs.dlq", 10)[0].headers["x-failure"], "plugin failed");
+
+            assert_eq!(mq.redrive("jobs.dlq", 10).await, 1);
+            assert!(mq.peek_dead_letters("jobs.dlq", 10).is_empty());
+
+            let redriven = mq.consume("jobs").await.unwrap();
+            assert_eq!(redriven.message.id, message.id);
+            assert!(redriven.message.headers.is_empty());
+        });
+    }
+
+    fn prioritized(id: &str, priority: u8) -> Message {
+        Message {
+            id: id.to_string(),
+            payload: b"Test message".to_vec(),
+            priority,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_strict_priority_order() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            mq.create_queue("strict_queue");
+            assert!(mq.set_priority_mode("strict_queue", PriorityMode::Strict));
+
+            mq.publish("strict_queue", prioritized("low", 1)).await;
+            mq.publish("strict_queue", prioritized("high-1", 9)).await;
+            mq.publish("strict_queue", prioritized("mid", 5)).await;
+            mq.publish("strict_queue", prioritized("high-2", 9)).await;
+
+            let mut order = Vec::new();
+            while let Some(delivery) = mq.consume("strict_queue").await {
+                mq.ack("strict_queue", &delivery.tag).await;
+                order.push(delivery.message.id);
+            }
+            assert_eq!(order, vec!["high-1", "high-2", "mid", "low"]);
+        });
+    }
+
+    #[test]
+    fn test_weighted_fair_does_not_starve_low_priority() {
+        let mut ready = ReadyQueue::new();
+        for seq in 0..20 {
+            let priority = if seq % 2 == 0 { 9 } else { 0 };
+            ready.push_back(StoredMessage { seq, priority, expires_at: None, encoded: Vec::new() });
+        }
+
+        // Priority 9 has ten times the weight of priority 0, so one low message per eleven pops
+        let picks: Vec<u8> = (0..11).map(|_| ready.pop(PriorityMode::WeightedFair).unwrap().priority).collect();
+        assert_eq!(picks.iter().filter(|&&p| p == 9).count(), 10);
+        assert_eq!(picks.iter().filter(|&&p| p == 0).count(), 1);
+    }
+
+    #[test]
+    fn test_fifo_mode_ignores_priority() {
+        let mut ready = ReadyQueue::new();
+        ready.push_back(StoredMessage { seq: 0, priority: 0, expires_at: None, encoded: Vec::new() });
+        ready.push_back(StoredMessage { seq: 1, priority: 9, expires_at: None, encoded: Vec::new() });
+        assert_eq!(ready.pop(PriorityMode::Fifo).unwrap().seq, 0);
+        assert_eq!(ready.pop(PriorityMode::Fifo).unwrap().seq, 1);
+    }
+
+    #[test]
+    fn test_delayed_delivery() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            mq.create_queue("delayed_queue");
+
+            let message = Message {
+                id: "later".to_string(),
+                payload: b"Test message".to_vec(),
+                deliver_at: Some(scheduler::now_millis() + 50),
+                ..Default::default()
+            };
+            mq.publish("delayed_queue", message.clone()).await;
+            assert!(mq.consume("delayed_queue").await.is_none());
+
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            let delivery = mq.consume("delayed_queue").await.unwrap();
+            assert_eq!(delivery.message.id, message.id);
+        });
+    }
+
+    #[test]
+    fn test_expired_messages_are_skipped() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            mq.create_queue("ttl_queue");
+
+            let stale = Message {
+                id: "stale".to_string(),
+                payload: b"Test message".to_vec(),
+                expires_at: Some(scheduler::now_millis() + 10),
+                ..Default::default()
+            };
+            let fresh = Message {
+                id: "fresh".to_string(),
+                payload: b"Test message".to_vec(),
+                ..Default::default()
+            };
+            mq.publish("ttl_queue", stale).await;
+            mq.publish("ttl_queue", fresh.clone()).await;
+
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let delivery = mq.consume("ttl_queue").await.unwrap();
+            assert_eq!(delivery.message.id, fresh.id);
+        });
+    }
+
+    #[test]
+    fn test_default_ttl_sweeps_into_dead_letter_queue() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            mq.create_queue("ttl_dlq_queue");
+            assert!(mq.set_default_ttl("ttl_dlq_queue", Some(Duration::from_millis(10))));
+            let mut policy = DeadLetterPolicy::new(3, "ttl_dlq_queue.dlq");
+            policy.dead_letter_expired = true;
+            mq.set_dead_letter_policy("ttl_dlq_queue", policy);
+
+            let message = Message {
+                id: "1".to_string(),
+                payload: b"Test message".to_vec(),
+                ..Default::default()
+            };
+            mq.publish("ttl_dlq_queue", message.clone()).await;
+
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert_eq!(mq.sweep_expired(), 1);
+
+            let parked = mq.peek_dead_letters("ttl_dlq_queue.dlq", 10);
+            assert_eq!(parked.len(), 1);
+            assert_eq!(parked[0].headers[dead_letter::DEFAULT_REASON_HEADER], "expired");
+            assert!(parked[0].expires_at.is_none());
+        });
+    }
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let mut headers = HashMap::new();
+        headers.insert("source".to_string(), "camera-7".to_string());
+        let message = Message {
+            id: "1".to_string(),
+            payload: vec![0x89, b'P', b'N', b'G', 0x00, 0xff],
+            headers,
+            timestamp: Some(1_700_000_000_000),
+            content_type: Some("image/png".to_string()),
+            correlation_id: Some("req-42".to_string()),
+            reply_to: Some("replies".to_string()),
+            ..Default::default()
+        };
+
+        let proto_message: RapidMQMessage = message.clone().into();
+        let decoded: Message = RapidMQMessage::decode(&proto_message.encode_to_vec()[..]).unwrap().into();
+        assert_eq!(decoded.payload, message.payload);
+        assert_eq!(decoded.headers, message.headers);
+        assert_eq!(decoded.timestamp, message.timestamp);
+        assert_eq!(decoded.content_type, message.content_type);
+        assert_eq!(decoded.correlation_id, message.correlation_id);
+        assert_eq!(decoded.reply_to, message.reply_to);
+        assert_eq!(decoded.key, None);
+    }
+
+    #[test]
+    fn test_content_only_records_stay_readable() {
+        // Shape of records persisted before the envelope carried a binary payload
+        let legacy = RapidMQMessage {
+            id: "old".to_string(),
+            content: "Test message".to_string(),
+            ..Default::default()
+        };
+        let decoded: Message = RapidMQMessage::decode(&legacy.encode_to_vec()[..]).unwrap().into();
+        assert_eq!(decoded.payload, b"Test message".to_vec());
+        assert!(decoded.content_type.is_none());
+    }
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(exchange::topic_matches("orders.*.created", "orders.eu.created"));
+        assert!(!exchange::topic_matches("orders.*.created", "orders.eu.west.created"));
+        assert!(exchange::topic_matches("orders.#", "orders"));
+        assert!(exchange::topic_matches("orders.#", "orders.eu.west.created"));
+        assert!(exchange::topic_matches("#.created", "orders.eu.created"));
+        assert!(exchange::topic_matches("#", ""));
+        assert!(!exchange::topic_matches("orders.*", "orders"));
+    }
+
+    #[test]
+    fn test_exchange_routing() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            mq.create_queue("eu_orders");
+            mq.create_queue("all_orders");
+            mq.create_queue("audit");
+
+            mq.declare_exchange("orders", ExchangeKind::Topic).unwrap();
+            mq.bind_queue("orders", Binding::new("eu_orders", "orders.eu.*")).unwrap();
+            mq.bind_queue("orders", Binding::new("all_orders", "orders.#")).unwrap();
+            // Binding twice must not double-deliver
+            mq.bind_queue("orders", Binding::new("all_orders", "orders.#")).unwrap();
+
+            let message = Message {
+                id: "1".to_string(),
+                payload: b"Test message".to_vec(),
+                ..Default::default()
+            };
+            assert_eq!(mq.publish_to_exchange("orders", "orders.eu.created", message.clone()).await, Ok(2));
+            assert_eq!(mq.publish_to_exchange("orders", "orders.us.created", message.clone()).await, Ok(1));
+
+            mq.declare_exchange("audit_headers", ExchangeKind::Headers).unwrap();
+            let mut binding = Binding::new("audit", "");
+            binding.headers.insert("audit".to_string(), "true".to_string());
+            mq.bind_queue("audit_headers", binding).unwrap();
+            let mut audited = message.clone();
+            audited.headers.insert("audit".to_string(), "true".to_string());
+            assert_eq!(mq.publish_to_exchange("audit_headers", "", audited).await, Ok(1));
+            assert_eq!(mq.publish_to_exchange("audit_headers", "", message).await, Ok(0));
+
+            assert!(mq.consume("eu_orders").await.is_some());
+            assert!(mq.consume("eu_orders").await.is_none());
+            assert!(mq.consume("all_orders").await.is_some());
+            assert!(mq.consume("all_orders").await.is_some());
+            assert!(mq.consume("audit").await.is_some());
+            assert!(mq.publish_to_exchange("missing", "", Message::default()).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_unsubscribe() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            mq.create_queue("feed");
+            mq.create_queue("mirror");
+            mq.subscribe("feed", "mirror").unwrap();
+            assert_eq!(mq.subscribers_of("feed"), vec!["mirror"]);
+
+            assert!(mq.unsubscribe("feed", "mirror").unwrap());
+            assert!(!mq.unsubscribe("feed", "mirror").unwrap());
+            assert!(mq.subscriptions().get("feed").is_none());
+
+            let message = Message {
+                id: "1".to_string(),
+                payload: b"Test message".to_vec(),
+                ..Default::default()
+            };
+            mq.publish("feed", message).await;
+            assert!(mq.consume("mirror").await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_consumer_group_rebalance() {
+        let mut state = cluster::ClusterState::default();
+        let join = |member_id: &str, queue_name: &str, at: u64| ClusterCommand::JoinGroup {
+            group: "workers".to_string(),
+            queue_name: queue_name.to_string(),
+            partitions: 4,
+            member_id: member_id.to_string(),
+            at,
+        };
+        state.apply(&join("a", "jobs", 1_000));
+        assert_eq!(state.consumer_groups["workers"].assignment("a").unwrap().partitions, vec![0, 1, 2, 3]);
+
+        state.apply(&join("b", "jobs", 1_000));
+        let group = &state.consumer_groups["workers"];
+        let (first, second) = (group.assignment("a").unwrap(), group.assignment("b").unwrap());
+        assert_eq!(first.partitions, vec![0, 2]);
+        assert_eq!(second.partitions, vec![1, 3]);
+        assert_eq!(first.generation, second.generation);
+
+        // "b" stops heartbeating and the leader drops it, handing its partitions back to "a"
+        state.apply(&ClusterCommand::GroupHeartbeat { group: "workers".to_string(), member_id: "a".to_string(), at: 10_000 });
+        let expired = state.consumer_groups["workers"].expired(12_000, Duration::from_secs(10));
+        assert_eq!(expired, vec!["b"]);
+        state.apply(&ClusterCommand::LeaveGroup { group: "workers".to_string(), member_ids: expired });
+        assert_eq!(state.consumer_groups["workers"].assignment("a").unwrap().partitions, vec![0, 1, 2, 3]);
+        assert!(state.consumer_groups["workers"].assignment("b").is_none());
+
+        // A group stays on the queue it was first joined with
+        state.apply(&join("c", "other_queue", 12_000));
+        assert!(state.consumer_groups["workers"].assignment("c").is_none());
+        state.apply(&ClusterCommand::LeaveGroup { group: "workers".to_string(), member_ids: vec!["a".to_string()] });
+        assert!(state.consumer_groups.get("workers").is_none());
+    }
+
+    #[test]
+    fn test_consume_group_reads_only_assigned_partitions() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            mq.create_partitioned_queue("events", 2);
+
+            for i in 0..6 {
+                let message = Message {
+                    id: i.to_string(),
+                    payload: b"Test message".to_vec(),
+                    ..Default::default()
+                };
+                mq.publish("events", message).await;
+            }
+
+            let a = mq.join_group("analytics", "events", Some("a".to_string())).await.unwrap();
+            let b = mq.join_group("analytics", "events", Some("b".to_string())).await.unwrap();
+            let a = mq.heartbeat("analytics", &a.member_id).unwrap();
+
+            let mut seen = Vec::new();
+            for member in [&a, &b] {
+                while let Some(group_delivery) = mq.consume_group("analytics", &member.member_id).await.unwrap() {
+                    assert!(member.partitions.contains(&group_delivery.partition));
+                    assert!(mq.ack(&group_delivery.queue_name, &group_delivery.delivery.tag).await);
+                    seen.push(group_delivery.delivery.message.id);
+                }
+            }
+            seen.sort();
+            assert_eq!(seen, vec!["0", "1", "2", "3", "4", "5"]);
+        });
+    }
+
+    #[test]
+    fn test_stream_replay_and_seek() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            // The node's database outlives the test, so each run gets a fresh stream
+            let clicks = format!("clicks_{}", uuid::Uuid::new_v4());
+            mq.create_stream(&clicks, RetentionPolicy::default()).unwrap();
+
+            for i in 0..5 {
+                let message = Message {
+                    id: i.to_string(),
+                    payload: b"Test message".to_vec(),
+                    timestamp: Some(1_000 + i as u64),
+                    ..Default::default()
+                };
+                mq.publish(&clicks, message).await;
+            }
+
+            // Reading does not consume, so two readers see the same records
+            let first = mq.read_stream(&clicks, StartOffset::Earliest, 10).unwrap();
+            let second = mq.read_stream(&clicks, StartOffset::Earliest, 10).unwrap();
+            assert_eq!(first.len(), 5);
+            assert_eq!(first.iter().map(|r| r.offset).collect::<Vec<_>>(), second.iter().map(|r| r.offset).collect::<Vec<_>>());
+
+            let from_two = mq.read_stream(&clicks, StartOffset::Offset(first[2].offset), 2).unwrap();
+            assert_eq!(from_two.iter().map(|r| r.message.id.as_str()).collect::<Vec<_>>(), vec!["2", "3"]);
+
+            let (offset, _, head) = mq.seek_stream(&clicks, StartOffset::Timestamp(1_003)).unwrap();
+            assert_eq!(offset, first[3].offset);
+            assert_eq!(mq.seek_stream(&clicks, StartOffset::Latest).unwrap().0, head);
+            assert!(mq.read_stream(&clicks, StartOffset::Latest, 10).unwrap().is_empty());
+            assert_eq!("timestamp:1003".parse::<StartOffset>().unwrap(), StartOffset::Timestamp(1_003));
+        });
+    }
+
+    #[test]
+    fn test_stream_retention_drops_oldest_records() {
+        let (mq, _) = setup();
+        let db = mq.db.clone();
+        let name = format!("retained_{}", uuid::Uuid::new_v4());
+        let mut stream = Stream::open(&name, db);
+        for i in 0..4 {
+            stream.append(Message { id: i.to_string(), timestamp: Some(i as u64 * 1_000), ..Default::default() });
+        }
+        let head = stream.next_offset();
+
+        stream.set_retention(RetentionPolicy { max_bytes: None, max_age_secs: Some(2) });
+        assert_eq!(stream.enforce_retention(3_500), 2);
+        assert_eq!(stream.read(0, 10).iter().map(|r| r.message.id.as_str()).collect::<Vec<_>>(), vec!["2", "3"]);
+
+        let record_size = stream.size_bytes() / stream.len() as u64;
+        stream.set_retention(RetentionPolicy { max_bytes: Some(record_size), max_age_secs: None });
+        assert_eq!(stream.enforce_retention(3_500), 1);
+        assert_eq!(stream.first_offset(), head - 1);
+        assert_eq!(stream.next_offset(), head);
+    }
+
+    #[test]
+    fn test_committed_offsets_and_lag() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            let clicks = format!("clicks_{}", uuid::Uuid::new_v4());
+            mq.create_stream(&clicks, RetentionPolicy::default()).unwrap();
+            for i in 0..4 {
+                mq.publish(&clicks, Message { id: i.to_string(), ..Default::default() }).await;
+            }
+
+            let fresh = mq.consumer_offset(&clicks, "billing").unwrap();
+            assert_eq!(fresh.offset, None);
+            assert_eq!(fresh.lag, 4);
+
+            let records = mq.read_stream(&clicks, StartOffset::Earliest, 3).unwrap();
+            let next = records.last().unwrap().offset + 1;
+            mq.commit_offset(&clicks, "billing", next).unwrap();
+            assert!(mq.commit_offset(&clicks, "billing", next + 10).is_err());
+
+            // Resuming from the committed offset picks up the one unread record
+            let resumed = mq.read_stream(&clicks, StartOffset::Offset(mq.committed_offset(&clicks, "billing").unwrap()), 10).unwrap();
+            assert_eq!(resumed.iter().map(|r| r.message.id.as_str()).collect::<Vec<_>>(), vec!["3"]);
+
+            let billing = mq.consumer_offset(&clicks, "billing").unwrap();
+            assert_eq!(billing.offset, Some(next));
+            assert_eq!(billing.lag, 1);
+            assert_eq!(mq.consumer_offsets(&clicks).unwrap(), vec![billing]);
+        });
+    }
+
+    fn keyed(key: &str, value: &str, timestamp: u64) -> Message {
+        Message {
+            id: format!("{}={}", key, value),
+            payload: value.as_bytes().to_vec(),
+            key: Some(key.to_string()),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_compaction_keeps_latest_value_per_key() {
+        let (mq, _) = setup();
+        let name = format!("devices_{}", uuid::Uuid::new_v4());
+        let mut stream = Stream::open(&name, mq.db.clone());
+        stream.set_compaction(Some(CompactionPolicy { tombstone_retention_secs: 10 }));
+
+        stream.append(keyed("sensor-1", "v1", 1_000));
+        stream.append(keyed("sensor-2", "v1", 1_000));
+        stream.append(Message { id: "unkeyed".to_string(), payload: b"log".to_vec(), timestamp: Some(1_000), ..Default::default() });
+        stream.append(keyed("sensor-1", "v2", 2_000));
+        stream.append(keyed("sensor-2", "", 2_000));
+        let head = stream.next_offset();
+
+        assert_eq!(stream.compact(3_000), 2);
+        let ids: Vec<String> = stream.read(0, 10).into_iter().map(|r| r.message.id).collect();
+        assert_eq!(ids, vec!["unkeyed", "sensor-1=v2", "sensor-2="]);
+
+        // The tombstone goes once consumers have had time to see it
+        assert_eq!(stream.compact(2_000 + 10_000 + 1), 1);
+        let ids: Vec<String> = stream.read(0, 10).into_iter().map(|r| r.message.id).collect();
+        assert_eq!(ids, vec!["unkeyed", "sensor-1=v2"]);
+        assert_eq!(stream.next_offset(), head);
+        assert_eq!(stream.compact(2_000 + 10_000 + 1), 0);
+    }
+
+    #[test]
+    fn test_duplicate_publishes_are_rejected() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            let queue_name = format!("payments_{}", uuid::Uuid::new_v4());
+            mq.create_queue(&queue_name);
+            mq.set_dedup_window(dedup::DEFAULT_DEDUP_WINDOW);
+
+            let message = Message { id: "payment-1".to_string(), payload: b"10 EUR".to_vec(), ..Default::default() };
+            assert_eq!(mq.publish(&queue_name, message.clone()).await, PublishOutcome::Published);
+            assert_eq!(mq.publish(&queue_name, message.clone()).await, PublishOutcome::Duplicate);
+
+            // Producer id and sequence take precedence over the message id
+            let mut numbered = Message { id: "retry-a".to_string(), ..Default::default() };
+            numbered.headers.insert(dedup::PRODUCER_ID_HEADER.to_string(), "edge-7".to_string());
+            numbered.headers.insert(dedup::PRODUCER_SEQ_HEADER.to_string(), "42".to_string());
+            assert_eq!(mq.publish(&queue_name, numbered.clone()).await, PublishOutcome::Published);
+            numbered.id = "retry-b".to_string();
+            assert_eq!(mq.publish(&queue_name, numbered).await, PublishOutcome::Duplicate);
+
+            let first = mq.consume(&queue_name).await.unwrap();
+            let second = mq.consume(&queue_name).await.unwrap();
+            assert!(mq.consume(&queue_name).await.is_none());
+            assert_eq!(first.message.id, "payment-1");
+            assert_eq!(second.message.id, "retry-a");
+        });
+    }
+
+    #[test]
+    fn test_messages_without_ids_are_not_deduplicated() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            let queue_name = format!("anonymous_{}", uuid::Uuid::new_v4());
+            mq.create_queue(&queue_name);
+            mq.set_dedup_window(dedup::DEFAULT_DEDUP_WINDOW);
+
+            let message = Message { payload: b"no id".to_vec(), ..Default::default() };
+            assert_eq!(mq.publish(&queue_name, message.clone()).await, PublishOutcome::Published);
+            assert_eq!(mq.publish(&queue_name, message).await, PublishOutcome::Published);
+
+            assert!(mq.consume(&queue_name).await.is_some());
+            assert!(mq.consume(&queue_name).await.is_some());
+        });
+    }
+
+    #[test]
+    fn test_transaction_commit_and_abort() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            let orders = format!("tx_orders_{}", uuid::Uuid::new_v4());
+            let invoices = format!("tx_invoices_{}", uuid::Uuid::new_v4());
+            let audit = format!("tx_audit_{}", uuid::Uuid::new_v4());
+            for queue_name in [&orders, &invoices, &audit] {
+                mq.create_queue(queue_name);
+            }
+            mq.publish(&orders, Message { id: "order-1".to_string(), payload: b"order".to_vec(), ..Default::default() }).await;
+
+            // Consume-transform-produce: nothing is visible until the commit
+            let input = mq.consume(&orders).await.unwrap();
+            let tx = mq.begin_transaction();
+            mq.transactional_ack(&tx, &orders, &input.tag).unwrap();
+            mq.transactional_publish(&tx, &invoices, Message { id: "invoice-1".to_string(), ..Default::default() }).unwrap();
+            mq.transactional_publish(&tx, &audit, Message { id: "audit-1".to_string(), ..Default::default() }).unwrap();
+            assert!(mq.consume(&invoices).await.is_none());
+            mq.commit_transaction(&tx).await.unwrap();
+
+            assert_eq!(mq.consume(&invoices).await.unwrap().message.id, "invoice-1");
+            assert_eq!(mq.consume(&audit).await.unwrap().message.id, "audit-1");
+            assert!(!mq.ack(&orders, &input.tag).await);
+            assert!(mq.commit_transaction(&tx).await.is_err());
+
+            // Aborting hands the consumed message straight back
+            mq.publish(&orders, Message { id: "order-2".to_string(), ..Default::default() }).await;
+            let input = mq.consume(&orders).await.unwrap();
+            let tx = mq.begin_transaction();
+            mq.transactional_ack(&tx, &orders, &input.tag).unwrap();
+            mq.transactional_publish(&tx, &invoices, Message { id: "invoice-2".to_string(), ..Default::default() }).unwrap();
+            assert!(mq.abort_transaction(&tx).await);
+            assert_eq!(mq.consume(&orders).await.unwrap().message.id, "order-2");
+            assert!(mq.consume(&invoices).await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_failed_transaction_writes_nothing() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            let results = format!("tx_results_{}", uuid::Uuid::new_v4());
+            mq.create_queue(&results);
+            let tx = mq.begin_transaction();
+            mq.transactional_publish(&tx, &results, Message { id: "result-1".to_string(), ..Default::default() }).unwrap();
+            mq.transactional_ack(&tx, &results, "no-such-tag").unwrap();
+            assert!(mq.commit_transaction(&tx).await.is_err());
+            assert!(mq.consume(&results).await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_prepared_transaction_commits_after_vote() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            let prepared = format!("tx_prepared_{}", uuid::Uuid::new_v4());
+            mq.create_queue(&prepared);
+            let tx_id = uuid::Uuid::new_v4().to_string();
+            let ops = vec![TxOp::Publish { queue_name: prepared.clone(), message: Message { id: "p-1".to_string(), ..Default::default() } }];
+            assert!(mq.prepare_transaction(&tx_id, ops).is_ok());
+            assert!(mq.in_doubt_transactions().contains(&tx_id));
+            assert!(mq.consume(&prepared).await.is_none());
+
+            assert_eq!(mq.commit_prepared(&tx_id).await, Ok(true));
+            assert!(!mq.in_doubt_transactions().contains(&tx_id));
+            assert_eq!(mq.consume(&prepared).await.unwrap().message.id, "p-1");
+
+            let missing = vec![TxOp::Ack { queue_name: prepared.clone(), delivery_tag: "gone".to_string() }];
+            assert!(mq.prepare_transaction("tx-remote-2", missing).is_err());
+        });
+    }
+
+    #[test]
+    fn test_publish_and_consume_batch() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            let queue_name = format!("batch_{}", uuid::Uuid::new_v4());
+            mq.create_queue(&queue_name);
+            mq.set_dedup_window(dedup::DEFAULT_DEDUP_WINDOW);
+
+            let messages: Vec<Message> = (0..5)
+                .map(|i| Message { id: format!("m{}", i % 4), payload: b"Test message".to_vec(), ..Default::default() })
+                .collect();
+            let outcomes = mq.publish_batch(&queue_name, messages).await;
+            assert_eq!(outcomes[..4], [PublishOutcome::Published; 4]);
+            // "m0" appears twice in the batch
+            assert_eq!(outcomes[4], PublishOutcome::Duplicate);
+
+            let first = mq.consume_batch(&queue_name, 3, Duration::ZERO).await;
+            let rest = mq.consume_batch(&queue_name, 10, Duration::ZERO).await;
+            let ids: Vec<String> = first.iter().chain(&rest).map(|d| d.message.id.clone()).collect();
+            assert_eq!(ids, vec!["m0", "m1", "m2", "m3"]);
+            assert_eq!(first.len(), 3);
+
+            // An empty queue is only waited on for as long as asked
+            let started = Instant::now();
+            assert!(mq.consume_batch(&queue_name, 10, Duration::from_millis(120)).await.is_empty());
+            assert!(started.elapsed() >= Duration::from_millis(120));
+        });
+    }
+
+    #[test]
+    fn test_consume_wait_wakes_on_publish() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            let queue_name = format!("wait_{}", uuid::Uuid::new_v4());
+            mq.create_queue(&queue_name);
+
+            let publisher = mq.clone();
+            let target = queue_name.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                publisher.publish(&target, Message { id: "late".to_string(), payload: b"Test message".to_vec(), ..Default::default() }).await;
+            });
+
+            let started = Instant::now();
+            let delivery = mq.consume_wait(&queue_name, Duration::from_secs(10)).await.unwrap();
+            assert_eq!(delivery.message.id, "late");
+            assert!(started.elapsed() < Duration::from_secs(5));
+
+            // Nothing arrives, so the wait runs out
+            let started = Instant::now();
+            assert!(mq.consume_wait(&queue_name, Duration::from_millis(100)).await.is_none());
+            assert!(started.elapsed() >= Duration::from_millis(100));
+        });
+    }
+
+    #[test]
+    fn test_consume_wait_wakes_for_delayed_message() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            let queue_name = format!("wait_delayed_{}", uuid::Uuid::new_v4());
+            mq.create_queue(&queue_name);
+            let message = Message {
+                id: "delayed".to_string(),
+                payload: b"Test message".to_vec(),
+                deliver_at: Some(scheduler::now_millis() + 80),
+                ..Default::default()
+            };
+            mq.publish(&queue_name, message).await;
+
+            let delivery = mq.consume_wait(&queue_name, Duration::from_secs(10)).await.unwrap();
+            assert_eq!(delivery.message.id, "delayed");
+        });
+    }
+
+    #[test]
+    fn test_queue_tap_is_non_destructive() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, _) = setup();
+            let queue_name = format!("tapped_{}", uuid::Uuid::new_v4());
+            mq.create_queue(&queue_name);
+            let tap = mq.tap_queue(&queue_name).unwrap();
+
+            let reader = mq.clone();
+            let stream = tap.stream().to_string();
+            let tail = tokio::spawn(async move {
+                reader.read_stream_wait(&stream, StartOffset::Latest, 10, Duration::from_secs(10)).await
+            });
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            mq.publish(&queue_name, Message { id: "seen".to_string(), payload: b"Test message".to_vec(), ..Default::default() }).await;
+
+            let records = tail.await.unwrap().unwrap();
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[0].message.id, "seen");
+            // The queue still has its copy
+            assert_eq!(mq.consume(&queue_name).await.unwrap().message.id, "seen");
+
+            // Closing the last feed disconnects the tap
+            drop(tap);
+            assert!(mq.subscribers_of(&queue_name).is_empty());
+            assert!(mq.tap_queue("no_such_queue").is_err());
+        });
+    }
+
+    #[test]
+    fn test_replica_follows_leader_journal() {
+        let (mq, _) = setup();
+        let suffix = uuid::Uuid::new_v4();
+        let follower_name = format!("replica_follower_{}", suffix);
+        let mut leader = Queue::new(&format!("replica_leader_{}", suffix), mq.db.clone());
+        let mut follower = Queue::new(&follower_name, mq.db.clone());
+        leader.set_replicated(true);
+        let message = |id: &str| Message {
+            id: id.to_string(),
+            payload: b"Test message".to_vec(),
+            ..Default::default()
+        };
+
+        for id in ["first", "second", "third"] {
+            leader.enqueue(message(id));
+        }
+        // A follower the leader has not caught up yet starts from a full copy
+        follower.reset_replica(leader.replica_snapshot());
+        leader.take_journal();
+
+        let delivery = leader.dequeue().unwrap();
+        assert!(leader.ack(&delivery.tag));
+        leader.enqueue(message("fourth"));
+        for op in leader.take_journal() {
+            follower.apply_replica(op);
+        }
+
+        let ids = |queue: &Queue| queue.peek(10).into_iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(ids(&follower), vec!["second", "third", "fourth"]);
+        assert_eq!(ids(&follower), ids(&leader));
+        // The copy is persisted like any other queue
+        assert_eq!(ids(&Queue::new(&follower_name, mq.db.clone())), vec!["second", "third", "fourth"]);
+    }
+
+    #[test]
+    fn test_single_replica_queue_publishes_without_followers() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mq, node_id) = setup();
+            let queue_name = format!("single_replica_{}", uuid::Uuid::new_v4());
+            assert_eq!(mq.create_replicated_queue(&queue_name, 1, 1).unwrap(), vec![node_id]);
+            assert!(mq.create_replicated_queue(&queue_name, 1, 1).is_err());
+            assert!(mq.create_replicated_queue("too_many_replicas", 2, 1).is_err());
+
+            let message = Message {
+                id: "replicated".to_string(),
+                payload: b"Test message".to_vec(),
+                ..Default::default()
+            };
+            assert_eq!(mq.publish(&queue_name, message).await, PublishOutcome::Published);
+            let delivery = mq.consume(&queue_name).await.unwrap();
+            assert!(mq.ack(&queue_name, &delivery.tag).await);
+
+            // Only the queue's leader may ship changes, and only for replicated queues
+            let stray = vec![ReplicaOp::Remove { seq: 0 }];
+            assert!(!mq.apply_replica_ops(&queue_name, NodeId::from(2), stray.clone(), false));
+            let plain = format!("plain_{}", uuid::Uuid::new_v4());
+            mq.create_queue(&plain);
+            assert!(!mq.apply_replica_ops(&plain, node_id, stray, true));
+        });
+    }
+
+    #[test]
+    fn test_promote_replica_moves_queue_leadership() {
+        let mut state = cluster::ClusterState::default();
+        let replica_set = ReplicaSet::new(vec![1, 2, 3], 2).unwrap();
+        state.apply(&ClusterCommand::CreateReplicatedQueue { queue_name: "orders".to_string(), replica_set });
+        assert_eq!(state.queue_assignments["orders"], 1);
+
+        // Only an in-sync follower can take over
+        state.apply(&ClusterCommand::SetInSync { queue_name: "orders".to_string(), node_id: 2, in_sync: false });
+        state.apply(&ClusterCommand::PromoteReplica { queue_name: "orders".to_string(), node_id: 2 });
+        assert_eq!(state.queue_assignments["orders"], 1);
+
+        state.apply(&ClusterCommand::PromoteReplica { queue_name: "orders".to_string(), node_id: 3 });
+        assert_eq!(state.queue_assignments["orders"], 3);
+        let replica_set = &state.replica_sets["orders"];
+        assert_eq!(replica_set.replicas, vec![3, 2, 1]);
+        assert_eq!(replica_set.in_sync, vec![3]);
     }
 
     #[test]
@@ -311,10 +3547,21 @@ mod tests {
 pub mod api;
 pub mod cluster;
 
-use cluster::ClusterManager;
+use cluster::{ClusterCommand, ClusterManager};
+use raft_transport::InMemoryNetwork;
 
 // Add new modules
 pub mod ai_module;
+pub mod consumer_group;
+pub mod dead_letter;
+pub mod dedup;
+pub mod exchange;
+pub mod priority;
+pub mod raft_storage;
+pub mod raft_transport;
+pub mod replication;
+pub mod scheduler;
+pub mod transaction;
 pub mod quantum_module;
 
 use ai_module::AIModule;
@@ -1,4 +1,5 @@
 use rapidmq::{RapidMQ, api};
+use rapidmq::exchange::{Binding, ExchangeKind};
 use raft::NodeId;
 use std::env;
 use clap::Parser;
@@ -32,18 +33,54 @@ async fn main() -> std::io::Result<()> {
         Commands::PublishMessage { queue_name, message } => {
             let msg = rapidmq::Message {
                 id: uuid::Uuid::new_v4().to_string(),
-                content: message.clone(),
+                payload: message.clone().into_bytes(),
+                ..Default::default()
             };
             rapidmq.publish(queue_name, msg).await.unwrap();
             println!("Message published to queue '{}'", queue_name);
         }
         Commands::ConsumeMessage { queue_name } => {
-            if let Some(message) = rapidmq.consume(queue_name).await {
-                println!("Consumed message: {}", message.content);
+            if let Some(delivery) = rapidmq.consume(queue_name).await {
+                println!("Consumed message: {}", String::from_utf8_lossy(&delivery.message.payload));
+                rapidmq.ack(queue_name, &delivery.tag).await;
             } else {
                 println!("No messages in queue '{}'", queue_name);
             }
         }
+        Commands::DeclareExchange { name, kind } => {
+            match kind.parse::<ExchangeKind>().and_then(|kind| rapidmq.declare_exchange(name, kind)) {
+                Ok(()) => println!("Exchange '{}' declared", name),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        Commands::DeleteExchange { name } => {
+            if rapidmq.delete_exchange(name) {
+                println!("Exchange '{}' deleted", name);
+            } else {
+                println!("No exchange named '{}'", name);
+            }
+        }
+        Commands::BindQueue { exchange, queue_name, routing_key } => {
+            match rapidmq.bind_queue(exchange, Binding::new(queue_name, routing_key)) {
+                Ok(()) => println!("Queue '{}' bound to '{}' with key '{}'", queue_name, exchange, routing_key),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        Commands::UnbindQueue { exchange, queue_name, routing_key } => {
+            match rapidmq.unbind_queue(exchange, &Binding::new(queue_name, routing_key)) {
+                Ok(true) => println!("Queue '{}' unbound from '{}'", queue_name, exchange),
+                Ok(false) => println!("No such binding on '{}'", exchange),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        Commands::ListExchanges => {
+            for exchange in rapidmq.list_exchanges() {
+                println!("{} ({:?})", exchange.name, exchange.kind);
+                for binding in exchange.bindings {
+                    println!("  -> {} [{}]", binding.queue, binding.routing_key);
+                }
+            }
+        }
         Commands::AddNode { node_id, address } => {
             rapidmq.add_node(NodeId::from(*node_id), address.to_string()).await;
             println!("Node {} added with address {}", node_id, address);
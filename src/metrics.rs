@@ -6,10 +6,14 @@ lazy_static! {
     pub static ref MESSAGE_COUNT: Counter = Counter::new("rapidmq_messages_total", "Total number of messages").expect("metric can be created");
     pub static ref QUEUE_SIZE: Gauge = Gauge::new("rapidmq_queue_size", "Current queue size").expect("metric can be created");
     pub static ref MESSAGE_PROCESSING_TIME: Histogram = Histogram::new("rapidmq_message_processing_seconds", "Message processing time in seconds").expect("metric can be created");
+    pub static ref MESSAGES_DEAD_LETTERED: Counter = Counter::new("rapidmq_messages_dead_lettered_total", "Messages moved to a dead-letter queue").expect("metric can be created");
+    pub static ref MESSAGES_EXPIRED: Counter = Counter::new("rapidmq_messages_expired_total", "Messages dropped or dead-lettered because their TTL ran out").expect("metric can be created");
 }
 
 pub fn register_metrics() {
     REGISTRY.register(Box::new(MESSAGE_COUNT.clone())).expect("collector can be registered");
     REGISTRY.register(Box::new(QUEUE_SIZE.clone())).expect("collector can be registered");
     REGISTRY.register(Box::new(MESSAGE_PROCESSING_TIME.clone())).expect("collector can be registered");
+    REGISTRY.register(Box::new(MESSAGES_DEAD_LETTERED.clone())).expect("collector can be registered");
+    REGISTRY.register(Box::new(MESSAGES_EXPIRED.clone())).expect("collector can be registered");
 }
\ No newline at end of file
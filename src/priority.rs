@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+use serde::{Serialize, Deserialize};
+
+// Priorities run from 0 (lowest) to MAX_PRIORITY (highest)
+pub const MAX_PRIORITY: u8 = 9;
+const LEVELS: usize = MAX_PRIORITY as usize + 1;
+
+// How a queue picks the next message among priority levels
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PriorityMode {
+    // Publish order, priority is ignored
+    #[default]
+    Fifo,
+    // Always the highest non-empty priority first
+    Strict,
+    // Priority p gets p + 1 shares, so lower priorities are slowed down but never starved
+    WeightedFair,
+}
+
+// A message as stored in a queue, tagged with its sequence number, priority and expiry
+pub struct StoredMessage {
+    pub seq: u64,
+    pub priority: u8,
+    // Unix time in milliseconds after which the message is dropped instead of delivered
+    pub expires_at: Option<u64>,
+    pub encoded: Vec<u8>,
+}
+
+impl StoredMessage {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map_or(false, |at| at <= now)
+    }
+}
+
+// Visible messages bucketed by priority, each bucket ordered by sequence number
+pub struct ReadyQueue {
+    levels: Vec<VecDeque<StoredMessage>>,
+    // Smooth weighted round-robin state for WeightedFair
+    credits: [i64; LEVELS],
+}
+
+impl ReadyQueue {
+    pub fn new() -> Self {
+        ReadyQueue {
+            levels: (0..LEVELS).map(|_| VecDeque::new()).collect(),
+            credits: [0; LEVELS],
+        }
+    }
+
+    // Appends a newly published message; its sequence number is the highest so far
+    pub fn push_back(&mut self, message: StoredMessage) {
+        self.levels[Self::level(message.priority)].push_back(message);
+    }
+
+    // Puts a message back in sequence order, e.g. after a nack or visibility timeout
+    pub fn insert(&mut self, message: StoredMessage) {
+        let level = &mut self.levels[Self::level(message.priority)];
+        let pos = level.partition_point(|m| m.seq < message.seq);
+        level.insert(pos, message);
+    }
+
+    pub fn pop(&mut self, mode: PriorityMode) -> Option<StoredMessage> {
+        let level = match mode {
+            PriorityMode::Fifo => self.oldest_level()?,
+            PriorityMode::Strict => self.highest_level()?,
+            PriorityMode::WeightedFair => self.weighted_level()?,
+        };
+        self.levels[level].pop_front()
+    }
+
+    // Messages in publish order under Fifo, and highest priority first under both
+    // Strict and WeightedFair. Weighted-fair picks depend on what was popped
+    // before, so listings show the strict order instead.
+    pub fn iter(&self, mode: PriorityMode) -> Box<dyn Iterator<Item = &StoredMessage> + '_> {
+        match mode {
+            PriorityMode::Fifo => {
+                let mut all: Vec<&StoredMessage> = self.levels.iter().flatten().collect();
+                all.sort_by_key(|m| m.seq);
+                Box::new(all.into_iter())
+            }
+            _ => Box::new(self.levels.iter().rev().flatten()),
+        }
+    }
+
+    // Takes every message matching `pred` out of the queue, keeping the rest in order
+    pub fn remove_where<F: Fn(&StoredMessage) -> bool>(&mut self, pred: F) -> Vec<StoredMessage> {
+        let mut removed = Vec::new();
+        for level in self.levels.iter_mut() {
+            let (matching, kept): (VecDeque<_>, VecDeque<_>) = level.drain(..).partition(|m| pred(m));
+            *level = kept;
+            removed.extend(matching);
+        }
+        removed
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels.iter().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.iter().all(VecDeque::is_empty)
+    }
+
+    // The highest sequence number held, used to resume numbering after a reload
+    pub fn last_seq(&self) -> Option<u64> {
+        self.levels.iter().filter_map(|level| level.back().map(|m| m.seq)).max()
+    }
+
+    fn level(priority: u8) -> usize {
+        priority.min(MAX_PRIORITY) as usize
+    }
+
+    fn oldest_level(&self) -> Option<usize> {
+        (0..LEVELS)
+            .filter_map(|i| self.levels[i].front().map(|m| (m.seq, i)))
+            .min()
+            .map(|(_, i)| i)
+    }
+
+    fn highest_level(&self) -> Option<usize> {
+        (0..LEVELS).rev().find(|&i| !self.levels[i].is_empty())
+    }
+
+    fn weighted_level(&mut self) -> Option<usize> {
+        let active: Vec<usize> = (0..LEVELS).filter(|&i| !self.levels[i].is_empty()).collect();
+        if active.is_empty() {
+            return None;
+        }
+        let total: i64 = active.iter().map(|&i| i as i64 + 1).sum();
+        for (i, level) in self.levels.iter().enumerate() {
+            if level.is_empty() {
+                // An idle level does not bank credit while it has nothing to send
+                self.credits[i] = 0;
+            } else {
+                self.credits[i] += i as i64 + 1;
+            }
+        }
+        // max_by_key keeps the last maximum, so ties go to the higher priority
+        let chosen = *active.iter().max_by_key(|&&i| self.credits[i])?;
+        self.credits[chosen] -= total;
+        Some(chosen)
+    }
+}
+
+impl Default for ReadyQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,235 @@
+use std::sync::Arc;
+use prost::Message as ProstMessage;
+use raft::prelude::{ConfState, Entry, HardState, Snapshot};
+use raft::{GetEntriesContext, RaftState, StorageError};
+use rocksdb::{DB, WriteBatch};
+
+const LOG_PREFIX: &str = "__raft:log:";
+const HARD_STATE_KEY: &str = "__raft:hard_state";
+const CONF_STATE_KEY: &str = "__raft:conf_state";
+const SNAPSHOT_KEY: &str = "__raft:snapshot";
+const APPLIED_KEY: &str = "__raft:applied";
+
+// Raft log of the cluster metadata group, persisted in the node's RocksDB.
+//
+// Entries live under `__raft:log:<index>` with the index zero-padded so they
+// iterate in log order. Everything up to the last snapshot has been compacted
+// away; the snapshot carries the serialized ClusterState at that index.
+pub struct RaftStorage {
+    hard_state: HardState,
+    conf_state: ConfState,
+    snapshot: Snapshot,
+    // Index of the first entry still in the log, one past the snapshot
+    first_index: u64,
+    last_index: u64,
+    db: Arc<DB>,
+}
+
+impl RaftStorage {
+    pub fn open(db: Arc<DB>) -> Self {
+        let hard_state = RaftStorage::load(&db, HARD_STATE_KEY).unwrap_or_default();
+        let conf_state = RaftStorage::load(&db, CONF_STATE_KEY).unwrap_or_default();
+        let snapshot: Snapshot = RaftStorage::load(&db, SNAPSHOT_KEY).unwrap_or_default();
+        let snapshot_index = snapshot.get_metadata().index;
+        let mut last_index = snapshot_index;
+        let iter = db.iterator(rocksdb::IteratorMode::From(LOG_PREFIX.as_bytes(), rocksdb::Direction::Forward));
+        for item in iter {
+            let (key, _) = item.unwrap();
+            match RaftStorage::parse_index(&key) {
+                Some(index) => last_index = last_index.max(index),
+                None => break,
+            }
+        }
+        RaftStorage {
+            hard_state,
+            conf_state,
+            snapshot,
+            first_index: snapshot_index + 1,
+            last_index,
+            db,
+        }
+    }
+
+    // True for a node that has never been part of a raft group
+    pub fn is_empty(&self) -> bool {
+        self.last_index == 0 && self.conf_state.voters.is_empty()
+    }
+
+    // Sets the initial members of a brand new group
+    pub fn bootstrap(&mut self, voters: Vec<u64>) {
+        self.set_conf_state(ConfState::from((voters, vec![])));
+    }
+
+    // Appends entries from a Ready, replacing any conflicting tail of the log
+    pub fn append(&mut self, entries: &[Entry]) {
+        let Some(first) = entries.first() else {
+            return;
+        };
+        let mut batch = WriteBatch::default();
+        for index in first.index..=self.last_index {
+            batch.delete(RaftStorage::log_key(index).as_bytes());
+        }
+        for entry in entries {
+            batch.put(RaftStorage::log_key(entry.index).as_bytes(), entry.encode_to_vec());
+        }
+        self.db.write(batch).unwrap();
+        self.last_index = entries.last().unwrap().index;
+    }
+
+    pub fn set_hard_state(&mut self, hard_state: HardState) {
+        self.db.put(HARD_STATE_KEY.as_bytes(), hard_state.encode_to_vec()).unwrap();
+        self.hard_state = hard_state;
+    }
+
+    // Records a commit index reported by a light ready
+    pub fn set_commit(&mut self, commit: u64) {
+        let mut hard_state = self.hard_state.clone();
+        hard_state.commit = commit;
+        self.set_hard_state(hard_state);
+    }
+
+    pub fn set_conf_state(&mut self, conf_state: ConfState) {
+        self.db.put(CONF_STATE_KEY.as_bytes(), conf_state.encode_to_vec()).unwrap();
+        self.conf_state = conf_state;
+    }
+
+    // Replaces the whole log with a snapshot received from the leader
+    pub fn apply_snapshot(&mut self, snapshot: Snapshot) {
+        let metadata = snapshot.get_metadata().clone();
+        let mut batch = WriteBatch::default();
+        for index in self.first_index..=self.last_index {
+            batch.delete(RaftStorage::log_key(index).as_bytes());
+        }
+        batch.put(SNAPSHOT_KEY.as_bytes(), snapshot.encode_to_vec());
+        self.db.write(batch).unwrap();
+
+        let mut hard_state = self.hard_state.clone();
+        hard_state.term = hard_state.term.max(metadata.term);
+        hard_state.commit = hard_state.commit.max(metadata.index);
+        self.set_hard_state(hard_state);
+        self.set_conf_state(metadata.get_conf_state().clone());
+        self.first_index = metadata.index + 1;
+        self.last_index = metadata.index;
+        self.snapshot = snapshot;
+    }
+
+    // Takes a snapshot of the applied state at `index` and drops the entries it
+    // covers. `data` is the serialized ClusterState as of that index.
+    pub fn compact(&mut self, index: u64, data: Vec<u8>) -> raft::Result<()> {
+        if index < self.first_index {
+            return Ok(());
+        }
+        let term = raft::Storage::term(self, index)?;
+        let mut snapshot = Snapshot::default();
+        snapshot.data = data.into();
+        let metadata = snapshot.mut_metadata();
+        metadata.index = index;
+        metadata.term = term;
+        metadata.set_conf_state(self.conf_state.clone());
+
+        let mut batch = WriteBatch::default();
+        for compacted in self.first_index..=index {
+            batch.delete(RaftStorage::log_key(compacted).as_bytes());
+        }
+        batch.put(SNAPSHOT_KEY.as_bytes(), snapshot.encode_to_vec());
+        self.db.write(batch).unwrap();
+        self.first_index = index + 1;
+        self.snapshot = snapshot;
+        Ok(())
+    }
+
+    pub fn conf_state(&self) -> &ConfState {
+        &self.conf_state
+    }
+
+    // The state machine as of the last applied entry, stored by the owner of the
+    // log so it does not have to replay everything on restart
+    pub fn applied_state(&self) -> Option<Vec<u8>> {
+        self.db.get(APPLIED_KEY.as_bytes()).unwrap()
+    }
+
+    pub fn set_applied_state(&mut self, data: Vec<u8>) {
+        self.db.put(APPLIED_KEY.as_bytes(), data).unwrap();
+    }
+
+    fn entry(&self, index: u64) -> raft::Result<Entry> {
+        let encoded = self.db.get(RaftStorage::log_key(index).as_bytes()).unwrap()
+            .ok_or(raft::Error::Store(StorageError::Unavailable))?;
+        Entry::decode(&encoded[..]).map_err(|e| raft::Error::Store(StorageError::Other(Box::new(e))))
+    }
+
+    fn load<T: ProstMessage + Default>(db: &DB, key: &str) -> Option<T> {
+        db.get(key.as_bytes()).unwrap().and_then(|value| T::decode(&value[..]).ok())
+    }
+
+    fn log_key(index: u64) -> String {
+        format!("{}{:020}", LOG_PREFIX, index)
+    }
+
+    fn parse_index(key: &[u8]) -> Option<u64> {
+        let index = std::str::from_utf8(key.strip_prefix(LOG_PREFIX.as_bytes())?).ok()?;
+        index.parse().ok()
+    }
+}
+
+impl raft::Storage for RaftStorage {
+    fn initial_state(&self) -> raft::Result<RaftState> {
+        Ok(RaftState {
+            hard_state: self.hard_state.clone(),
+            conf_state: self.conf_state.clone(),
+        })
+    }
+
+    fn entries(&self, low: u64, high: u64, max_size: impl Into<Option<u64>>, _context: GetEntriesContext) -> raft::Result<Vec<Entry>> {
+        if low < self.first_index {
+            return Err(raft::Error::Store(StorageError::Compacted));
+        }
+        if high > self.last_index + 1 {
+            return Err(raft::Error::Store(StorageError::Unavailable));
+        }
+        let max_size = max_size.into().unwrap_or(u64::MAX);
+        let mut entries = Vec::new();
+        let mut size = 0;
+        for index in low..high {
+            let entry = self.entry(index)?;
+            size += entry.encoded_len() as u64;
+            // Always return at least one entry, as raft expects
+            if !entries.is_empty() && size > max_size {
+                break;
+            }
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    fn term(&self, index: u64) -> raft::Result<u64> {
+        let snapshot = self.snapshot.get_metadata();
+        if index == snapshot.index {
+            return Ok(snapshot.term);
+        }
+        if index < self.first_index {
+            return Err(raft::Error::Store(StorageError::Compacted));
+        }
+        if index > self.last_index {
+            return Err(raft::Error::Store(StorageError::Unavailable));
+        }
+        Ok(self.entry(index)?.term)
+    }
+
+    fn first_index(&self) -> raft::Result<u64> {
+        Ok(self.first_index)
+    }
+
+    fn last_index(&self) -> raft::Result<u64> {
+        Ok(self.last_index)
+    }
+
+    // Only the snapshot taken at the last compaction is available; raft retries
+    // later if a follower needs a newer one
+    fn snapshot(&self, request_index: u64, _to: u64) -> raft::Result<Snapshot> {
+        if self.snapshot.get_metadata().index < request_index {
+            return Err(raft::Error::Store(StorageError::SnapshotTemporarilyUnavailable));
+        }
+        Ok(self.snapshot.clone())
+    }
+}
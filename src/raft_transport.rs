@@ -0,0 +1,144 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use raft::NodeId;
+use raft::prelude::Message;
+
+// Carries raft messages between the members of the cluster metadata group
+pub trait RaftTransport: Send + Sync {
+    // Hands `message` off for delivery to `message.to`. Delivery is best effort;
+    // raft resends whatever a peer does not acknowledge.
+    fn send(&self, message: Message);
+}
+
+// The receiving end of a transport: a node's raft group
+pub trait RaftReceiver: Send + Sync {
+    fn receive(&self, message: Message);
+}
+
+struct Conditions {
+    // Pairs of nodes that cannot reach each other, stored in both directions
+    cut: HashSet<(NodeId, NodeId)>,
+    delay: Duration,
+    // Fraction of messages lost in transit, from 0.0 to 1.0
+    drop_rate: f64,
+    // xorshift state deciding which messages are lost, so runs are repeatable
+    rng: u64,
+    dropped: u64,
+}
+
+// A network of raft nodes inside one process. Messages are handed straight to
+// the receiving node unless a partition, delay or drop rate says otherwise.
+pub struct InMemoryNetwork {
+    nodes: Mutex<HashMap<NodeId, Weak<dyn RaftReceiver>>>,
+    conditions: Mutex<Conditions>,
+}
+
+impl InMemoryNetwork {
+    pub fn new() -> Arc<Self> {
+        Arc::new(InMemoryNetwork {
+            nodes: Mutex::new(HashMap::new()),
+            conditions: Mutex::new(Conditions {
+                cut: HashSet::new(),
+                delay: Duration::ZERO,
+                drop_rate: 0.0,
+                rng: 0x2545f4914f6cdd1d,
+                dropped: 0,
+            }),
+        })
+    }
+
+    // Makes `receiver` reachable as `node_id`. The network does not keep the
+    // node alive; messages to a dropped node are lost.
+    pub fn register(&self, node_id: NodeId, receiver: Arc<dyn RaftReceiver>) {
+        self.nodes.lock().unwrap().insert(node_id, Arc::downgrade(&receiver));
+    }
+
+    // The transport `node_id` sends through
+    pub fn transport(self: &Arc<Self>, node_id: NodeId) -> Arc<dyn RaftTransport> {
+        Arc::new(InMemoryTransport {
+            from: node_id,
+            network: self.clone(),
+        })
+    }
+
+    // Cuts every link between a node in `side` and a node in `other`
+    pub fn partition(&self, side: &[NodeId], other: &[NodeId]) {
+        let mut conditions = self.conditions.lock().unwrap();
+        for &a in side {
+            for &b in other {
+                conditions.cut.insert((a, b));
+                conditions.cut.insert((b, a));
+            }
+        }
+    }
+
+    // Cuts `node_id` off from every other registered node
+    pub fn isolate(&self, node_id: NodeId) {
+        let others: Vec<NodeId> = self.nodes.lock().unwrap().keys().cloned().filter(|&id| id != node_id).collect();
+        self.partition(&[node_id], &others);
+    }
+
+    // Restores every link cut by `partition` or `isolate`
+    pub fn heal(&self) {
+        self.conditions.lock().unwrap().cut.clear();
+    }
+
+    // Holds back every message for `delay` before delivering it
+    pub fn set_delay(&self, delay: Duration) {
+        self.conditions.lock().unwrap().delay = delay;
+    }
+
+    pub fn set_drop_rate(&self, drop_rate: f64) {
+        self.conditions.lock().unwrap().drop_rate = drop_rate.clamp(0.0, 1.0);
+    }
+
+    // Messages lost to partitions or the drop rate so far
+    pub fn dropped(&self) -> u64 {
+        self.conditions.lock().unwrap().dropped
+    }
+
+    fn deliver(&self, from: NodeId, message: Message) {
+        let delay = {
+            let mut conditions = self.conditions.lock().unwrap();
+            let lost = conditions.cut.contains(&(from, message.to)) || conditions.roll() < conditions.drop_rate;
+            if lost {
+                conditions.dropped += 1;
+                return;
+            }
+            conditions.delay
+        };
+        let Some(receiver) = self.nodes.lock().unwrap().get(&message.to).and_then(Weak::upgrade) else {
+            return;
+        };
+        if delay.is_zero() {
+            receiver.receive(message);
+        } else {
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                receiver.receive(message);
+            });
+        }
+    }
+}
+
+impl Conditions {
+    // Uniform in [0, 1)
+    fn roll(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+struct InMemoryTransport {
+    from: NodeId,
+    network: Arc<InMemoryNetwork>,
+}
+
+impl RaftTransport for InMemoryTransport {
+    fn send(&self, message: Message) {
+        self.network.deliver(self.from, message);
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use raft::NodeId;
+use serde::{Serialize, Deserialize};
+
+// How long a queue leader waits for a follower to confirm a round of changes
+pub const REPLICATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The nodes holding a copy of a replicated queue, kept in ClusterState.
+//
+// The first replica leads: it serves publishes and consumers and ships every
+// change to the others. A follower drops out of `in_sync` when it misses a
+// change and rejoins after the leader has sent it a full copy.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicaSet {
+    pub replicas: Vec<NodeId>,
+    pub in_sync: Vec<NodeId>,
+    // Copies, counting the leader's, that must confirm a write before it succeeds
+    pub write_quorum: usize,
+}
+
+impl ReplicaSet {
+    // A replica set whose members all start out in sync
+    pub fn new(replicas: Vec<NodeId>, write_quorum: usize) -> Result<Self, String> {
+        if replicas.is_empty() {
+            return Err("a replicated queue needs at least one replica".to_string());
+        }
+        if write_quorum == 0 || write_quorum > replicas.len() {
+            return Err(format!("write quorum must be between 1 and {}, got {}", replicas.len(), write_quorum));
+        }
+        Ok(ReplicaSet {
+            in_sync: replicas.clone(),
+            replicas,
+            write_quorum,
+        })
+    }
+
+    pub fn leader(&self) -> NodeId {
+        self.replicas[0]
+    }
+
+    pub fn followers(&self) -> &[NodeId] {
+        &self.replicas[1..]
+    }
+
+    pub fn is_in_sync(&self, node_id: NodeId) -> bool {
+        self.in_sync.contains(&node_id)
+    }
+
+    // Makes an in-sync follower the leader. The old leader becomes the last
+    // follower and is out of sync until the new leader has sent it a full copy.
+    pub fn promote(&mut self, node_id: NodeId) {
+        let previous = self.leader();
+        self.replicas.retain(|&id| id != node_id && id != previous);
+        self.replicas.insert(0, node_id);
+        self.replicas.push(previous);
+        self.set_in_sync(previous, false);
+        self.set_in_sync(node_id, true);
+    }
+
+    // Marks a follower as holding, or no longer holding, every confirmed change
+    pub fn set_in_sync(&mut self, node_id: NodeId, in_sync: bool) {
+        if !self.replicas.contains(&node_id) {
+            return;
+        }
+        self.in_sync.retain(|&id| id != node_id);
+        if in_sync {
+            self.in_sync.push(node_id);
+            // Keep the replica order so every node lists them the same way
+            let replicas = &self.replicas;
+            self.in_sync.sort_by_key(|id| replicas.iter().position(|r| r == id));
+        }
+    }
+}
+
+// Picks `factor` nodes for a new replicated queue, least loaded first. Ties go
+// to the lower id so the choice does not depend on map order.
+pub fn place_replicas(node_loads: &HashMap<NodeId, usize>, nodes: &[NodeId], factor: usize) -> Result<Vec<NodeId>, String> {
+    if factor == 0 || factor > nodes.len() {
+        return Err(format!("replication factor must be between 1 and {}, got {}", nodes.len(), factor));
+    }
+    let mut candidates = nodes.to_vec();
+    candidates.sort_by_key(|id| (node_loads.get(id).copied().unwrap_or(0), *id));
+    candidates.truncate(factor);
+    Ok(candidates)
+}
+
+// One change to the persisted messages of a queue, as shipped from its leader
+// to the followers. Followers apply them in order, so their copy matches the
+// leader's record for record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplicaOp {
+    // A visible message stored under sequence number `seq`
+    Ready { seq: u64, encoded: Vec<u8> },
+    // A delayed message waiting in the timer index
+    Scheduled { due: u64, seq: u64, encoded: Vec<u8> },
+    // The delayed message of timer `(due, id)` fell due and became `seq`
+    Promote { due: u64, id: u64, seq: u64 },
+    // A message acked, expired or dead-lettered
+    Remove { seq: u64 },
+}
@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rocksdb::DB;
+
+// Milliseconds since the Unix epoch, the unit used for `deliver_at`
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+// Delayed messages of one queue, persisted under `name@timer:<due>:<id>`.
+//
+// Both numbers are zero-padded so RocksDB iterates timers in due order. A
+// timer stays in the index until it fires, at which point the queue moves the
+// message into its regular log in the same write batch.
+pub struct TimerIndex {
+    name: String,
+    timers: BTreeMap<(u64, u64), Vec<u8>>,
+}
+
+impl TimerIndex {
+    pub fn load(name: &str, db: &DB) -> Self {
+        let mut timers = BTreeMap::new();
+        let prefix = TimerIndex::prefix(name);
+        let iter = db.iterator(rocksdb::IteratorMode::From(prefix.as_bytes(), rocksdb::Direction::Forward));
+        for item in iter {
+            let (key, value) = item.unwrap();
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            if let Some(timer) = TimerIndex::parse_key(&prefix, &key) {
+                timers.insert(timer, value.to_vec());
+            }
+        }
+        TimerIndex {
+            name: name.to_string(),
+            timers,
+        }
+    }
+
+    pub fn schedule(&mut self, db: &DB, due: u64, id: u64, encoded: Vec<u8>) {
+        db.put(self.key(due, id).as_bytes(), &encoded).unwrap();
+        self.timers.insert((due, id), encoded);
+    }
+
+    // Tracks a timer whose key was already written, e.g. as part of a batch
+    pub fn insert(&mut self, due: u64, id: u64, encoded: Vec<u8>) {
+        self.timers.insert((due, id), encoded);
+    }
+
+    // Removes and returns every timer due at or before `now`, earliest first
+    pub fn take_due(&mut self, now: u64) -> Vec<((u64, u64), Vec<u8>)> {
+        let pending = self.timers.split_off(&(now + 1, 0));
+        std::mem::replace(&mut self.timers, pending).into_iter().collect()
+    }
+
+    // Stops tracking one timer whose key was already deleted
+    pub fn remove(&mut self, due: u64, id: u64) -> Option<Vec<u8>> {
+        self.timers.remove(&(due, id))
+    }
+
+    // Every pending timer as `(due, id, encoded)`, earliest first
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64, &Vec<u8>)> + '_ {
+        self.timers.iter().map(|(&(due, id), encoded)| (due, id, encoded))
+    }
+
+    // When the earliest timer fires, in Unix milliseconds
+    pub fn next_due(&self) -> Option<u64> {
+        self.timers.keys().next().map(|(due, _)| *due)
+    }
+
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+
+    // The largest timer id, so the owning queue never reuses one after a reload
+    pub fn last_id(&self) -> Option<u64> {
+        self.timers.keys().map(|(_, id)| *id).max()
+    }
+
+    pub fn key(&self, due: u64, id: u64) -> String {
+        format!("{}{:020}:{:020}", TimerIndex::prefix(&self.name), due, id)
+    }
+
+    fn prefix(name: &str) -> String {
+        format!("{}@timer:", name)
+    }
+
+    fn parse_key(prefix: &str, key: &[u8]) -> Option<(u64, u64)> {
+        let rest = std::str::from_utf8(key.strip_prefix(prefix.as_bytes())?).ok()?;
+        let (due, id) = rest.split_once(':')?;
+        if due.len() != 20 || id.len() != 20 {
+            return None;
+        }
+        Some((due.parse().ok()?, id.parse().ok()?))
+    }
+}
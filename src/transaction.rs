@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use prost::Message as ProstMessage;
+use rocksdb::DB;
+use serde::{Serialize, Deserialize};
+use crate::Message;
+use crate::proto::RapidMQMessage;
+
+// Transactions prepared on this node, persisted as JSON under `__txn:<id>` until
+// the coordinator commits or aborts them
+const PREPARED_PREFIX: &str = "__txn:";
+
+// One buffered step of a transaction
+#[derive(Clone, Debug)]
+pub enum TxOp {
+    Publish { queue_name: String, message: Message },
+    Ack { queue_name: String, delivery_tag: String },
+}
+
+impl TxOp {
+    pub fn queue_name(&self) -> &str {
+        match self {
+            TxOp::Publish { queue_name, .. } => queue_name,
+            TxOp::Ack { queue_name, .. } => queue_name,
+        }
+    }
+}
+
+// Stored form of a TxOp; messages are kept as encoded RapidMQMessage bytes
+#[derive(Serialize, Deserialize)]
+struct PreparedOp {
+    queue_name: String,
+    message: Option<Vec<u8>>,
+    delivery_tag: Option<String>,
+}
+
+impl From<&TxOp> for PreparedOp {
+    fn from(op: &TxOp) -> Self {
+        match op {
+            TxOp::Publish { queue_name, message } => PreparedOp {
+                queue_name: queue_name.clone(),
+                message: Some(RapidMQMessage::from(message.clone()).encode_to_vec()),
+                delivery_tag: None,
+            },
+            TxOp::Ack { queue_name, delivery_tag } => PreparedOp {
+                queue_name: queue_name.clone(),
+                message: None,
+                delivery_tag: Some(delivery_tag.clone()),
+            },
+        }
+    }
+}
+
+impl PreparedOp {
+    fn into_op(self) -> Option<TxOp> {
+        match (self.message, self.delivery_tag) {
+            (Some(encoded), _) => RapidMQMessage::decode(&encoded[..]).ok().map(|message| TxOp::Publish {
+                queue_name: self.queue_name,
+                message: message.into(),
+            }),
+            (None, Some(delivery_tag)) => Some(TxOp::Ack { queue_name: self.queue_name, delivery_tag }),
+            (None, None) => None,
+        }
+    }
+}
+
+// Open transactions started on this node, and transactions this node has voted
+// to commit in the first phase of a cross-node commit
+pub struct TransactionManager {
+    open: HashMap<String, Vec<TxOp>>,
+    prepared: HashMap<String, Vec<TxOp>>,
+    db: Arc<DB>,
+}
+
+impl TransactionManager {
+    pub fn load(db: Arc<DB>) -> Self {
+        let mut prepared = HashMap::new();
+        let iter = db.iterator(rocksdb::IteratorMode::From(PREPARED_PREFIX.as_bytes(), rocksdb::Direction::Forward));
+        for item in iter {
+            let (key, value) = item.unwrap();
+            let Some(id) = key.strip_prefix(PREPARED_PREFIX.as_bytes()) else {
+                break;
+            };
+            match serde_json::from_slice::<Vec<PreparedOp>>(&value) {
+                Ok(ops) => {
+                    let ops = ops.into_iter().filter_map(PreparedOp::into_op).collect();
+                    prepared.insert(String::from_utf8_lossy(id).into_owned(), ops);
+                }
+                Err(e) => eprintln!("Skipping unreadable prepared transaction: {}", e),
+            }
+        }
+        TransactionManager {
+            open: HashMap::new(),
+            prepared,
+            db,
+        }
+    }
+
+    pub fn begin(&mut self) -> String {
+        let id = uuid::Uuid::new_v4().to_string();
+        self.open.insert(id.clone(), Vec::new());
+        id
+    }
+
+    pub fn add(&mut self, id: &str, op: TxOp) -> Result<(), String> {
+        let ops = self.open.get_mut(id).ok_or_else(|| format!("Transaction '{}' not found", id))?;
+        ops.push(op);
+        Ok(())
+    }
+
+    // Ends an open transaction, returning its buffered operations
+    pub fn take(&mut self, id: &str) -> Option<Vec<TxOp>> {
+        self.open.remove(id)
+    }
+
+    // Records a yes vote. The operations are persisted so the decision can still
+    // be carried out after a restart.
+    pub fn prepare(&mut self, id: &str, ops: Vec<TxOp>) {
+        let stored: Vec<PreparedOp> = ops.iter().map(PreparedOp::from).collect();
+        self.db.put(TransactionManager::prepared_key(id).as_bytes(), serde_json::to_vec(&stored).unwrap()).unwrap();
+        self.prepared.insert(id.to_string(), ops);
+    }
+
+    // Removes a prepared transaction from memory. The caller deletes its record,
+    // together with the transaction's own writes when committing.
+    pub fn take_prepared(&mut self, id: &str) -> Option<Vec<TxOp>> {
+        self.prepared.remove(id)
+    }
+
+    // Prepared transactions still waiting for the coordinator's decision
+    pub fn in_doubt(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.prepared.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    pub fn prepared_key(id: &str) -> String {
+        format!("{}{}", PREPARED_PREFIX, id)
+    }
+}
@@ -24,19 +24,21 @@ fn test_cluster_operations() {
         // Test message publishing and consuming across nodes
         let message = Message {
             id: "1".to_string(),
-            content: "Test message".to_string(),
+            payload: b"Test message".to_vec(),
+            ..Default::default()
         };
         nodes[0].publish("test_queue", message.clone()).await.unwrap();
         tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
 
         let consumed = nodes[1].consume("test_queue").await.unwrap().unwrap();
         assert_eq!(consumed.id, message.id);
-        assert_eq!(consumed.content, message.content);
+        assert_eq!(consumed.payload, message.payload);
 
         // Test AI-based message prioritization
         let high_priority_message = Message {
             id: "2".to_string(),
-            content: "High priority message".to_string(),
+            payload: b"High priority message".to_vec(),
+            ..Default::default()
         };
         nodes[0].publish_with_priority("test_queue", high_priority_message.clone()).await.unwrap();
         let consumed = nodes[2].consume("test_queue").await.unwrap().unwrap();
@@ -0,0 +1,261 @@
+use rapidmq::{Message, PublishOutcome, RapidMQ};
+use raft::NodeId;
+use std::net::{SocketAddr, TcpListener};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+// Picks a loopback port that is free right now
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+// Polls `condition` until it holds, failing the test after a few election timeouts
+async fn wait_until(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+// Starts two nodes in this process that know each other's gRPC address and
+// waits until they have elected a leader. Each test uses its own ids, since a
+// node's database can only be opened once.
+async fn start_pair(first_id: u64) -> ((RapidMQ, NodeId), (RapidMQ, NodeId)) {
+    let (id1, id2) = (NodeId::from(first_id), NodeId::from(first_id + 1));
+    let (addr1, addr2) = (free_addr(), free_addr());
+    let node1 = RapidMQ::new(id1, vec![id2]);
+    let node2 = RapidMQ::new(id2, vec![id1]);
+    for node in [&node1, &node2] {
+        node.set_dedup_window(Duration::ZERO);
+        node.add_node(id1, format!("http://{}", addr1));
+        node.add_node(id2, format!("http://{}", addr2));
+    }
+    for (node, addr) in [(node1.clone(), addr1), (node2.clone(), addr2)] {
+        tokio::spawn(async move {
+            node.serve_rpc(addr).await.unwrap();
+        });
+    }
+    wait_until("a leader is elected", || {
+        let leader = node1.cluster_leader();
+        leader.is_some() && leader == node2.cluster_leader()
+    }).await;
+    ((node1, id1), (node2, id2))
+}
+
+fn message(id: &str) -> Message {
+    Message {
+        id: id.to_string(),
+        payload: b"Test message".to_vec(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn message_published_on_one_node_is_consumed_from_another() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let ((node1, id1), (node2, _)) = start_pair(101).await;
+        let queue_name = format!("cross_node_{}", uuid::Uuid::new_v4());
+        node1.create_queue_on(&queue_name, id1);
+        wait_until("both nodes apply the placement", || {
+            node1.queue_node(&queue_name) == Some(id1) && node2.queue_node(&queue_name) == Some(id1)
+        }).await;
+
+        // Published through node 2, stored on node 1, consumed through node 2
+        node2.publish(&queue_name, message("forwarded")).await;
+        let delivery = node2.consume(&queue_name).await.unwrap();
+        assert_eq!(delivery.message.id, "forwarded");
+        assert_eq!(delivery.delivery_count, 1);
+        assert!(node2.ack(&queue_name, &delivery.tag).await);
+
+        // Acked on the owning node, so nothing is left there and the tag is spent
+        assert!(node1.consume(&queue_name).await.is_none());
+        assert!(!node2.ack(&queue_name, &delivery.tag).await);
+        assert!(!node2.nack(&queue_name, &delivery.tag).await);
+    });
+}
+
+#[test]
+fn remote_consume_waits_on_the_owning_node() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let ((node1, _), (node2, id2)) = start_pair(111).await;
+        let queue_name = format!("cross_node_wait_{}", uuid::Uuid::new_v4());
+        // Proposed by the node that does not own the queue
+        node1.create_queue_on(&queue_name, id2);
+        wait_until("node 2 learns the placement", || node2.queue_node(&queue_name) == Some(id2)).await;
+
+        let publisher = node2.clone();
+        let target = queue_name.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            publisher.publish(&target, message("late")).await;
+        });
+
+        let delivery = node1.consume_wait(&queue_name, Duration::from_secs(10)).await.unwrap();
+        assert_eq!(delivery.message.id, "late");
+
+        // A nack through node 1 makes it visible again on node 2
+        assert!(node1.nack(&queue_name, &delivery.tag).await);
+        let redelivery = node2.consume(&queue_name).await.unwrap();
+        assert_eq!(redelivery.message.id, "late");
+        assert_eq!(redelivery.delivery_count, 2);
+    });
+}
+
+#[test]
+fn metadata_changes_apply_on_every_node() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let ((node1, _), (node2, id2)) = start_pair(121).await;
+        let source = format!("replicated_source_{}", uuid::Uuid::new_v4());
+        let copy = format!("replicated_copy_{}", uuid::Uuid::new_v4());
+        node2.create_queue_on(&source, id2);
+        node2.create_queue_on(&copy, id2);
+        wait_until("node 1 applies both placements", || {
+            node1.queue_node(&source) == Some(id2) && node1.queue_node(&copy) == Some(id2)
+        }).await;
+
+        assert!(node1.subscribe(&source, &copy).unwrap());
+        wait_until("both nodes apply the subscription", || {
+            node1.subscribers_of(&source) == vec![copy.clone()] && node2.subscribers_of(&source) == vec![copy.clone()]
+        }).await;
+
+        // Fan-out follows the replicated subscription whichever node publishes
+        node1.publish(&source, message("fanned")).await;
+        assert_eq!(node2.consume(&copy).await.unwrap().message.id, "fanned");
+    });
+}
+
+#[test]
+fn replicated_queue_keeps_a_copy_on_the_follower() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let ((node1, id1), (node2, id2)) = start_pair(131).await;
+        let queue_name = format!("replicated_{}", uuid::Uuid::new_v4());
+        assert_eq!(node2.create_replicated_queue(&queue_name, 2, 2).unwrap(), vec![id1, id2]);
+        wait_until("both nodes apply the replica set", || {
+            node1.replica_set(&queue_name).is_some() && node2.replica_set(&queue_name).is_some()
+        }).await;
+
+        // Forwarded to the leader, which only reports success once node 2 has it
+        assert_eq!(node2.publish(&queue_name, message("copied")).await, PublishOutcome::Published);
+        let copies: Vec<String> = node2.peek_local(&queue_name, 10).into_iter().map(|m| m.id).collect();
+        assert_eq!(copies, vec!["copied"]);
+
+        // The ack reaches the follower before it returns
+        let delivery = node2.consume(&queue_name).await.unwrap();
+        assert!(node2.ack(&queue_name, &delivery.tag).await);
+        assert!(node2.peek_local(&queue_name, 10).is_empty());
+        assert_eq!(node2.replica_set(&queue_name).unwrap().in_sync, vec![id1, id2]);
+    });
+}
+
+#[test]
+fn publish_reports_a_missed_write_quorum() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        // Three voters, of which the third never starts
+        let ids: Vec<NodeId> = (141..144).map(NodeId::from).collect();
+        let addrs: Vec<SocketAddr> = ids.iter().map(|_| free_addr()).collect();
+        let nodes: Vec<RapidMQ> = ids[..2].iter()
+            .map(|&id| RapidMQ::new(id, ids.iter().cloned().filter(|&peer| peer != id).collect()))
+            .collect();
+        for (node, addr) in nodes.iter().zip(&addrs) {
+            for (&id, peer_addr) in ids.iter().zip(&addrs) {
+                node.add_node(id, format!("http://{}", peer_addr));
+            }
+            let (node, addr) = (node.clone(), *addr);
+            tokio::spawn(async move {
+                node.serve_rpc(addr).await.unwrap();
+            });
+        }
+        wait_until("a leader is elected", || {
+            let leader = nodes[0].cluster_leader();
+            leader.is_some() && leader == nodes[1].cluster_leader()
+        }).await;
+
+        let strict = format!("strict_{}", uuid::Uuid::new_v4());
+        let lenient = format!("lenient_{}", uuid::Uuid::new_v4());
+        assert_eq!(nodes[0].create_replicated_queue(&strict, 3, 3).unwrap(), ids);
+        wait_until("the first placement applies", || nodes[1].replica_set(&strict).is_some()).await;
+        nodes[0].create_replicated_queue(&lenient, 3, 2).unwrap();
+        wait_until("both nodes apply the placements", || {
+            nodes.iter().all(|node| node.replica_set(&strict).is_some() && node.replica_set(&lenient).is_some())
+        }).await;
+
+        // Node 142 confirms, node 143 cannot, so only the lenient queue has its quorum
+        assert_eq!(nodes[1].publish(&strict, message("short")).await, PublishOutcome::Unreplicated);
+        assert_eq!(nodes[1].publish(&lenient, message("enough")).await, PublishOutcome::Published);
+        let copies: Vec<String> = nodes[1].peek_local(&strict, 10).into_iter().map(|m| m.id).collect();
+        assert_eq!(copies, vec!["short"]);
+
+        // The leader reports the missing follower as out of sync
+        wait_until("node 143 leaves the in-sync replicas", || {
+            nodes.iter().all(|node| node.replica_set(&strict).unwrap().in_sync == ids[..2])
+        }).await;
+
+        // Transactions on the leader are held to the same quorums
+        let tx = nodes[0].begin_transaction();
+        nodes[0].transactional_publish(&tx, &strict, message("tx-short")).unwrap();
+        assert!(nodes[0].commit_transaction(&tx).await.is_err());
+        let tx = nodes[0].begin_transaction();
+        nodes[0].transactional_publish(&tx, &lenient, message("tx-enough")).unwrap();
+        assert_eq!(nodes[0].commit_transaction(&tx).await, Ok(()));
+        let copies: Vec<String> = nodes[1].peek_local(&lenient, 10).into_iter().map(|m| m.id).collect();
+        assert_eq!(copies, vec!["enough", "tx-enough"]);
+
+        // A plain queue on the missing node has no replica to fall back on
+        let stranded = format!("stranded_{}", uuid::Uuid::new_v4());
+        nodes[0].create_queue_on(&stranded, ids[2]);
+        wait_until("the placement applies", || nodes[1].queue_node(&stranded) == Some(ids[2])).await;
+        assert_eq!(nodes[1].publish(&stranded, message("lost")).await, PublishOutcome::Unavailable);
+    });
+}
+
+#[test]
+fn replica_takes_over_a_queue_whose_leader_is_down() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        // Three voters, of which the first never starts. Every node carries the
+        // same load, so the dead node is placed as the queue's leader.
+        let ids: Vec<NodeId> = (151..154).map(NodeId::from).collect();
+        let addrs: Vec<SocketAddr> = ids.iter().map(|_| free_addr()).collect();
+        let nodes: Vec<RapidMQ> = ids[1..].iter()
+            .map(|&id| RapidMQ::new(id, ids.iter().cloned().filter(|&peer| peer != id).collect()))
+            .collect();
+        for (node, addr) in nodes.iter().zip(&addrs[1..]) {
+            for (&id, peer_addr) in ids.iter().zip(&addrs) {
+                node.add_node(id, format!("http://{}", peer_addr));
+            }
+            let (node, addr) = (node.clone(), *addr);
+            tokio::spawn(async move {
+                node.serve_rpc(addr).await.unwrap();
+            });
+        }
+        wait_until("a leader is elected", || {
+            let leader = nodes[0].cluster_leader();
+            leader.is_some() && leader == nodes[1].cluster_leader()
+        }).await;
+
+        let queue_name = format!("failover_{}", uuid::Uuid::new_v4());
+        assert_eq!(nodes[1].create_replicated_queue(&queue_name, 3, 2).unwrap(), ids);
+        wait_until("both nodes apply the placement", || {
+            nodes.iter().all(|node| node.queue_node(&queue_name) == Some(ids[0]))
+        }).await;
+
+        // The publish keeps retrying until the first in-sync follower takes over
+        assert_eq!(nodes[1].publish(&queue_name, message("failed_over")).await, PublishOutcome::Published);
+        for node in &nodes {
+            assert_eq!(node.queue_node(&queue_name), Some(ids[1]));
+        }
+        let replica_set = nodes[1].replica_set(&queue_name).unwrap();
+        assert_eq!(replica_set.replicas, vec![ids[1], ids[2], ids[0]]);
+        assert!(!replica_set.is_in_sync(ids[0]));
+
+        let delivery = nodes[1].consume(&queue_name).await.unwrap();
+        assert_eq!(delivery.message.id, "failed_over");
+        assert!(nodes[1].ack(&queue_name, &delivery.tag).await);
+    });
+}
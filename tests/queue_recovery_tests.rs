@@ -0,0 +1,441 @@
+use rapidmq::{CompactionPolicy, Message, PublishOutcome, Queue, RapidMQ, RetentionPolicy, StartOffset, Stream};
+use rapidmq::dead_letter::DeadLetterPolicy;
+use rapidmq::dedup::{dedup_key, DedupIndex, DEFAULT_DEDUP_WINDOW};
+use rapidmq::exchange::{Binding, ExchangeKind};
+use rapidmq::priority::PriorityMode;
+use rapidmq::raft_storage::RaftStorage;
+use rapidmq::scheduler::now_millis;
+use rapidmq::transaction::{TransactionManager, TxOp};
+use raft::prelude::{Entry, HardState};
+use raft::{GetEntriesContext, NodeId, Storage};
+use rocksdb::{Options, DB};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+fn db_path() -> PathBuf {
+    std::env::temp_dir().join(format!("rapidmq_recovery_{}", uuid::Uuid::new_v4()))
+}
+
+fn open_db(path: &PathBuf) -> Arc<DB> {
+    let mut opts = Options::default();
+    opts.create_if_missing(true);
+    Arc::new(DB::open(&opts, path).unwrap())
+}
+
+fn message(i: usize) -> Message {
+    Message {
+        id: i.to_string(),
+        payload: format!("Test message {}", i).into_bytes(),
+        ..Default::default()
+    }
+}
+
+fn drain(queue: &mut Queue) -> Vec<String> {
+    let mut ids = Vec::new();
+    while let Some(delivery) = queue.dequeue() {
+        ids.push(delivery.message.id.clone());
+        queue.ack(&delivery.tag);
+    }
+    ids
+}
+
+#[test]
+fn test_acked_messages_are_not_resurrected() {
+    let path = db_path();
+    {
+        let db = open_db(&path);
+        let mut queue = Queue::new("orders", db);
+        for i in 0..5 {
+            queue.enqueue(message(i));
+        }
+        for _ in 0..3 {
+            let delivery = queue.dequeue().unwrap();
+            assert!(queue.ack(&delivery.tag));
+        }
+        // Dropping the queue and the DB here simulates the process dying
+    }
+
+    let db = open_db(&path);
+    let mut queue = Queue::new("orders", db);
+    assert_eq!(drain(&mut queue), vec!["3", "4"]);
+    let _ = DB::destroy(&Options::default(), &path);
+}
+
+#[test]
+fn test_unacked_messages_are_redelivered_in_order() {
+    let path = db_path();
+    {
+        let db = open_db(&path);
+        let mut queue = Queue::new("orders", db);
+        for i in 0..4 {
+            queue.enqueue(message(i));
+        }
+        // Delivered but never acked before the crash
+        queue.dequeue().unwrap();
+        queue.dequeue().unwrap();
+    }
+
+    let db = open_db(&path);
+    let mut queue = Queue::new("orders", db);
+    assert_eq!(drain(&mut queue), vec!["0", "1", "2", "3"]);
+    let _ = DB::destroy(&Options::default(), &path);
+}
+
+#[test]
+fn test_reload_preserves_publish_order() {
+    let path = db_path();
+    {
+        let db = open_db(&path);
+        let mut queue = Queue::new("orders", db);
+        // More than ten messages so lexicographic and numeric key order would differ without padding
+        for i in 0..25 {
+            queue.enqueue(message(i));
+        }
+    }
+
+    let db = open_db(&path);
+    let mut queue = Queue::new("orders", db);
+    let expected: Vec<String> = (0..25).map(|i| i.to_string()).collect();
+    assert_eq!(drain(&mut queue), expected);
+    let _ = DB::destroy(&Options::default(), &path);
+}
+
+#[test]
+fn test_sequence_continues_after_restart() {
+    let path = db_path();
+    {
+        let db = open_db(&path);
+        let mut queue = Queue::new("orders", db);
+        queue.enqueue(message(0));
+        queue.enqueue(message(1));
+    }
+    {
+        let db = open_db(&path);
+        let mut queue = Queue::new("orders", db);
+        let delivery = queue.dequeue().unwrap();
+        queue.ack(&delivery.tag);
+        queue.enqueue(message(2));
+    }
+
+    let db = open_db(&path);
+    let mut queue = Queue::new("orders", db);
+    assert_eq!(drain(&mut queue), vec!["1", "2"]);
+    let _ = DB::destroy(&Options::default(), &path);
+}
+
+#[test]
+fn test_queue_config_survives_restart() {
+    let path = db_path();
+    {
+        let db = open_db(&path);
+        let mut queue = Queue::new("orders", db);
+        queue.set_priority_mode(PriorityMode::Strict);
+        queue.set_dead_letter_policy(Some(DeadLetterPolicy::new(3, "orders_dlq")));
+        queue.enqueue(Message { priority: 1, ..message(0) });
+        queue.enqueue(Message { priority: 9, ..message(1) });
+        // Set last so it does not apply to the messages above
+        queue.set_default_ttl(Some(std::time::Duration::from_millis(50)));
+    }
+
+    let db = open_db(&path);
+    let mut queue = Queue::new("orders", db);
+    assert_eq!(queue.priority_mode(), PriorityMode::Strict);
+    assert_eq!(queue.dead_letter_policy().map(|policy| policy.max_deliveries), Some(3));
+    assert_eq!(drain(&mut queue), vec!["1", "0"]);
+    // Published after the restart without an expiry of its own, so the default applies
+    queue.enqueue(message(2));
+    std::thread::sleep(std::time::Duration::from_millis(80));
+    assert!(queue.dequeue().is_none());
+    let _ = DB::destroy(&Options::default(), &path);
+}
+
+#[test]
+fn test_reload_is_bounded_to_own_queue() {
+    let path = db_path();
+    {
+        let db = open_db(&path);
+        let mut orders = Queue::new("orders", db.clone());
+        let mut orders_eu = Queue::new("orders:eu", db.clone());
+        let mut payments = Queue::new("payments", db);
+        orders.enqueue(message(0));
+        orders_eu.enqueue(message(1));
+        payments.enqueue(message(2));
+    }
+
+    let db = open_db(&path);
+    let mut orders = Queue::new("orders", db.clone());
+    let mut orders_eu = Queue::new("orders:eu", db.clone());
+    let mut payments = Queue::new("payments", db);
+    assert_eq!(drain(&mut orders), vec!["0"]);
+    assert_eq!(drain(&mut orders_eu), vec!["1"]);
+    assert_eq!(drain(&mut payments), vec!["2"]);
+    let _ = DB::destroy(&Options::default(), &path);
+}
+
+#[test]
+fn test_repeated_crashes_mid_stream() {
+    let path = db_path();
+    let mut consumed = Vec::new();
+    for round in 0..3 {
+        let db = open_db(&path);
+        let mut queue = Queue::new("stream", db);
+        for i in 0..4 {
+            queue.enqueue(message(round * 4 + i));
+        }
+        // Ack two, leave one in flight, then "crash"
+        for _ in 0..2 {
+            let delivery = queue.dequeue().unwrap();
+            consumed.push(delivery.message.id.clone());
+            queue.ack(&delivery.tag);
+        }
+        queue.dequeue().unwrap();
+    }
+
+    let db = open_db(&path);
+    let mut queue = Queue::new("stream", db);
+    consumed.extend(drain(&mut queue));
+    let expected: Vec<String> = (0..12).map(|i| i.to_string()).collect();
+    assert_eq!(consumed, expected);
+    let _ = DB::destroy(&Options::default(), &path);
+}
+
+#[test]
+fn test_delayed_messages_survive_restart() {
+    let path = db_path();
+    let due = now_millis() + 100;
+    {
+        let db = open_db(&path);
+        let mut queue = Queue::new("orders", db);
+        queue.enqueue(Message {
+            deliver_at: Some(due),
+            ..message(0)
+        });
+        queue.enqueue(message(1));
+        assert_eq!(queue.scheduled_count(), 1);
+    }
+
+    let db = open_db(&path);
+    let mut queue = Queue::new("orders", db);
+    assert_eq!(queue.scheduled_count(), 1);
+    assert_eq!(drain(&mut queue), vec!["1"]);
+
+    std::thread::sleep(std::time::Duration::from_millis(due.saturating_sub(now_millis()) + 10));
+    assert_eq!(drain(&mut queue), vec!["0"]);
+    assert_eq!(queue.scheduled_count(), 0);
+    let _ = DB::destroy(&Options::default(), &path);
+}
+
+#[test]
+fn test_fired_timers_are_not_replayed() {
+    let path = db_path();
+    {
+        let db = open_db(&path);
+        let mut queue = Queue::new("orders", db);
+        queue.enqueue(Message {
+            deliver_at: Some(now_millis() + 20),
+            ..message(0)
+        });
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        // Promoted into the log but never acked
+        queue.dequeue().unwrap();
+    }
+
+    let db = open_db(&path);
+    let mut queue = Queue::new("orders", db);
+    assert_eq!(queue.scheduled_count(), 0);
+    assert_eq!(drain(&mut queue), vec!["0"]);
+    let _ = DB::destroy(&Options::default(), &path);
+}
+
+#[test]
+fn test_exchange_bindings_survive_restart() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    // A node's database can only be opened once, so no other test uses this id
+    let node_id = NodeId::from(172u64);
+    let (exchange, queue_name) = (format!("orders_{}", uuid::Uuid::new_v4()), format!("eu_orders_{}", uuid::Uuid::new_v4()));
+    rt.block_on(async {
+        let mq = RapidMQ::new(node_id, vec![]);
+        mq.create_queue(&queue_name);
+        mq.declare_exchange(&exchange, ExchangeKind::Topic).unwrap();
+        assert!(mq.bind_queue(&exchange, Binding::new(&queue_name, "orders.eu.#")).unwrap());
+        assert!(mq.bind_queue(&exchange, Binding::new("stale", "orders.#")).unwrap());
+        assert!(mq.unbind_queue(&exchange, &Binding::new("stale", "orders.#")).unwrap());
+    });
+
+    // Exchanges are restored with the saved cluster state
+    rt.block_on(async {
+        let mq = RapidMQ::new(node_id, vec![]);
+        assert_eq!(mq.publish_to_exchange(&exchange, "orders.eu.created", message(1)).await, Ok(1));
+        assert_eq!(mq.consume(&queue_name).await.unwrap().message.id, "1");
+    });
+}
+
+fn entry(index: u64, term: u64) -> Entry {
+    let mut entry = Entry::default();
+    entry.index = index;
+    entry.term = term;
+    entry.data = format!("command {}", index).into_bytes().into();
+    entry
+}
+
+#[test]
+fn test_raft_log_survives_restart() {
+    let path = db_path();
+    {
+        let db = open_db(&path);
+        let mut storage = RaftStorage::open(db);
+        assert!(storage.is_empty());
+        storage.bootstrap(vec![1, 2, 3]);
+        storage.append(&(1..=5).map(|i| entry(i, 1)).collect::<Vec<_>>());
+        // A new leader overwrites the uncommitted tail
+        storage.append(&[entry(4, 2)]);
+        let mut hard_state = HardState::default();
+        hard_state.term = 2;
+        hard_state.vote = 2;
+        hard_state.commit = 3;
+        storage.set_hard_state(hard_state);
+        storage.compact(2, b"state at 2".to_vec()).unwrap();
+    }
+
+    let db = open_db(&path);
+    let storage = RaftStorage::open(db);
+    assert!(!storage.is_empty());
+    let state = storage.initial_state().unwrap();
+    assert_eq!(state.hard_state.commit, 3);
+    assert_eq!(state.hard_state.vote, 2);
+    assert_eq!(state.conf_state.voters, vec![1, 2, 3]);
+    assert_eq!(storage.first_index().unwrap(), 3);
+    assert_eq!(storage.last_index().unwrap(), 4);
+    assert_eq!(storage.term(2).unwrap(), 1);
+    assert_eq!(storage.term(4).unwrap(), 2);
+    assert!(storage.entries(1, 3, None, GetEntriesContext::empty(false)).is_err());
+    let entries = storage.entries(3, 5, None, GetEntriesContext::empty(false)).unwrap();
+    assert_eq!(entries.iter().map(|e| (e.index, e.term)).collect::<Vec<_>>(), vec![(3, 1), (4, 2)]);
+    let snapshot = storage.snapshot(2, 2).unwrap();
+    assert_eq!(&snapshot.data[..], b"state at 2");
+    let _ = DB::destroy(&Options::default(), &path);
+}
+
+#[test]
+fn test_stream_offsets_survive_restart() {
+    let path = db_path();
+    {
+        let db = open_db(&path);
+        let mut stream = Stream::open("clicks", db);
+        for i in 0..3 {
+            assert_eq!(stream.append(message(i)), i as u64);
+        }
+        // Retention empties the stream; its offsets must still not be reused
+        stream.set_retention(RetentionPolicy { max_bytes: Some(0), max_age_secs: None });
+        assert_eq!(stream.enforce_retention(now_millis()), 3);
+    }
+
+    let db = open_db(&path);
+    let mut stream = Stream::open("clicks", db);
+    assert_eq!(stream.retention().max_bytes, Some(0));
+    assert_eq!(stream.resolve(StartOffset::Earliest), 3);
+    stream.set_retention(RetentionPolicy::default());
+    assert_eq!(stream.append(message(3)), 3);
+    let ids: Vec<String> = stream.read(0, 10).into_iter().map(|r| r.message.id).collect();
+    assert_eq!(ids, vec!["3"]);
+    let _ = DB::destroy(&Options::default(), &path);
+}
+
+#[test]
+fn test_compaction_survives_restart() {
+    let path = db_path();
+    {
+        let db = open_db(&path);
+        let mut stream = Stream::open("device_config", db);
+        stream.set_compaction(Some(CompactionPolicy::default()));
+        for i in 0..4 {
+            stream.append(Message { key: Some(format!("device-{}", i % 2)), ..message(i) });
+        }
+        // Supersedes offsets 1 and 3
+        stream.append(Message { key: Some("device-1".to_string()), ..message(4) });
+        assert_eq!(stream.compact(now_millis()), 3);
+    }
+
+    let db = open_db(&path);
+    let mut stream = Stream::open("device_config", db);
+    assert!(stream.compaction().is_some());
+    let ids: Vec<String> = stream.read(0, 10).into_iter().map(|r| r.message.id).collect();
+    assert_eq!(ids, vec!["2", "4"]);
+    assert_eq!(stream.append(message(5)), 5);
+    let _ = DB::destroy(&Options::default(), &path);
+}
+
+#[test]
+fn test_dedup_window_survives_restart() {
+    let path = db_path();
+    let now = now_millis();
+    let key = dedup_key("payments", &message(1)).unwrap();
+    {
+        let db = open_db(&path);
+        let mut dedup = DedupIndex::load(db);
+        assert!(!dedup.is_duplicate(&key, now));
+        dedup.record(&key, now);
+    }
+
+    let db = open_db(&path);
+    let mut dedup = DedupIndex::load(db);
+    assert!(dedup.is_duplicate(&key, now + 1));
+    assert!(!dedup.is_duplicate(&dedup_key("payments", &message(2)).unwrap(), now + 1));
+    // Once the window has passed the id may be published again
+    let after_window = now + DEFAULT_DEDUP_WINDOW.as_millis() as u64;
+    assert!(!dedup.is_duplicate(&key, after_window));
+    assert_eq!(dedup.sweep(after_window), 1);
+    assert!(dedup.is_empty());
+    let _ = DB::destroy(&Options::default(), &path);
+}
+
+#[test]
+fn test_prepared_transactions_survive_restart() {
+    let path = db_path();
+    {
+        let db = open_db(&path);
+        let mut transactions = TransactionManager::load(db);
+        let ops = vec![
+            TxOp::Publish { queue_name: "invoices".to_string(), message: message(1) },
+            TxOp::Ack { queue_name: "orders".to_string(), delivery_tag: "tag-1".to_string() },
+        ];
+        transactions.prepare("tx-1", ops);
+        // Open transactions are never persisted
+        let open = transactions.begin();
+        transactions.add(&open, TxOp::Publish { queue_name: "invoices".to_string(), message: message(2) }).unwrap();
+    }
+
+    let db = open_db(&path);
+    let mut transactions = TransactionManager::load(db);
+    assert_eq!(transactions.in_doubt(), vec!["tx-1"]);
+    let ops = transactions.take_prepared("tx-1").unwrap();
+    match &ops[..] {
+        [TxOp::Publish { queue_name, message }, TxOp::Ack { delivery_tag, .. }] => {
+            assert_eq!(queue_name, "invoices");
+            assert_eq!(message.payload, b"Test message 1");
+            assert_eq!(delivery_tag, "tag-1");
+        }
+        other => panic!("unexpected operations: {:?}", other),
+    }
+    let _ = DB::destroy(&Options::default(), &path);
+}
+
+#[test]
+fn test_owned_queues_reopen_after_restart() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    // A node's database can only be opened once, so no other test uses this id
+    let node_id = NodeId::from(171u64);
+    let queue_name = format!("reopened_{}", uuid::Uuid::new_v4());
+    rt.block_on(async {
+        let mq = RapidMQ::new(node_id, vec![]);
+        mq.create_queue(&queue_name);
+        assert_eq!(mq.queue_node(&queue_name), Some(node_id));
+        assert_eq!(mq.publish(&queue_name, message(1)).await, PublishOutcome::Published);
+    });
+
+    // The placement is in the saved cluster state, not replayed from the log
+    rt.block_on(async {
+        let mq = RapidMQ::new(node_id, vec![]);
+        assert_eq!(mq.consume(&queue_name).await.unwrap().message.id, "1");
+    });
+}
@@ -0,0 +1,188 @@
+use rapidmq::{Message, RapidMQ};
+use rapidmq::exchange::{Binding, ExchangeKind};
+use rapidmq::raft_transport::InMemoryNetwork;
+use raft::NodeId;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+// Polls `condition` until it holds, failing the test after a few election timeouts
+async fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(15);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+// Starts three nodes on one in-process network and waits for a leader. Each test
+// uses its own ids, since a node's database can only be opened once.
+async fn start_cluster(first_id: u64) -> (Arc<InMemoryNetwork>, Vec<RapidMQ>, Vec<NodeId>) {
+    let ids: Vec<NodeId> = (first_id..first_id + 3).map(NodeId::from).collect();
+    let network = InMemoryNetwork::new();
+    let nodes: Vec<RapidMQ> = ids.iter()
+        .map(|&id| RapidMQ::new(id, ids.iter().cloned().filter(|&peer| peer != id).collect()))
+        .collect();
+    for node in &nodes {
+        node.join_network(&network);
+    }
+    wait_until("a leader is elected", || leader_of(&nodes).is_some()).await;
+    (network, nodes, ids)
+}
+
+// The leader every node agrees on, if they do
+fn leader_of(nodes: &[RapidMQ]) -> Option<NodeId> {
+    let leader = nodes[0].cluster_leader()?;
+    nodes.iter().all(|node| node.cluster_leader() == Some(leader)).then_some(leader)
+}
+
+fn unique(prefix: &str) -> String {
+    format!("{}_{}", prefix, uuid::Uuid::new_v4())
+}
+
+#[test]
+fn metadata_replicates_to_every_node() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (_network, nodes, ids) = start_cluster(201).await;
+        let leader = leader_of(&nodes).unwrap();
+        let follower = ids.iter().position(|&id| id != leader).unwrap();
+        let (source, copy) = (unique("source"), unique("copy"));
+
+        // Proposed on a follower, which forwards it to the leader
+        assert!(nodes[follower].subscribe(&source, &copy).unwrap());
+        nodes[follower].create_queue_on(&copy, ids[2]);
+        wait_until("every node applies both commands", || {
+            nodes.iter().all(|node| node.subscribers_of(&source) == vec![copy.clone()] && node.queue_node(&copy) == Some(ids[2]))
+        }).await;
+    });
+}
+
+#[test]
+fn minority_side_of_a_partition_cannot_commit() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (network, nodes, ids) = start_cluster(211).await;
+        let old_leader = leader_of(&nodes).unwrap();
+        let cut_off = ids.iter().position(|&id| id == old_leader).unwrap();
+        let majority: Vec<NodeId> = ids.iter().cloned().filter(|&id| id != old_leader).collect();
+        network.partition(&[old_leader], &majority);
+
+        // The old leader still accepts the proposal but can never commit it
+        let (lost, kept) = (unique("lost"), unique("kept"));
+        assert!(nodes[cut_off].subscribe(&lost, "nobody").unwrap());
+
+        // The majority elects a new leader and keeps going
+        wait_until("the majority elects a new leader", || {
+            let leader = nodes[(cut_off + 1) % 3].cluster_leader();
+            leader.is_some() && leader != Some(old_leader) && leader == nodes[(cut_off + 2) % 3].cluster_leader()
+        }).await;
+        let survivor = &nodes[(cut_off + 1) % 3];
+        assert!(survivor.subscribe(&kept, "somebody").unwrap());
+        wait_until("the majority applies its command", || {
+            nodes.iter().enumerate()
+                .filter(|(i, _)| *i != cut_off)
+                .all(|(_, node)| node.subscribers_of(&kept) == vec!["somebody".to_string()])
+        }).await;
+        assert!(nodes[cut_off].subscribers_of(&kept).is_empty());
+        assert!(network.dropped() > 0);
+
+        // After healing, the old leader steps down, catches up and discards its
+        // uncommitted entry
+        network.heal();
+        wait_until("the old leader catches up", || {
+            nodes[cut_off].subscribers_of(&kept) == vec!["somebody".to_string()]
+        }).await;
+        assert_ne!(nodes[cut_off].cluster_leader(), Some(old_leader));
+        for node in &nodes {
+            assert!(node.subscribers_of(&lost).is_empty());
+        }
+    });
+}
+
+#[test]
+fn cluster_converges_despite_drops_and_delays() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (network, nodes, _) = start_cluster(221).await;
+        network.set_delay(Duration::from_millis(20));
+        network.set_drop_rate(0.3);
+
+        // Proposals forwarded over the lossy network can vanish, so each node
+        // keeps proposing its subscription until it sees it applied
+        let source = unique("lossy");
+        let subscribers: Vec<String> = (0..nodes.len()).map(|i| format!("subscriber_{}", i)).collect();
+        wait_until("every node applies every subscription", || {
+            for (node, subscriber) in nodes.iter().zip(&subscribers) {
+                if !node.subscribers_of(&source).contains(subscriber) {
+                    let _ = node.subscribe(&source, subscriber);
+                }
+            }
+            nodes.iter().all(|node| {
+                let mut applied = node.subscribers_of(&source);
+                applied.sort();
+                applied == subscribers
+            })
+        }).await;
+        assert!(network.dropped() > 0);
+    });
+}
+
+#[test]
+fn consumer_groups_are_shared_by_every_node() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (_network, nodes, _) = start_cluster(231).await;
+        let (queue_name, group) = (unique("partitioned"), unique("group"));
+        nodes[0].create_partitioned_queue(&queue_name, 2);
+        wait_until("every node applies the partitions", || {
+            nodes.iter().all(|node| node.partition_count(&queue_name) == Some(2))
+        }).await;
+
+        // Members joining through different nodes end up in one group
+        let first = nodes[1].join_group(&group, &queue_name, Some("a".to_string())).await.unwrap();
+        assert_eq!(first.partitions, vec![0, 1]);
+        let second = nodes[2].join_group(&group, &queue_name, Some("b".to_string())).await.unwrap();
+        assert_eq!(second.partitions, vec![1]);
+        wait_until("every node applies both joins", || {
+            nodes.iter().all(|node| node.describe_group(&group).map_or(false, |description| description.assignments.len() == 2))
+        }).await;
+        assert_eq!(nodes[0].heartbeat(&group, "a").unwrap().partitions, vec![0]);
+
+        assert!(nodes[0].leave_group(&group, "b").unwrap());
+        wait_until("every node applies the leave", || {
+            nodes.iter().all(|node| node.heartbeat(&group, "a").map_or(false, |assignment| assignment.partitions == vec![0, 1]))
+        }).await;
+    });
+}
+
+#[test]
+fn exchanges_are_shared_by_every_node() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (_network, nodes, ids) = start_cluster(241).await;
+        let (exchange, queue_name) = (unique("orders"), unique("eu_orders"));
+        // The in-memory network only carries raft messages, so the queue lives
+        // on the node that publishes to it
+        nodes[0].create_queue_on(&queue_name, ids[2]);
+        nodes[0].declare_exchange(&exchange, ExchangeKind::Topic).unwrap();
+        wait_until("every node applies the exchange", || {
+            nodes.iter().all(|node| node.list_exchanges().iter().any(|e| e.name == exchange))
+        }).await;
+
+        // A binding made through one node routes messages published through another
+        assert!(nodes[1].bind_queue(&exchange, Binding::new(&queue_name, "orders.eu.*")).unwrap());
+        wait_until("every node applies the binding", || {
+            nodes.iter().all(|node| node.list_exchanges().iter().any(|e| e.name == exchange && e.bindings.len() == 1))
+        }).await;
+        let message = Message { id: "1".to_string(), payload: b"order".to_vec(), ..Default::default() };
+        wait_until("the queue opens on its node", || nodes[2].queue_node(&queue_name) == Some(ids[2])).await;
+        assert_eq!(nodes[2].publish_to_exchange(&exchange, "orders.eu.created", message).await, Ok(1));
+        assert_eq!(nodes[2].consume(&queue_name).await.unwrap().message.id, "1");
+
+        assert!(nodes[2].delete_exchange(&exchange).unwrap());
+        wait_until("every node applies the delete", || {
+            nodes.iter().all(|node| node.list_exchanges().iter().all(|e| e.name != exchange))
+        }).await;
+    });
+}